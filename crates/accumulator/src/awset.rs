@@ -0,0 +1,282 @@
+//! Add-wins set as a delta-state CRDT.
+//!
+//! Every add of an item is tagged with a unique dot, a replica id and a
+//! per-replica counter. The causal context records every dot a replica has
+//! seen, added or since removed, so a join can tell an item the other side
+//! removed (its dot is in the other context) from one the other side never
+//! saw (it is not). A remove drops the dots it observed, so an add concurrent
+//! with it survives: adds win.
+//!
+//! Mutations return a delta, a small set holding only the new dots and the
+//! context they supersede, which joins into any replica like a full state.
+//! Deltas since the last broadcast are grouped, see `AddWinsSet::take_delta`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Unique tag of one add: the replica it happened on, and that replica's
+/// counter.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dot {
+    pub replica: u128,
+    pub counter: u64,
+}
+
+/// The dots a replica has seen. Dots are kept compactly as the contiguous
+/// counter prefix of each replica, plus the dots beyond a gap.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CausalContext {
+    /// Per replica, the counter up to which all dots were seen.
+    compact: BTreeMap<u128, u64>,
+    /// Seen dots that do not extend a compact prefix yet.
+    cloud: BTreeSet<Dot>,
+}
+
+impl CausalContext {
+    pub fn contains(&self, dot: &Dot) -> bool {
+        self.compact
+            .get(&dot.replica)
+            .is_some_and(|counter| dot.counter <= *counter)
+            || self.cloud.contains(dot)
+    }
+
+    /// The next dot of `replica`, not seen yet.
+    fn next_dot(&self, replica: u128) -> Dot {
+        Dot {
+            replica,
+            counter: self.compact.get(&replica).copied().unwrap_or(0) + 1,
+        }
+    }
+
+    fn insert(&mut self, dot: Dot) {
+        self.cloud.insert(dot);
+        self.compact();
+    }
+
+    fn join(&mut self, other: &Self) {
+        for (replica, counter) in &other.compact {
+            let own = self.compact.entry(*replica).or_default();
+            *own = (*own).max(*counter);
+        }
+        self.cloud.extend(other.cloud.iter().copied());
+        self.compact();
+    }
+
+    /// Fold the cloud dots that extend a compact prefix into it.
+    fn compact(&mut self) {
+        let cloud = std::mem::take(&mut self.cloud);
+        for dot in cloud {
+            let counter = self.compact.entry(dot.replica).or_default();
+            if dot.counter == *counter + 1 {
+                *counter = dot.counter;
+            } else if dot.counter > *counter {
+                self.cloud.insert(dot);
+            }
+        }
+        self.compact.retain(|_, counter| *counter > 0);
+    }
+}
+
+/// Set of strings where an add concurrent with a remove of the same item
+/// wins. A delta is itself an `AddWinsSet`. Sets compare equal by items,
+/// dots and context, regardless of the deltas pending a broadcast.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AddWinsSet {
+    /// The live dots of each item present.
+    entries: BTreeMap<String, BTreeSet<Dot>>,
+    context: CausalContext,
+    /// Deltas of the mutations since the last `take_delta`.
+    #[serde(skip)]
+    delta: Option<Box<AddWinsSet>>,
+}
+
+impl PartialEq for AddWinsSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries && self.context == other.context
+    }
+}
+
+impl Eq for AddWinsSet {}
+
+impl AddWinsSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contains(&self, item: &str) -> bool {
+        self.entries.contains_key(item)
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = &String> {
+        self.entries.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn context(&self) -> &CausalContext {
+        &self.context
+    }
+
+    /// Number of dots carried, which is what a delta costs to send besides
+    /// its context.
+    pub fn dots(&self) -> usize {
+        self.entries.values().map(BTreeSet::len).sum()
+    }
+
+    /// Add `item` on `replica`, superseding the dots of it seen so far.
+    /// Returns the delta of the add.
+    pub fn add(&mut self, replica: u128, item: &str) -> AddWinsSet {
+        let dot = self.context.next_dot(replica);
+        let mut delta = self.removal(item);
+        delta
+            .entries
+            .insert(item.to_string(), BTreeSet::from([dot]));
+        delta.context.insert(dot);
+        self.apply(delta)
+    }
+
+    /// Remove `item`, i.e. the dots of it seen so far; adds not seen yet
+    /// survive. Returns the delta of the remove, which is empty if the item
+    /// is not present.
+    pub fn remove(&mut self, item: &str) -> AddWinsSet {
+        let delta = self.removal(item);
+        self.apply(delta)
+    }
+
+    /// A delta with the dots of `item` in its context only.
+    fn removal(&self, item: &str) -> AddWinsSet {
+        let mut delta = AddWinsSet::new();
+        for dot in self.entries.get(item).into_iter().flatten() {
+            delta.context.insert(*dot);
+        }
+        delta
+    }
+
+    /// Join a local mutation's delta, and group it for the next broadcast.
+    fn apply(&mut self, delta: AddWinsSet) -> AddWinsSet {
+        self.merge(&delta);
+        self.delta
+            .get_or_insert_with(Default::default)
+            .merge(&delta);
+        delta
+    }
+
+    /// Join a full state or a delta into this set. Joins are commutative,
+    /// associative and idempotent, so deltas may arrive in any order, more
+    /// than once, or be replaced by a full state. Returns true if the set
+    /// changed.
+    pub fn merge(&mut self, other: &AddWinsSet) -> bool {
+        let before = (self.entries.clone(), self.context.clone());
+        let items: BTreeSet<&String> = self.entries.keys().chain(other.entries.keys()).collect();
+        let mut entries = BTreeMap::new();
+        for item in items {
+            let empty = BTreeSet::new();
+            let own = self.entries.get(item).unwrap_or(&empty);
+            let theirs = other.entries.get(item).unwrap_or(&empty);
+            // kept by both, or added on one side and not yet seen by the other
+            let dots: BTreeSet<Dot> = own
+                .intersection(theirs)
+                .chain(own.iter().filter(|dot| !other.context.contains(dot)))
+                .chain(theirs.iter().filter(|dot| !self.context.contains(dot)))
+                .copied()
+                .collect();
+            if !dots.is_empty() {
+                entries.insert(item.clone(), dots);
+            }
+        }
+        self.entries = entries;
+        self.context.join(&other.context);
+        (&self.entries, &self.context) != (&before.0, &before.1)
+    }
+
+    /// The grouped deltas of the mutations since the last call, to broadcast
+    /// instead of the full state, or `None` if nothing changed.
+    pub fn take_delta(&mut self) -> Option<AddWinsSet> {
+        self.delta.take().map(|delta| *delta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(set: &AddWinsSet) -> Vec<&str> {
+        set.items().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn concurrent_add_and_remove() {
+        let mut a = AddWinsSet::new();
+        let mut b = AddWinsSet::new();
+        b.merge(&a.add(0, "x"));
+        a.add(0, "y");
+        b.merge(&a.take_delta().unwrap());
+        assert_eq!(items(&b), ["x", "y"]);
+
+        // b removes x while a adds it again, and both remove y
+        let removed = b.remove("x");
+        let added = a.add(0, "x");
+        let removed_y = [a.remove("y"), b.remove("y")];
+        b.merge(&added);
+        a.merge(&removed);
+        for delta in &removed_y {
+            a.merge(delta);
+            b.merge(delta);
+        }
+        assert_eq!(items(&a), ["x"]);
+        assert_eq!(a, b);
+
+        // an observed add is removed for good
+        let removed = b.remove("x");
+        a.merge(&removed);
+        assert!(a.is_empty() && b.is_empty());
+        assert_eq!(a.context(), b.context());
+
+        // concurrent adds of the same item keep both dots until removed
+        let mut c = AddWinsSet::new();
+        c.merge(&a.add(0, "z"));
+        c.merge(&b.add(1, "z"));
+        assert_eq!(c.dots(), 2);
+        let removed = c.remove("z");
+        a.merge(&removed);
+        assert!(!a.contains("z"));
+    }
+
+    #[test]
+    fn delta_only_propagation() {
+        let mut a = AddWinsSet::new();
+        for i in 0..100 {
+            a.add(0, &i.to_string());
+        }
+        let mut b = a.clone();
+        a.take_delta();
+
+        let delta = a.add(0, "new");
+        assert_eq!(delta.len(), 1);
+        assert_eq!(delta.dots(), 1);
+        // the full state is not needed, nor is the order or number of deltas
+        let later = a.add(0, "later");
+        assert!(b.merge(&later));
+        assert!(b.merge(&delta));
+        assert!(!b.merge(&delta));
+        assert_eq!(a.entries, b.entries);
+        assert_eq!(b.len(), 102);
+
+        let grouped = a.take_delta().unwrap();
+        assert_eq!(items(&grouped), ["later", "new"]);
+        assert!(a.take_delta().is_none());
+        let mut c = b.clone();
+        c.merge(&grouped);
+        assert_eq!(c.entries, b.entries);
+
+        // a delta group survives the wire
+        let wire = serde_json::to_string(&grouped).unwrap();
+        assert_eq!(serde_json::from_str::<AddWinsSet>(&wire).unwrap(), grouped);
+    }
+}
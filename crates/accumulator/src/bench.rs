@@ -0,0 +1,133 @@
+//! Ingestion benchmark for an accumulator cluster.
+//!
+//! The accumulator counterpart of the clock throughput benchmarks in `vlc`,
+//! returning structured results so that a cluster's performance can be
+//! measured programmatically on the target hardware.
+
+use crate::{Client, Configuration, Server};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Builder;
+use tokio::time::Instant;
+
+/// Receive timeout of the benchmarked servers, which bounds how long they
+/// take to notice they should stop.
+const BENCH_RECV_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// How long a cluster is given to converge once the client stopped.
+const CONVERGENCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Throughput of one cluster.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    /// Number of servers in the cluster.
+    pub n_servers: usize,
+    /// Items the client sent.
+    pub sent: usize,
+    /// Items the receiving server added to its state, the others having been
+    /// dropped under load.
+    pub ingested: usize,
+    /// Items ingested per second.
+    pub items_per_sec: f64,
+    /// How long after the client stopped every server held as many items as
+    /// the receiving one, which had handled all items it buffered, or `None`
+    /// if that took longer than `CONVERGENCE_TIMEOUT`.
+    pub convergence_lag: Option<Duration>,
+}
+
+/// Start a local cluster of `n_servers`, and have a client disseminate
+/// distinct items to the first server as fast as it can for `duration`.
+/// Reports how many items the first server ingested per second, and how
+/// long the cluster then took to converge.
+///
+/// This builds and drives its own multi-threaded runtime, so it must not be
+/// called from within an async context.
+pub fn ingest_throughput(n_servers: usize, duration: Duration) -> io::Result<BenchResult> {
+    let n_servers = n_servers.max(1);
+    let rt = Builder::new_multi_thread().enable_all().build()?;
+    let result = rt.block_on(async {
+        // let the OS pick free ports, and release them for the servers to bind
+        let sockets = (0..n_servers)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0"))
+            .collect::<io::Result<Vec<_>>>()?;
+        let addrs = sockets
+            .iter()
+            .map(|socket| socket.local_addr())
+            .collect::<io::Result<_>>()?;
+        drop(sockets);
+        let mut config = Configuration::new(addrs);
+        config.set_recv_timeout(BENCH_RECV_TIMEOUT);
+
+        let mut held = Vec::new();
+        let mut servers = Vec::new();
+        for index in 0..n_servers {
+            let mut server = Server::new(&config, index).await?;
+            let mut changes = server.subscribe();
+            let count = Arc::new(AtomicUsize::new(0));
+            held.push(count.clone());
+            tokio::spawn(async move {
+                while let Some(change) = changes.recv().await {
+                    count.fetch_add(change.added.len(), Ordering::Relaxed);
+                }
+            });
+            servers.push((
+                server.running(),
+                tokio::spawn(async move { server.run().await }),
+            ));
+        }
+        // servers that have not started running yet would miss the first items
+        tokio::time::sleep(BENCH_RECV_TIMEOUT).await;
+
+        let mut client = Client::new(&config).await?;
+        let start = Instant::now();
+        let mut sent = 0;
+        while start.elapsed() < duration {
+            client.disseminate(&sent.to_string()).await?;
+            sent += 1;
+        }
+        let stopped = Instant::now();
+
+        let held_count = |i: usize| held[i].load(Ordering::Relaxed);
+        let mut convergence_lag = None;
+        while stopped.elapsed() < CONVERGENCE_TIMEOUT {
+            // the receiving server may still be handling its mailbox
+            let ingested = held_count(0);
+            tokio::time::sleep(BENCH_RECV_TIMEOUT).await;
+            if held_count(0) == ingested && (1..n_servers).all(|i| held_count(i) >= ingested) {
+                convergence_lag = Some(stopped.elapsed());
+                break;
+            }
+        }
+        let ingested = held_count(0);
+
+        for (running, handle) in servers {
+            running.store(false, Ordering::SeqCst);
+            handle.await?;
+        }
+        io::Result::Ok(BenchResult {
+            n_servers,
+            sent,
+            ingested,
+            items_per_sec: ingested as f64 / duration.as_secs_f64(),
+            convergence_lag,
+        })
+    });
+    rt.shutdown_background();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_server_throughput() {
+        let result = ingest_throughput(1, Duration::from_millis(50)).unwrap();
+        assert_eq!(result.n_servers, 1);
+        assert!(result.ingested > 0 && result.ingested <= result.sent);
+        assert!(result.items_per_sec > 0.);
+        assert!(result.convergence_lag.is_some());
+    }
+}
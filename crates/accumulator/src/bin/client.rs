@@ -1,17 +1,31 @@
 use accumulator::Configuration;
 use clap::Parser;
+use std::time::Duration;
 
 /// Command line parser
 #[derive(Parser)]
 struct Cli {
+    /// Server addresses, one per line, or a TOML configuration file if the
+    /// path ends in `.toml`
     config_path: String,
     message: String,
+    /// Wait up to this many milliseconds for the server to confirm the message
+    #[arg(long)]
+    confirm_ms: Option<u64>,
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> std::io::Result<()> {
     let args = Cli::parse();
-    let config = Configuration::from_file(&args.config_path);
-    let mut client = accumulator::Client::new(&config).await;
-    client.disseminate(&args.message).await;
+    let config = Configuration::load(&args.config_path)?;
+    let mut client = accumulator::Client::new(&config).await?;
+    match args.confirm_ms {
+        Some(ms) => {
+            client
+                .disseminate_confirmed(&args.message, Duration::from_millis(ms))
+                .await?
+        }
+        None => client.disseminate(&args.message).await?,
+    }
+    Ok(())
 }
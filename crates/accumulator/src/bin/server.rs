@@ -1,17 +1,33 @@
-use accumulator::Configuration;
+use accumulator::{Configuration, PersistencePolicy};
 use clap::Parser;
 
 /// Command line parser
 #[derive(Parser)]
 struct Cli {
+    /// Server addresses, one per line, or a TOML configuration file if the
+    /// path ends in `.toml`
     config_path: String,
     index: usize,
+    /// Persist the state to this file, flushing every `--flush-every` changes
+    #[arg(long)]
+    state_path: Option<String>,
+    #[arg(long, default_value_t = 1)]
+    flush_every: usize,
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> std::io::Result<()> {
     let args = Cli::parse();
-    let config = Configuration::from_file(&args.config_path);
-    let mut server = accumulator::Server::new(&config, args.index).await;
+    let config = Configuration::load(&args.config_path)?;
+    let mut builder = accumulator::Server::builder(&config, args.index);
+    if let Some(path) = args.state_path {
+        let policy = match args.flush_every {
+            0 | 1 => PersistencePolicy::OnEveryMerge,
+            n => PersistencePolicy::EveryN(n),
+        };
+        builder = builder.persistence(path, policy);
+    }
+    let mut server = builder.build().await?;
     server.run().await;
+    server.close().await
 }
@@ -0,0 +1,134 @@
+//! A local cluster running all the servers of a configuration, for
+//! embedding the accumulator in an application or a test.
+
+use crate::{Client, Configuration, Server};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// All servers of a configuration, each running on its own task, and a
+/// client to query and terminate them.
+pub struct Cluster {
+    config: Configuration,
+    client: Client,
+    handles: Vec<JoinHandle<io::Result<BTreeSet<String>>>>,
+}
+
+impl Cluster {
+    /// Bind the sockets of all configured servers, then start running them.
+    /// Fails if any server cannot bind, in which case none is started.
+    pub async fn spawn(config: &Configuration) -> io::Result<Self> {
+        let mut servers = Vec::new();
+        for index in 0..config.server_addrs.len() {
+            servers.push(Server::new(config, index).await?);
+        }
+        let handles = servers
+            .into_iter()
+            .map(|mut server| {
+                tokio::spawn(async move {
+                    server.run().await;
+                    let items = server.state.items.clone();
+                    server.close().await?;
+                    Ok(items)
+                })
+            })
+            .collect();
+        Ok(Self {
+            config: config.clone(),
+            client: Client::new(config).await?,
+            handles,
+        })
+    }
+
+    pub fn config(&self) -> &Configuration {
+        &self.config
+    }
+
+    /// Tasks running the servers, by index. Each returns the items its
+    /// server held when it stopped.
+    pub fn handles(&self) -> &[JoinHandle<io::Result<BTreeSet<String>>>] {
+        &self.handles
+    }
+
+    /// The client the cluster is queried with, e.g. to disseminate items.
+    pub fn client(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// The items held by each server answering within the receive timeout,
+    /// by address.
+    pub async fn items(&mut self) -> io::Result<HashMap<SocketAddr, HashSet<String>>> {
+        let addrs = self.config.server_addrs.clone();
+        self.client.list_items(&addrs).await
+    }
+
+    /// Wait until all servers answer with the same items, and return them.
+    /// Fails with `TimedOut` if they did not converge within `timeout`.
+    pub async fn converged(&mut self, timeout: Duration) -> io::Result<HashSet<String>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let items = self.items().await?;
+            let mut sets = items.into_values();
+            if let Some(first) = sets.next() {
+                let answered = 1 + sets.len();
+                if answered == self.config.server_addrs.len() && sets.all(|set| set == first) {
+                    return Ok(first);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            tokio::time::sleep(self.config.recv_timeout).await;
+        }
+    }
+
+    /// Terminate all servers and wait for them to shut down. Returns the
+    /// items each server held, by index.
+    pub async fn terminate(mut self) -> io::Result<Vec<BTreeSet<String>>> {
+        let addrs = self.config.server_addrs.clone();
+        for (_, result) in self.client.terminate_all(&addrs).await {
+            result?;
+        }
+        let mut states = Vec::new();
+        for handle in self.handles {
+            states.push(handle.await.map_err(io::Error::other)??);
+        }
+        Ok(states)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cluster_converges_and_terminates() {
+        let sockets: Vec<_> = (0..3)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let mut config =
+            Configuration::new(sockets.iter().map(|s| s.local_addr().unwrap()).collect());
+        config.set_recv_timeout(Duration::from_millis(20));
+        drop(sockets);
+
+        let mut cluster = Cluster::spawn(&config).await.unwrap();
+        assert_eq!(cluster.handles().len(), 3);
+        cluster.client().disseminate("hello").await.unwrap();
+        cluster.client().disseminate("world").await.unwrap();
+        let items = cluster.converged(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(items, HashSet::from(["hello".into(), "world".into()]));
+
+        let states = cluster.terminate().await.unwrap();
+        assert_eq!(states.len(), 3);
+        for state in states {
+            assert_eq!(state, BTreeSet::from(["hello".into(), "world".into()]));
+        }
+        // a configured address that is taken fails the whole cluster
+        let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Configuration::new(vec![taken.local_addr().unwrap()]);
+        assert!(Cluster::spawn(&config).await.is_err());
+    }
+}
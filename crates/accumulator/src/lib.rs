@@ -5,292 +5,5121 @@
 //! new state to other nodes in the network. All nodes eventually converge to
 //! the same state, by merging received states into their own states.
 
+pub mod awset;
+pub mod bench;
+mod cluster;
+mod persistence;
+mod transport;
+
+use awset::AddWinsSet;
+pub use cluster::Cluster;
+use persistence::Persistence;
+pub use persistence::PersistencePolicy;
+use rand::rngs::StdRng;
+use rand::seq::{IteratorRandom, SliceRandom};
+use rand::SeedableRng;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::cmp;
-use std::collections::BTreeSet;
-use std::io::BufRead;
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{self, BufRead};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+pub use transport::{FaultyTransport, SendFuture, Transport, UdpTransport};
+use types::retry::RetryPolicy;
+use vlc::ordinary_clock::{Clock as _, OrdinaryClock};
 use vlc::Clock;
 
 #[derive(Serialize, Deserialize, Debug)]
 enum Message {
     FromClient(ClientMessage),
     FromServer(ServerMessage),
+    /// Ask a server whether it holds `item`, answered with a `QueryReply`.
+    /// With `repair`, the server first pulls missing items from its peers.
+    /// The reply goes to `reply_to` as in `ClientMessage`.
+    Query {
+        item: String,
+        #[serde(default)]
+        repair: bool,
+        #[serde(default)]
+        reply_to: Option<String>,
+    },
+    QueryReply {
+        item: String,
+        present: bool,
+    },
+    /// Disseminate `item` as in `FromClient`, and answer with a `QuorumAck`
+    /// once `w` servers hold it, see `Client::disseminate_quorum`.
+    DisseminateQuorum {
+        item: String,
+        w: usize,
+        #[serde(default)]
+        reply_to: Option<String>,
+    },
+    QuorumAck {
+        item: String,
+    },
+    /// A client's item was not accepted, e.g. because it is too long.
+    Rejected {
+        item: String,
+        reason: String,
+    },
+    /// Ask a server for all items it holds, answered with an `ItemList`.
+    ListItems,
+    ItemList {
+        items: Vec<String>,
+    },
+    /// Remove `item`, in add-wins mode, see `Client::remove`.
+    Remove {
+        item: String,
+    },
+    /// Add `delta` to the counter of `key`, see `Server::count`. Answered
+    /// with a `Counted`, or a `Rejected` if the tally would overflow.
+    Increment {
+        key: String,
+        delta: i64,
+    },
+    Counted {
+        key: String,
+    },
+    /// A new configuration pushed by a coordinator, e.g. an updated peer list.
+    Config(Configuration),
+    /// A server at `addr` asking to be added to the peer list.
+    Join {
+        addr: String,
+    },
     Terminate,
 }
 
-/// Network configuration. Contains a list of server addresses.
-#[derive(Debug, Clone)]
-pub struct Configuration {
-    server_addrs: Vec<SocketAddr>,
-}
+impl Message {
+    /// Number of strings the message carries, and their total length in
+    /// bytes.
+    fn payload_size(&self) -> (usize, usize) {
+        let strings: Vec<&String> = match self {
+            Message::FromClient(ClientMessage { item, .. })
+            | Message::Query { item, .. }
+            | Message::QueryReply { item, .. }
+            | Message::Increment { key: item, .. }
+            | Message::Counted { key: item }
+            | Message::Remove { item }
+            | Message::DisseminateQuorum { item, .. }
+            | Message::QuorumAck { item }
+            | Message::Join { addr: item } => vec![item],
+            Message::Rejected { item, reason } => vec![item, reason],
+            Message::FromServer(ServerMessage::State(state)) => state
+                .items
+                .iter()
+                .chain(state.stamps.keys())
+                .chain(state.added_at.keys())
+                .chain(state.inputs.values().flatten())
+                .chain(state.counters.keys())
+                .chain(state.set.items())
+                .chain(
+                    state
+                        .provenance
+                        .iter()
+                        .flat_map(|(item, sources)| [item].into_iter().chain(sources)),
+                )
+                .collect(),
+            Message::FromServer(ServerMessage::StateChunk { items, .. })
+            | Message::ItemList { items } => items.iter().collect(),
+            Message::FromServer(ServerMessage::Delta { delta, .. }) => delta.items().collect(),
+            Message::Config(config) => return (config.server_addrs.len(), 0),
+            Message::FromServer(
+                ServerMessage::Digest { .. }
+                | ServerMessage::Ping { .. }
+                | ServerMessage::Pong { .. }
+                | ServerMessage::Leave { .. },
+            )
+            | Message::ListItems
+            | Message::Terminate => Vec::new(),
+        };
+        (strings.len(), strings.iter().map(|s| s.len()).sum())
+    }
 
-impl Configuration {
-    /// Create configuration from a file.
-    pub fn from_file(path: &str) -> Self {
-        let mut config = Configuration {
-            server_addrs: Vec::new(),
+    /// Whether the message is within the limits a server accepts.
+    fn within_limits(&self) -> bool {
+        let (items, bytes) = self.payload_size();
+        let chunks_ok = match self {
+            Message::FromServer(ServerMessage::StateChunk { seq, total, .. }) => {
+                seq < total && *total <= MAX_STATE_CHUNKS
+            }
+            _ => true,
         };
-        let file = std::fs::File::open(path).unwrap();
-        let reader = std::io::BufReader::new(file);
-        for line in reader.lines() {
-            let addr = line.unwrap().parse().unwrap();
-            config.server_addrs.push(addr);
-        }
-        config
+        items <= MAX_MESSAGE_ITEMS && bytes <= MAX_MESSAGE_BYTES && chunks_ok
     }
 }
 
-/// Client message type for the accumulator application. Each message contains
-/// a string.
-#[derive(Serialize, Deserialize, Debug)]
-struct ClientMessage {
-    item: String,
+/// Most strings a server accepts in a single message.
+const MAX_MESSAGE_ITEMS: usize = 4096;
+
+/// Most bytes of strings a server accepts in a single message.
+const MAX_MESSAGE_BYTES: usize = 1 << 20;
+
+/// Most chunks a server buffers for a single chunked state sync.
+const MAX_STATE_CHUNKS: usize = 4096;
+
+/// Most bytes of items a server buffers across all incomplete chunked state
+/// syncs. The syncs least recently added to are discarded beyond it.
+const MAX_REASSEMBLY_BYTES: usize = 16 << 20;
+
+/// Default time after its last chunk an incomplete chunked state sync is
+/// discarded.
+const DEFAULT_CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default time a server waits for a message before waking up.
+const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a client waits for a query reply before asking again.
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a server collects peer states for a read-repairing query before
+/// answering it.
+const READ_REPAIR_WAIT: Duration = Duration::from_millis(100);
+
+/// Reason client changes to a sealed server are rejected with.
+const SEALED: &str = "sealed";
+
+/// Receive timeouts a quorum write waits for acknowledgements, asking the
+/// peers again after each.
+const QUORUM_WRITE_ROUNDS: u32 = 3;
+
+/// Default number of received datagrams a server buffers while busy.
+const DEFAULT_MAILBOX_CAPACITY: usize = 1024;
+
+/// Number of state changes buffered for a subscriber that does not keep up.
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+/// Version of the wire format of `Message`, carried by every datagram. Bump
+/// it on any change that older nodes cannot parse, so that they drop the
+/// messages of newer ones with a clear log instead of failing to parse them.
+const PROTOCOL_VERSION: u8 = 1;
+
+/// A message as sent on the wire, tagged with the protocol version.
+#[derive(Serialize, Deserialize)]
+struct Envelope<M> {
+    version: u8,
+    msg: M,
 }
 
-/// The current node state, which is a set of strings.
-#[derive(Serialize, Deserialize, Debug)]
-struct ServerMessage {
-    state: ServerState,
+/// Just the version of an envelope, readable whatever its message.
+#[derive(Deserialize)]
+struct Version {
+    version: u8,
 }
 
-/// A client node for the accumulator application.
-pub struct Client {
-    socket: UdpSocket,
-    config: Configuration,
+/// Why a datagram could not be decoded.
+#[derive(Debug)]
+enum DecodeError {
+    /// The datagram is of another protocol version.
+    Incompatible(u8),
+    Malformed,
 }
 
-impl Client {
-    /// Create a new client
-    pub async fn new(config: &Configuration) -> Self {
-        let s = UdpSocket::bind("0.0.0.0:0").await.unwrap();
-        Self {
-            socket: s,
-            config: config.clone(),
+/// Serialize a message into its envelope.
+fn encode(msg: &Message) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&Envelope {
+        version: PROTOCOL_VERSION,
+        msg,
+    })
+}
+
+/// Parse the message of an envelope of the current protocol version. The
+/// datagram is parsed once, unless it is not a valid envelope.
+fn decode(buf: &[u8]) -> Result<Message, DecodeError> {
+    let version = match serde_json::from_slice::<Envelope<Message>>(buf) {
+        Ok(Envelope { version, msg }) if version == PROTOCOL_VERSION => return Ok(msg),
+        Ok(Envelope { version, .. }) => version,
+        Err(_) => {
+            let Version { version } =
+                serde_json::from_slice(buf).map_err(|_| DecodeError::Malformed)?;
+            version
         }
+    };
+    if version != PROTOCOL_VERSION {
+        return Err(DecodeError::Incompatible(version));
     }
+    Err(DecodeError::Malformed)
+}
 
-    /// Disseminate a string to the accumulator network.
-    pub async fn disseminate(&mut self, item: &str) {
-        let msg = Message::FromClient(ClientMessage {
-            item: String::from(item),
-        });
-        self.socket
-            .send_to(
-                serde_json::to_string(&msg).unwrap().as_bytes(),
-                self.config.server_addrs[0],
-            )
-            .await
-            .unwrap();
-    }
+/// How a send is retried while the socket is not ready.
+const SEND_RETRY: RetryPolicy =
+    match RetryPolicy::new(Duration::from_millis(1), Duration::from_millis(1), 4, 0.) {
+        Ok(policy) => policy,
+        Err(_) => panic!("invalid send retry policy"),
+    };
 
-    /// Terminate a running accumulator server.
-    pub async fn terminate(&mut self, index: usize) {
-        let msg = Message::Terminate;
-        self.socket
-            .send_to(
-                serde_json::to_string(&msg).unwrap().as_bytes(),
-                self.config.server_addrs[index],
-            )
-            .await
-            .unwrap();
+/// Send one datagram, briefly retrying while the socket would block. Fails on
+/// any other error, or if only part of the datagram was sent.
+async fn send_datagram(socket: &UdpSocket, buf: &[u8], addr: SocketAddr) -> io::Result<()> {
+    let mut retries = 0;
+    loop {
+        match socket.send_to(buf, addr).await {
+            Ok(n) if n == buf.len() => return Ok(()),
+            Ok(n) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!("sent {n} of {} bytes to {addr}", buf.len()),
+                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                let Some(delay) = SEND_RETRY.delay(retries, 0.) else {
+                    return Err(err);
+                };
+                retries += 1;
+                tokio::time::sleep(delay).await;
+            }
+            Err(err) => return Err(err),
+        }
     }
 }
 
-/// State of a server node.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct ServerState {
-    clock: Clock,
-    id: u128,
-    items: BTreeSet<String>,
+/// Whether two addresses name the same node, whatever their textual form:
+/// IPv4-mapped IPv6 addresses equal their IPv4 form, and all loopback
+/// addresses with the same port are the same node.
+fn same_node(a: SocketAddr, b: SocketAddr) -> bool {
+    let (ip_a, ip_b) = (a.ip().to_canonical(), b.ip().to_canonical());
+    a.port() == b.port() && (ip_a == ip_b || (ip_a.is_loopback() && ip_b.is_loopback()))
 }
 
-impl ServerState {
-    /// Create a new server state.
-    fn new(id: u128) -> Self {
-        Self {
-            clock: Clock::new(),
-            id,
-            items: BTreeSet::new(),
+/// Order in which a server sends a broadcast to its peers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BroadcastOrder {
+    /// Configuration order, the first peer is always served first.
+    #[default]
+    Sequential,
+    /// Start each broadcast one peer further than the previous one.
+    RoundRobin,
+    /// Shuffle the peers on every broadcast. The seed makes the sequence of
+    /// orders reproducible.
+    Shuffled { seed: u64 },
+}
+
+/// Which peers a server sends its state changes to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PeerSelector {
+    /// Broadcast to all peers.
+    #[default]
+    All,
+    /// Gossip to `fanout` peers chosen at random.
+    RandomFanout { fanout: usize },
+    /// Send to the `fanout` peers the changed item hashes to, by rendezvous
+    /// hashing of the item with each peer's address. The same item always
+    /// reaches the same peers, and items spread evenly over all peers.
+    /// Changes without a single item, e.g. after a merge, are hashed by the
+    /// broadcast round instead.
+    ConsistentHash { fanout: usize },
+}
+
+impl PeerSelector {
+    /// Number of peers a change is sent to, unless it is broadcast to all.
+    pub fn fanout(&self) -> Option<usize> {
+        match self {
+            PeerSelector::All => None,
+            PeerSelector::RandomFanout { fanout } | PeerSelector::ConsistentHash { fanout } => {
+                Some(*fanout)
+            }
         }
     }
+}
 
-    /// Add items into the state. Returns true if resulting in a new state.
-    fn add(&mut self, items: BTreeSet<String>) -> bool {
-        if items.is_subset(&self.items) {
-            false
-        } else {
-            self.items.extend(items);
-            self.clock.inc(self.id);
-            true
+/// How `Server::state_digest` hashes the state for anti-entropy. A digest
+/// carries its algorithm, and the receiver hashes its own state the same way
+/// to compare, so servers configured differently still agree.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(try_from = "DigestAlgorithmRepr")]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+    /// SHA-256 truncated to its first `len` bytes, between 4 and 32, to save
+    /// datagram space. Truncation weakens collision resistance: differing
+    /// states are more likely to share a digest, in which case anti-entropy
+    /// does not repair them, and collisions become feasible to craft.
+    TruncatedSha256 {
+        len: usize,
+    },
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    /// Lengths a truncated digest may have. Shorter ones collide too often
+    /// for anti-entropy to be of use, and SHA-256 has no more bytes.
+    const TRUNCATED_LENS: std::ops::RangeInclusive<usize> = 4..=32;
+
+    /// Check that a truncated digest length is in range.
+    fn validate(self) -> Result<Self, String> {
+        match self {
+            DigestAlgorithm::TruncatedSha256 { len } if !Self::TRUNCATED_LENS.contains(&len) => {
+                Err(format!(
+                    "truncated digest length must be between {} and {}, not {len}",
+                    Self::TRUNCATED_LENS.start(),
+                    Self::TRUNCATED_LENS.end()
+                ))
+            }
+            _ => Ok(self),
         }
     }
+}
 
-    /// Merge another ServerState into the current state. Returns true if
-    /// resulting in a new state (different from current and received
-    /// state).
-    fn merge(&mut self, other: &Self) -> bool {
-        match self.clock.partial_cmp(&other.clock) {
-            Some(cmp::Ordering::Equal) => false,
-            Some(cmp::Ordering::Greater) => false,
-            Some(cmp::Ordering::Less) => {
-                self.clock = other.clock.clone();
-                self.items = other.items.clone();
-                false
-            }
-            None => {
-                self.clock.merge(&vec![&other.clock]);
-                self.add(other.items.clone())
+/// Wire form of `DigestAlgorithm`, validated on deserialization so that
+/// digests and configurations from peers cannot carry a bad length.
+#[derive(Deserialize)]
+enum DigestAlgorithmRepr {
+    Sha256,
+    TruncatedSha256 { len: usize },
+    Blake3,
+}
+
+impl TryFrom<DigestAlgorithmRepr> for DigestAlgorithm {
+    type Error = String;
+
+    fn try_from(repr: DigestAlgorithmRepr) -> Result<Self, String> {
+        match repr {
+            DigestAlgorithmRepr::Sha256 => DigestAlgorithm::Sha256,
+            DigestAlgorithmRepr::TruncatedSha256 { len } => {
+                DigestAlgorithm::TruncatedSha256 { len }
             }
+            DigestAlgorithmRepr::Blake3 => DigestAlgorithm::Blake3,
         }
+        .validate()
     }
 }
 
-/// An accumulator server node. Each node maintains a UDP socket, and a set of
-/// strings as its internal state.
-pub struct Server {
-    config: Configuration,
-    index: usize,
-    socket: UdpSocket,
-    state: ServerState,
-    running: bool,
+/// How servers combine the items disseminated through each of them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeMode {
+    /// Every server holds every item disseminated through any server.
+    #[default]
+    Union,
+    /// Every server holds the items disseminated through all servers.
+    ///
+    /// Servers cannot drop items a peer lacks when merging its state, since
+    /// the peer may just not have received them yet, and dropped items would
+    /// be added back by the next merge with a node that still holds them.
+    /// Instead of tombstones for dropped items, the state records the items
+    /// disseminated through each node, one grow-only set per node id, which
+    /// merge by union. A server holds the intersection of the sets of all
+    /// configured nodes, so an item appears once every node reported it and
+    /// is never removed again. Items do not expire in this mode.
+    Intersection,
+    /// Every server holds the items added and not removed since, where an
+    /// add concurrent with a remove of the same item wins, see
+    /// `Client::remove`.
+    ///
+    /// The state records the items in an [`awset::AddWinsSet`]. A server
+    /// broadcasts each local add or remove as a delta holding only the new
+    /// dots and the context they supersede, instead of its full state.
+    /// Servers in this mode run anti-entropy whatever the peer selector, and
+    /// the full states it exchanges repair the deltas a peer missed. Items do
+    /// not expire in this mode.
+    AddWins,
 }
 
-impl Server {
-    /// Create a new server
-    pub async fn new(config: &Configuration, index: usize) -> Self {
-        let s = UdpSocket::bind(config.server_addrs[index]).await.unwrap();
+/// Network configuration. Contains a list of server addresses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "ConfigurationRepr")]
+pub struct Configuration {
+    server_addrs: Vec<SocketAddr>,
+    recv_timeout: Duration,
+    peer_selector: PeerSelector,
+    broadcast_order: BroadcastOrder,
+    strict_peers: bool,
+    item_ttl: Option<Duration>,
+    suspect_after: Option<Duration>,
+    mailbox_capacity: usize,
+    track_provenance: bool,
+    causal_order: bool,
+    merge_mode: MergeMode,
+    chunk_timeout: Duration,
+    max_item_len: Option<usize>,
+    max_peers: Option<usize>,
+    digest_algorithm: DigestAlgorithm,
+}
+
+/// Wire form of `Configuration`, validated on deserialization so that a
+/// configuration pushed by a peer cannot carry settings a server rejects
+/// when loading it.
+#[derive(Deserialize)]
+struct ConfigurationRepr {
+    server_addrs: Vec<SocketAddr>,
+    recv_timeout: Duration,
+    peer_selector: PeerSelector,
+    broadcast_order: BroadcastOrder,
+    strict_peers: bool,
+    item_ttl: Option<Duration>,
+    suspect_after: Option<Duration>,
+    mailbox_capacity: usize,
+    track_provenance: bool,
+    causal_order: bool,
+    merge_mode: MergeMode,
+    chunk_timeout: Duration,
+    max_item_len: Option<usize>,
+    max_peers: Option<usize>,
+    digest_algorithm: DigestAlgorithm,
+}
+
+impl TryFrom<ConfigurationRepr> for Configuration {
+    type Error = io::Error;
+
+    fn try_from(repr: ConfigurationRepr) -> io::Result<Self> {
+        let config = Self {
+            server_addrs: repr.server_addrs,
+            recv_timeout: repr.recv_timeout,
+            peer_selector: repr.peer_selector,
+            broadcast_order: repr.broadcast_order,
+            strict_peers: repr.strict_peers,
+            item_ttl: repr.item_ttl,
+            suspect_after: repr.suspect_after,
+            mailbox_capacity: repr.mailbox_capacity,
+            track_provenance: repr.track_provenance,
+            causal_order: repr.causal_order,
+            merge_mode: repr.merge_mode,
+            chunk_timeout: repr.chunk_timeout,
+            max_item_len: repr.max_item_len,
+            max_peers: repr.max_peers,
+            digest_algorithm: repr.digest_algorithm,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Configuration {
+    /// Create configuration from a list of server addresses, with default
+    /// settings otherwise.
+    pub fn new(server_addrs: Vec<SocketAddr>) -> Self {
         Self {
-            config: config.clone(),
-            index,
-            socket: s,
-            state: ServerState::new(index.try_into().unwrap()),
-            running: false,
+            server_addrs,
+            recv_timeout: DEFAULT_RECV_TIMEOUT,
+            peer_selector: PeerSelector::All,
+            broadcast_order: BroadcastOrder::default(),
+            strict_peers: false,
+            item_ttl: None,
+            suspect_after: None,
+            mailbox_capacity: DEFAULT_MAILBOX_CAPACITY,
+            track_provenance: false,
+            causal_order: false,
+            merge_mode: MergeMode::Union,
+            chunk_timeout: DEFAULT_CHUNK_TIMEOUT,
+            max_item_len: None,
+            max_peers: None,
+            digest_algorithm: DigestAlgorithm::default(),
         }
     }
 
-    /// Handle a message
-    async fn handle_msg(&mut self, msg: Message) {
-        match msg {
-            Message::FromClient(msg) => {
-                if self.state.add(BTreeSet::from_iter(vec![msg.item])) {
-                    self.broadcast_state().await;
-                }
+    /// Create configuration from a file.
+    pub fn from_file(path: &str) -> Self {
+        let file = std::fs::File::open(path).unwrap();
+        let reader = std::io::BufReader::new(file);
+        let server_addrs = reader
+            .lines()
+            .map(|line| line.unwrap().parse().unwrap())
+            .collect();
+        Self::new(server_addrs)
+    }
+
+    /// Load configuration from `path`, a TOML file if it has a `.toml`
+    /// extension, see `from_toml_path`, or otherwise a file with one server
+    /// address per line. Returns an `InvalidData` error if the file is
+    /// malformed or fails validation.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|ext| ext == "toml") {
+            return Self::from_toml_path(path);
+        }
+        let mut server_addrs = Vec::new();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let addr = line.trim().parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: invalid server address {line:?}", path.display()),
+                )
+            })?;
+            server_addrs.push(addr);
+        }
+        let config = Self::new(server_addrs);
+        config
+            .validate()
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))?;
+        Ok(config)
+    }
+
+    /// Create configuration from a TOML file, e.g.
+    ///
+    /// ```toml
+    /// server_addrs = ["127.0.0.1:8000", "localhost:8001"]
+    /// # optional
+    /// recv_timeout_ms = 500
+    /// peer_selector = "consistent_hash" # or "all", "random_fanout"
+    /// fanout = 2
+    /// broadcast_order = "shuffled" # or "sequential", "round_robin"
+    /// broadcast_seed = 7
+    /// strict_peers = true
+    /// item_ttl_ms = 60000
+    /// suspect_after_ms = 3000
+    /// mailbox_capacity = 1024
+    /// track_provenance = true
+    /// causal_order = true
+    /// merge_mode = "intersection" # or "union", "add_wins"
+    /// chunk_timeout_ms = 5000
+    /// max_item_len = 4096
+    /// max_peers = 16
+    /// digest_algorithm = "truncated_sha256" # or "sha256", "blake3"
+    /// digest_len = 16
+    /// ```
+    ///
+    /// Addresses may use host names, which are resolved on load. Unset
+    /// options keep their defaults, and `fanout` alone selects random
+    /// fanout. Returns an `InvalidData` error describing the problem if the
+    /// file is malformed or fails validation.
+    pub fn from_toml_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let invalid = |msg: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {msg}", path.display()),
+            )
+        };
+        let file: ConfigFile = toml::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|err| invalid(err.to_string()))?;
+
+        let mut server_addrs = Vec::new();
+        for addr in &file.server_addrs {
+            let resolved = addr
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .ok_or_else(|| invalid(format!("invalid server address {addr:?}")))?;
+            server_addrs.push(resolved);
+        }
+        let mut config = Self::new(server_addrs);
+        if let Some(ms) = file.recv_timeout_ms {
+            config.recv_timeout = Duration::from_millis(ms);
+        }
+        match (file.peer_selector.as_deref(), file.fanout) {
+            (None | Some("all"), None) => {}
+            (None | Some("random_fanout"), Some(fanout)) => {
+                config.peer_selector = PeerSelector::RandomFanout { fanout }
+            }
+            (Some("consistent_hash"), Some(fanout)) => {
+                config.peer_selector = PeerSelector::ConsistentHash { fanout }
             }
-            Message::FromServer(msg) => {
-                if self.state.merge(&msg.state) {
-                    self.broadcast_state().await;
+            (Some("all"), Some(_)) => {
+                return Err(invalid(
+                    "fanout is not used by peer_selector \"all\"".into(),
+                ))
+            }
+            (Some(selector @ ("random_fanout" | "consistent_hash")), None) => {
+                return Err(invalid(format!(
+                    "peer_selector {selector:?} requires fanout"
+                )))
+            }
+            (Some(selector), _) => {
+                return Err(invalid(format!("unknown peer_selector {selector:?}")))
+            }
+        }
+        match (file.broadcast_order.as_deref(), file.broadcast_seed) {
+            (None, None) => {}
+            (Some("sequential"), None) => config.broadcast_order = BroadcastOrder::Sequential,
+            (Some("round_robin"), None) => config.broadcast_order = BroadcastOrder::RoundRobin,
+            (Some("shuffled"), seed) => {
+                config.broadcast_order = BroadcastOrder::Shuffled {
+                    seed: seed.unwrap_or_default(),
                 }
             }
-            Message::Terminate => {
-                self.running = false;
+            (Some(order @ ("sequential" | "round_robin")), Some(_)) => {
+                return Err(invalid(format!(
+                    "broadcast_seed is not used by broadcast_order {order:?}"
+                )))
+            }
+            (None, Some(_)) => {
+                return Err(invalid(
+                    "broadcast_seed requires broadcast_order \"shuffled\"".into(),
+                ))
+            }
+            (Some(order), _) => return Err(invalid(format!("unknown broadcast_order {order:?}"))),
+        }
+        if let Some(strict) = file.strict_peers {
+            config.strict_peers = strict;
+        }
+        config.item_ttl = file.item_ttl_ms.map(Duration::from_millis);
+        config.suspect_after = file.suspect_after_ms.map(Duration::from_millis);
+        if let Some(ms) = file.chunk_timeout_ms {
+            config.chunk_timeout = Duration::from_millis(ms);
+        }
+        if let Some(capacity) = file.mailbox_capacity {
+            config.mailbox_capacity = capacity;
+        }
+        if let Some(track) = file.track_provenance {
+            config.track_provenance = track;
+        }
+        if let Some(enabled) = file.causal_order {
+            config.causal_order = enabled;
+        }
+        match file.merge_mode.as_deref() {
+            None => {}
+            Some("union") => config.merge_mode = MergeMode::Union,
+            Some("intersection") => config.merge_mode = MergeMode::Intersection,
+            Some("add_wins") => config.merge_mode = MergeMode::AddWins,
+            Some(mode) => return Err(invalid(format!("unknown merge_mode {mode:?}"))),
+        }
+        config.max_item_len = file.max_item_len;
+        config.max_peers = file.max_peers;
+        let algorithm = match (file.digest_algorithm.as_deref(), file.digest_len) {
+            (None, None) => None,
+            (Some("sha256"), None) => Some(DigestAlgorithm::Sha256),
+            (Some("blake3"), None) => Some(DigestAlgorithm::Blake3),
+            (Some("truncated_sha256"), Some(len)) => Some(DigestAlgorithm::TruncatedSha256 { len }),
+            (Some("truncated_sha256"), None) => {
+                return Err(invalid(
+                    "digest_algorithm \"truncated_sha256\" requires digest_len".into(),
+                ))
+            }
+            (Some(algorithm @ ("sha256" | "blake3")), Some(_)) => {
+                return Err(invalid(format!(
+                    "digest_len is not used by digest_algorithm {algorithm:?}"
+                )))
+            }
+            (None, Some(_)) => {
+                return Err(invalid(
+                    "digest_len requires digest_algorithm \"truncated_sha256\"".into(),
+                ))
             }
+            (Some(algorithm), _) => {
+                return Err(invalid(format!("unknown digest_algorithm {algorithm:?}")))
+            }
+        };
+        if let Some(algorithm) = algorithm {
+            config.digest_algorithm = algorithm;
         }
+        config.validate().map_err(|err| invalid(err.to_string()))?;
+        Ok(config)
     }
 
-    /// Broadcast current state to all other nodes in the network.
-    async fn broadcast_state(&mut self) {
-        let msg = Message::FromServer(ServerMessage {
-            state: self.state.clone(),
-        });
-        for i in 0..self.config.server_addrs.len() {
-            if self.index != i {
-                self.socket
-                    .send_to(
-                        serde_json::to_string(&msg).unwrap().as_bytes(),
-                        self.config.server_addrs[i],
-                    )
-                    .await
-                    .unwrap();
+    /// Check the settings, returning an `InvalidData` error naming the first
+    /// one that is out of range: addresses must be present and distinct,
+    /// timeouts, fanout and capacities positive, `max_peers` at least the
+    /// number of peers and a truncated digest length between 4 and 32.
+    /// Configurations are checked when loaded from TOML, deserialized or
+    /// pushed to a server, and by the setters.
+    pub fn validate(&self) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        if self.server_addrs.is_empty() {
+            return invalid("server_addrs must not be empty".into());
+        }
+        for (i, addr) in self.server_addrs.iter().enumerate() {
+            if self.server_addrs[..i].contains(addr) {
+                return invalid(format!("server address {addr} is listed twice"));
+            }
+        }
+        for (timeout, name) in [
+            (Some(self.recv_timeout), "recv_timeout"),
+            (self.item_ttl, "item_ttl"),
+            (self.suspect_after, "suspect_after"),
+            (Some(self.chunk_timeout), "chunk_timeout"),
+        ] {
+            if timeout.is_some_and(|timeout| timeout.is_zero()) {
+                return invalid(format!("{name} must be positive"));
             }
         }
+        if self.peer_selector.fanout() == Some(0) {
+            return invalid("fanout must be positive".into());
+        }
+        if self.mailbox_capacity == 0 {
+            return invalid("mailbox_capacity must be positive".into());
+        }
+        if self.max_item_len == Some(0) {
+            return invalid("max_item_len must be positive".into());
+        }
+        let peers = self.server_addrs.len() - 1;
+        if self.max_peers.is_some_and(|max| max < peers) {
+            return invalid(format!(
+                "max_peers must be at least the {peers} configured peers"
+            ));
+        }
+        if let Err(err) = self.digest_algorithm.validate() {
+            return invalid(format!("digest_algorithm: {err}"));
+        }
+        Ok(())
     }
 
-    /// Main event loop.
-    pub async fn run(&mut self) {
-        self.running = true;
-        while self.running {
-            let mut buf = [0; 1500];
-            let (n, _) = self.socket.recv_from(&mut buf).await.unwrap();
-            let msg: Message = serde_json::from_str(&String::from_utf8_lossy(&buf[..n])).unwrap();
-            self.handle_msg(msg).await;
+    /// Panic unless the configuration passes `validate`, after a setter.
+    fn assert_valid(&self) {
+        if let Err(err) = self.validate() {
+            panic!("invalid configuration: {err}");
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use rand::Rng;
-    use std::time;
-    use tokio::task::JoinHandle;
+    /// Set how long a server blocks waiting for a message before it wakes up
+    /// to check whether it should keep running.
+    ///
+    /// # Panics
+    ///
+    /// If `timeout` is zero.
+    pub fn set_recv_timeout(&mut self, timeout: Duration) {
+        self.recv_timeout = timeout;
+        self.assert_valid();
+    }
 
-    use super::*;
+    /// Gossip state changes to `fanout` randomly chosen peers instead of
+    /// broadcasting them to all. Servers then also send a digest of their
+    /// state to one random peer every receive timeout (anti-entropy), which
+    /// replies with its full state if the digests differ. This is what
+    /// eventually reaches the peers skipped by gossip. Shorthand for
+    /// `set_peer_selector(PeerSelector::RandomFanout { fanout })`.
+    ///
+    /// # Panics
+    ///
+    /// If `fanout` is zero.
+    pub fn set_fanout(&mut self, fanout: usize) {
+        self.set_peer_selector(PeerSelector::RandomFanout { fanout });
+    }
 
-    async fn start_servers(n_server: usize) -> (Configuration, Vec<JoinHandle<BTreeSet<String>>>) {
-        let mut config = Configuration {
-            server_addrs: Vec::new(),
-        };
-        let port = 8000 + rand::thread_rng().gen_range(0..100) * 10;
-        for i in 0..n_server {
-            let addr = format!("127.0.0.1:{}", port + i).parse().unwrap();
-            config.server_addrs.push(addr);
-        }
-        let mut handles = Vec::new();
-        for i in 0..n_server {
-            let c = config.clone();
-            handles.push(tokio::spawn(async move {
-                let mut server = Server::new(&c, i).await;
-                server.run().await;
-                server.state.items
-            }));
-        }
-        (config, handles)
+    /// Set which peers state changes are sent to. Unless they are broadcast
+    /// to all, servers also run anti-entropy as described in `set_fanout`.
+    ///
+    /// # Panics
+    ///
+    /// If the selector's fanout is zero.
+    pub fn set_peer_selector(&mut self, selector: PeerSelector) {
+        self.peer_selector = selector;
+        self.assert_valid();
     }
 
-    async fn collect_states(handles: Vec<JoinHandle<BTreeSet<String>>>) -> Vec<BTreeSet<String>> {
-        let mut states = Vec::new();
-        for handle in handles {
-            states.push(handle.await.unwrap());
-        }
-        states
+    /// Set the order in which peers are sent a broadcast, so that under load
+    /// the same peer is not always the first to receive it.
+    pub fn set_broadcast_order(&mut self, order: BroadcastOrder) {
+        self.broadcast_order = order;
     }
 
-    async fn terminate(config: &Configuration) {
-        let mut client = Client::new(config).await;
-        for i in 0..config.server_addrs.len() {
-            client.terminate(i).await;
-        }
+    /// Only accept server, configuration and terminate messages whose source
+    /// address is one of the configured server addresses; others are dropped
+    /// and counted. Client messages are accepted from anywhere. Servers must
+    /// then be bound to the exact addresses they are listed with, and can
+    /// only be stopped through `Server::running`.
+    pub fn set_strict_peers(&mut self, strict: bool) {
+        self.strict_peers = strict;
     }
 
-    #[tokio::test]
-    async fn single_server() {
-        // Start server
-        let (config, handles) = start_servers(1).await;
-        // Run client
-        let mut client = Client::new(&config).await;
-        client.disseminate("hello").await;
-        // End test
-        tokio::time::sleep(time::Duration::from_millis(100)).await;
-        terminate(&config).await;
-        let states = collect_states(handles).await;
-        assert!(states[0].contains("hello"));
+    /// Expire items `ttl` after they were last disseminated. Items are
+    /// stamped with the wall clock time of the server receiving them from a
+    /// client, the stamps are exchanged with the state and the latest stamp
+    /// of an item wins, so all servers agree on when it expires. Expired
+    /// items are dropped lazily, whenever a server merges or is queried.
+    /// Items that arrive without a stamp, e.g. through a chunked sync or from
+    /// a persistence file, are stamped when first seen.
+    ///
+    /// # Panics
+    ///
+    /// If `ttl` is zero.
+    pub fn set_item_ttl(&mut self, ttl: Duration) {
+        self.item_ttl = Some(ttl);
+        self.assert_valid();
     }
 
-    #[tokio::test]
-    async fn multi_servers() {
-        // Start servers
-        let (config, handles) = start_servers(3).await;
-        // Run client
-        let mut client = Client::new(&config).await;
-        client.disseminate("hello").await;
-        client.disseminate("world").await;
-        // End test
-        tokio::time::sleep(time::Duration::from_millis(100)).await;
-        terminate(&config).await;
-        let states = collect_states(handles).await;
-        assert!(states.iter().all(|s| s.contains("hello")));
-        assert!(states.iter().all(|s| s.contains("world")));
-        assert!(states.iter().all(|s| s.len() == 2));
+    /// Ping peers every receive timeout, and suspect a peer to be down if
+    /// nothing was heard from it for `timeout`. Suspected peers are skipped
+    /// by broadcasts until they answer a ping again.
+    ///
+    /// # Panics
+    ///
+    /// If `timeout` is zero.
+    pub fn set_suspect_after(&mut self, timeout: Duration) {
+        self.suspect_after = Some(timeout);
+        self.assert_valid();
+    }
+
+    /// Set how many received datagrams a server buffers while it is busy
+    /// handling earlier ones. Datagrams arriving at a full mailbox are
+    /// dropped. Takes effect when a server starts running.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is zero.
+    pub fn set_mailbox_capacity(&mut self, capacity: usize) {
+        self.mailbox_capacity = capacity;
+        self.assert_valid();
+    }
+
+    /// Record the addresses of the clients each item was disseminated from,
+    /// and exchange them with the state, see `Server::provenance`.
+    pub fn set_track_provenance(&mut self, track: bool) {
+        self.track_provenance = track;
+    }
+
+    /// Record the clock at which each item was added, and exchange it with
+    /// the state, see `Server::items_in_causal_order`.
+    pub fn set_causal_order(&mut self, enabled: bool) {
+        self.causal_order = enabled;
+    }
+
+    /// Set how servers combine their items, see [`MergeMode`].
+    pub fn set_merge_mode(&mut self, mode: MergeMode) {
+        self.merge_mode = mode;
+    }
+
+    /// Set how long a server keeps the chunks of an incomplete chunked state
+    /// sync after the last one arrived, before it discards them.
+    ///
+    /// # Panics
+    ///
+    /// If `timeout` is zero.
+    pub fn set_chunk_timeout(&mut self, timeout: Duration) {
+        self.chunk_timeout = timeout;
+        self.assert_valid();
+    }
+
+    /// Drop the items received from clients that are longer than `len`
+    /// bytes, so that a single oversized item is not broadcast to every
+    /// peer. Drops are counted, see `Server::oversized_items`.
+    ///
+    /// # Panics
+    ///
+    /// If `len` is zero.
+    pub fn set_max_item_len(&mut self, len: usize) {
+        self.max_item_len = Some(len);
+        self.assert_valid();
+    }
+
+    /// Refuse joins once a server has `max` peers. Refusals are counted, see
+    /// `Server::rejected_joins`.
+    ///
+    /// # Panics
+    ///
+    /// If `max` is below the number of configured peers.
+    pub fn set_max_peers(&mut self, max: usize) {
+        self.max_peers = Some(max);
+        self.assert_valid();
+    }
+
+    /// Set how state digests are computed for anti-entropy, see
+    /// `DigestAlgorithm`.
+    ///
+    /// # Panics
+    ///
+    /// If a truncated digest length is not between 4 and 32.
+    pub fn set_digest_algorithm(&mut self, algorithm: DigestAlgorithm) {
+        self.digest_algorithm = algorithm;
+        self.assert_valid();
+    }
+}
+
+/// Items a server's state grew by, in one handled message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    pub added: Vec<String>,
+}
+
+/// Outcome of sending a message to several peers.
+#[derive(Debug, Default)]
+pub struct BroadcastReport {
+    /// Number of peers the message was sent to.
+    pub sent: usize,
+    /// The address of each peer the message could not be sent to, and why.
+    pub failed: Vec<(String, io::Error)>,
+}
+
+/// Liveness of a peer, as seen by a server's failure detector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerStatus {
+    Alive,
+    /// Nothing was heard from the peer for longer than the configured
+    /// timeout, or sending to it failed since it was last heard from.
+    Suspected,
+    /// The peer announced it was shutting down, and is skipped by
+    /// broadcasts until its state or a ping is received from it again.
+    Left,
+}
+
+/// On-disk layout of a TOML configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    server_addrs: Vec<String>,
+    recv_timeout_ms: Option<u64>,
+    peer_selector: Option<String>,
+    fanout: Option<usize>,
+    broadcast_order: Option<String>,
+    broadcast_seed: Option<u64>,
+    strict_peers: Option<bool>,
+    item_ttl_ms: Option<u64>,
+    suspect_after_ms: Option<u64>,
+    mailbox_capacity: Option<usize>,
+    track_provenance: Option<bool>,
+    causal_order: Option<bool>,
+    merge_mode: Option<String>,
+    chunk_timeout_ms: Option<u64>,
+    max_item_len: Option<usize>,
+    max_peers: Option<usize>,
+    digest_algorithm: Option<String>,
+    digest_len: Option<usize>,
+}
+
+/// Client message type for the accumulator application. Each message contains
+/// a string.
+#[derive(Serialize, Deserialize, Debug)]
+struct ClientMessage {
+    item: String,
+    /// Socket address the client is reached at, when it differs from the
+    /// source of its datagrams, e.g. behind a NAT or proxy. Servers reply
+    /// there and record it as the item's source, falling back to the
+    /// datagram source if it is absent or not a socket address.
+    #[serde(default)]
+    reply_to: Option<String>,
+}
+
+/// Where to reply to a client whose datagram came from `src`.
+fn reply_addr(reply_to: Option<&str>, src: SocketAddr) -> SocketAddr {
+    reply_to.and_then(|addr| addr.parse().ok()).unwrap_or(src)
+}
+
+/// Server message type for the accumulator application.
+#[derive(Serialize, Deserialize, Debug)]
+enum ServerMessage {
+    /// The current node state, which is a set of strings.
+    State(ServerState),
+    /// One piece of a full state sync that was split into several messages.
+    /// The receiver reassembles all `total` chunks before merging.
+    StateChunk {
+        clock: Clock,
+        id: u128,
+        seq: usize,
+        total: usize,
+        items: Vec<String>,
+    },
+    /// The changes of the add-wins set of server `id` since its last
+    /// broadcast, in add-wins mode. Receivers join it into their set without
+    /// passing it on.
+    Delta {
+        clock: Clock,
+        id: u128,
+        delta: AddWinsSet,
+    },
+    /// Digest of the state of the server at `index`. A receiver whose own
+    /// digest differs replies with its full state.
+    Digest {
+        index: usize,
+        digest: Vec<u8>,
+        #[serde(default)]
+        algorithm: DigestAlgorithm,
+    },
+    /// Heartbeat of the server at `index`, answered with a `Pong`.
+    Ping {
+        index: usize,
+    },
+    Pong {
+        index: usize,
+    },
+    /// The server at `index` is shutting down, see `Server::close`.
+    Leave {
+        index: usize,
+    },
+}
+
+/// A client node for the accumulator application.
+pub struct Client {
+    socket: UdpSocket,
+    config: Configuration,
+    reply_to: Option<SocketAddr>,
+    policy: ClientPolicy,
+    /// Disseminations sent so far, for `ClientPolicy::RoundRobin`.
+    sent: usize,
+}
+
+/// Which server a client disseminates an item to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientPolicy {
+    /// Always the first server.
+    #[default]
+    First,
+    /// A server chosen at random for each item.
+    Random,
+    /// Each server in turn.
+    RoundRobin,
+    /// The server the item hashes to, so the same item always goes to the
+    /// same server.
+    HashByItem,
+}
+
+impl Client {
+    /// Create a new client. Fails if the configuration has no server
+    /// addresses.
+    pub async fn new(config: &Configuration) -> io::Result<Self> {
+        if config.server_addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "configuration has no server addresses",
+            ));
+        }
+        let s = UdpSocket::bind("0.0.0.0:0").await?;
+        Ok(Self {
+            socket: s,
+            config: config.clone(),
+            reply_to: None,
+            policy: ClientPolicy::default(),
+            sent: 0,
+        })
+    }
+
+    /// Create a new client disseminating to servers chosen by `policy`,
+    /// spreading the load of many clients over the servers.
+    pub async fn with_policy(config: &Configuration, policy: ClientPolicy) -> io::Result<Self> {
+        let mut client = Self::new(config).await?;
+        client.policy = policy;
+        Ok(client)
+    }
+
+    /// The server to disseminate `item` to.
+    fn target(&mut self, item: &str) -> SocketAddr {
+        let addrs = &self.config.server_addrs;
+        let index = match self.policy {
+            ClientPolicy::First => 0,
+            ClientPolicy::Random => rand::random::<usize>() % addrs.len(),
+            ClientPolicy::RoundRobin => self.sent % addrs.len(),
+            ClientPolicy::HashByItem => {
+                let hash: [u8; 32] = Sha256::digest(item.as_bytes()).into();
+                (u64::from_le_bytes(hash[..8].try_into().unwrap()) % addrs.len() as u64) as usize
+            }
+        };
+        self.sent = self.sent.wrapping_add(1);
+        addrs[index]
+    }
+
+    /// Ask servers to send replies to `addr` instead of the address this
+    /// client's datagrams come from, e.g. its public address behind a NAT.
+    /// `None` restores replying to the datagram source. Replies sent
+    /// elsewhere only reach `query` and `disseminate_confirmed` if they are
+    /// forwarded to this client's socket.
+    pub fn set_reply_to(&mut self, addr: Option<SocketAddr>) {
+        self.reply_to = addr;
+    }
+
+    fn reply_to(&self) -> Option<String> {
+        self.reply_to.map(|addr| addr.to_string())
+    }
+
+    /// Send a message to `addr`.
+    async fn send_message(&self, msg: &Message, addr: SocketAddr) -> io::Result<()> {
+        send_datagram(&self.socket, &encode(msg)?, addr).await
+    }
+
+    /// Disseminate a string to the accumulator network, through the server
+    /// chosen by the client's policy.
+    pub async fn disseminate(&mut self, item: &str) -> io::Result<()> {
+        self.disseminate_to_target(item).await.map(|_| ())
+    }
+
+    /// Disseminate a string, returning the server it was sent to.
+    async fn disseminate_to_target(&mut self, item: &str) -> io::Result<SocketAddr> {
+        let msg = Message::FromClient(ClientMessage {
+            item: String::from(item),
+            reply_to: self.reply_to(),
+        });
+        let target = self.target(item);
+        self.send_message(&msg, target).await?;
+        Ok(target)
+    }
+
+    /// Disseminate a string and wait until `w` servers hold it, the receiving
+    /// one included, which asks its peers for acknowledgements. Fails with
+    /// `InvalidInput` if the item is rejected, or with `TimedOut` if the
+    /// quorum is not reached within a few receive timeouts.
+    pub async fn disseminate_quorum(&mut self, item: &str, w: usize) -> io::Result<()> {
+        let deadline = Instant::now() + self.config.recv_timeout * QUORUM_WRITE_ROUNDS;
+        let msg = Message::DisseminateQuorum {
+            item: String::from(item),
+            w,
+            reply_to: self.reply_to(),
+        };
+        let target = self.target(item);
+        self.send_message(&msg, target).await?;
+        let mut buf = [0; 1500];
+        while let Ok(recv) =
+            tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await
+        {
+            let Ok((n, _)) = recv else { continue };
+            match decode(&buf[..n]) {
+                Ok(Message::QuorumAck { item: acked }) if acked == item => return Ok(()),
+                Ok(Message::Rejected {
+                    item: rejected,
+                    reason,
+                }) if rejected == item => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{item:?} rejected: {reason}"),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{item:?} not held by {w} servers in time"),
+        ))
+    }
+
+    /// Add `delta` to the counter of `key`, and wait for the server to
+    /// apply it. Fails with `InvalidInput` if `delta` exceeds `i64::MAX` or
+    /// the server rejects it because its tally would overflow, or with
+    /// `TimedOut` if the server does not answer in time.
+    pub async fn increment(&mut self, key: &str, delta: u64) -> io::Result<()> {
+        let delta = counter_delta(delta)?;
+        self.add_to_counter(key, delta).await
+    }
+
+    /// Subtract `delta` from the counter of `key`, failing as `increment`
+    /// does.
+    pub async fn decrement(&mut self, key: &str, delta: u64) -> io::Result<()> {
+        let delta = counter_delta(delta)?;
+        self.add_to_counter(key, -delta).await
+    }
+
+    /// Remove a string from the accumulator network, through the server
+    /// chosen by the client's policy. Servers only remove items in add-wins
+    /// mode, and reject the remove otherwise. A remove only cancels the adds
+    /// of the item that the server has seen, so an add concurrent with it
+    /// wins.
+    pub async fn remove(&mut self, item: &str) -> io::Result<()> {
+        let msg = Message::Remove {
+            item: String::from(item),
+        };
+        let target = self.target(item);
+        self.send_message(&msg, target).await
+    }
+
+    /// Ask all servers to add the server at `addr` to their peer list.
+    pub async fn join(&mut self, addr: &str) -> io::Result<()> {
+        let msg = Message::Join {
+            addr: String::from(addr),
+        };
+        for server in self.config.server_addrs.clone() {
+            self.send_message(&msg, server).await?;
+        }
+        Ok(())
+    }
+
+    async fn add_to_counter(&mut self, key: &str, delta: i64) -> io::Result<()> {
+        let deadline = Instant::now() + self.config.recv_timeout * QUORUM_WRITE_ROUNDS;
+        let msg = Message::Increment {
+            key: String::from(key),
+            delta,
+        };
+        self.send_message(&msg, self.config.server_addrs[0]).await?;
+        let mut buf = [0; 1500];
+        while let Ok(recv) =
+            tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await
+        {
+            let Ok((n, _)) = recv else { continue };
+            match decode(&buf[..n]) {
+                Ok(Message::Counted { key: counted }) if counted == key => return Ok(()),
+                Ok(Message::Rejected {
+                    item: rejected,
+                    reason,
+                }) if rejected == key => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("adding {delta} to {key:?} rejected: {reason}"),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("adding {delta} to {key:?} not confirmed in time"),
+        ))
+    }
+
+    /// Disseminate a string and wait until the receiving server confirms it
+    /// holds it, which is immediate if it was already present. Fails with
+    /// `InvalidInput` and the server's reason if it rejects the item, or with
+    /// `TimedOut` if no confirmation arrives within `timeout`.
+    pub async fn disseminate_confirmed(&mut self, item: &str, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let target = self.disseminate_to_target(item).await?;
+        let query = Message::Query {
+            item: String::from(item),
+            repair: false,
+            reply_to: self.reply_to(),
+        };
+        while Instant::now() < deadline {
+            self.send_message(&query, target).await?;
+            let poll_deadline = cmp::min(deadline, Instant::now() + CONFIRM_POLL_INTERVAL);
+            let mut buf = [0; 1500];
+            while let Ok(recv) =
+                tokio::time::timeout_at(poll_deadline, self.socket.recv_from(&mut buf)).await
+            {
+                let Ok((n, _)) = recv else { continue };
+                match decode(&buf[..n]) {
+                    // replies to earlier queries may still arrive
+                    Ok(Message::QueryReply {
+                        item: reply_item,
+                        present: true,
+                    }) if reply_item == item => return Ok(()),
+                    Ok(Message::Rejected {
+                        item: rejected,
+                        reason,
+                    }) if rejected == item => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("{item:?} rejected: {reason}"),
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{item:?} not confirmed within {timeout:?}"),
+        ))
+    }
+
+    /// Ask the first server whether it holds `item`. With `read_repair`, the
+    /// server first pulls items it misses from its peers, which delays the
+    /// answer. Fails with `TimedOut` if no answer arrives within `timeout`.
+    pub async fn query(
+        &mut self,
+        item: &str,
+        read_repair: bool,
+        timeout: Duration,
+    ) -> io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let query = Message::Query {
+            item: String::from(item),
+            repair: read_repair,
+            reply_to: self.reply_to(),
+        };
+        self.send_message(&query, self.config.server_addrs[0])
+            .await?;
+        let mut buf = [0; 1500];
+        while let Ok(recv) =
+            tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await
+        {
+            let Ok((n, _)) = recv else { continue };
+            if let Ok(Message::QueryReply {
+                item: reply_item,
+                present,
+            }) = decode(&buf[..n])
+            {
+                if reply_item == item {
+                    return Ok(present);
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("query for {item:?} not answered within {timeout:?}"),
+        ))
+    }
+
+    /// Ask the servers at `addrs` for the items they hold, and return the
+    /// items held by at least `quorum_size` of them. Servers not answering
+    /// within the receive timeout count as holding nothing.
+    pub async fn query_quorum(
+        &mut self,
+        addrs: &[SocketAddr],
+        quorum_size: usize,
+    ) -> io::Result<HashSet<String>> {
+        let mut votes: HashMap<String, usize> = HashMap::new();
+        for items in self.list_items(addrs).await?.into_values() {
+            for item in items {
+                *votes.entry(item).or_default() += 1;
+            }
+        }
+        Ok(votes
+            .into_iter()
+            .filter(|(_, n)| *n >= quorum_size)
+            .map(|(item, _)| item)
+            .collect())
+    }
+
+    /// Ask the servers at `addr_a` and `addr_b` for the items they hold, and
+    /// return the items only held by the first and the items only held by
+    /// the second, which are both empty once they converged. Fails with
+    /// `TimedOut` if either does not answer within the receive timeout.
+    pub async fn diff(
+        &mut self,
+        addr_a: SocketAddr,
+        addr_b: SocketAddr,
+    ) -> io::Result<(HashSet<String>, HashSet<String>)> {
+        let mut lists = self.list_items(&[addr_a, addr_b]).await?;
+        let (Some(a), Some(b)) = (lists.remove(&addr_a), lists.remove(&addr_b)) else {
+            return Err(io::ErrorKind::TimedOut.into());
+        };
+        Ok((
+            a.difference(&b).cloned().collect(),
+            b.difference(&a).cloned().collect(),
+        ))
+    }
+
+    /// Ask the servers at `addrs` for the items they hold, and return the
+    /// answers received within the receive timeout, by address.
+    async fn list_items(
+        &mut self,
+        addrs: &[SocketAddr],
+    ) -> io::Result<HashMap<SocketAddr, HashSet<String>>> {
+        let deadline = Instant::now() + self.config.recv_timeout;
+        for &addr in addrs {
+            self.send_message(&Message::ListItems, addr).await?;
+        }
+        let mut answers = HashMap::new();
+        // item lists may be larger than other replies
+        let mut buf = vec![0; 65536];
+        while answers.len() < addrs.len() {
+            let Ok(recv) = tokio::time::timeout_at(deadline, self.socket.recv_from(&mut buf)).await
+            else {
+                break;
+            };
+            let Ok((n, src)) = recv else { continue };
+            if !addrs.contains(&src) || answers.contains_key(&src) {
+                continue;
+            }
+            if let Ok(Message::ItemList { items }) = decode(&buf[..n]) {
+                answers.insert(src, items.into_iter().collect());
+            }
+        }
+        Ok(answers)
+    }
+
+    /// Push a new configuration to every server it lists, and use it for this
+    /// client from now on. Servers dropped from the peer list are not
+    /// notified.
+    pub async fn push_config(&mut self, config: &Configuration) -> io::Result<()> {
+        let buf = encode(&Message::Config(config.clone()))?;
+        for addr in &config.server_addrs {
+            send_datagram(&self.socket, &buf, *addr).await?;
+        }
+        self.config = config.clone();
+        Ok(())
+    }
+
+    /// Terminate a running accumulator server.
+    pub async fn terminate(&mut self, index: usize) -> io::Result<()> {
+        self.send_message(&Message::Terminate, self.config.server_addrs[index])
+            .await
+    }
+
+    /// Terminate the servers at `addrs`, e.g. a whole cluster. Keeps going
+    /// past failed sends, and returns the outcome for each address.
+    pub async fn terminate_all(&mut self, addrs: &[SocketAddr]) -> Vec<(String, io::Result<()>)> {
+        let mut results = Vec::new();
+        for &addr in addrs {
+            let result = self.send_message(&Message::Terminate, addr).await;
+            results.push((addr.to_string(), result));
+        }
+        results
+    }
+}
+
+/// State of a server node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ServerState {
+    clock: Clock,
+    id: u128,
+    items: BTreeSet<String>,
+    /// Time items were last disseminated, in milliseconds since the Unix
+    /// epoch. Only tracked when items expire.
+    #[serde(default)]
+    stamps: BTreeMap<String, u64>,
+    /// Addresses of the clients each item was disseminated from. Only
+    /// tracked when enabled in the configuration.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted_provenance"
+    )]
+    provenance: HashMap<String, HashSet<String>>,
+    /// Clock of the state at which each item was first added. Only tracked
+    /// when causal ordering is enabled in the configuration.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    added_at: BTreeMap<String, OrdinaryClock>,
+    /// Items disseminated through each node, by node id. Only tracked in
+    /// intersection mode.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    inputs: BTreeMap<u128, BTreeSet<String>>,
+    /// Counters incremented by clients, by key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    counters: BTreeMap<String, PnCounter>,
+    /// The items with their dots and causal context. Only tracked in add-wins
+    /// mode.
+    #[serde(default, skip_serializing_if = "is_default")]
+    set: AddWinsSet,
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Counter that can be incremented and decremented concurrently on several
+/// nodes: each node tallies its own increments and decrements, tallies
+/// merge by maximum, and the value is the difference of their sums.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct PnCounter {
+    increments: BTreeMap<u128, u64>,
+    decrements: BTreeMap<u128, u64>,
+}
+
+impl PnCounter {
+    /// The difference of the sums of the tallies. Sums of up to 2^63 tallies
+    /// fit an `i128`.
+    fn value(&self) -> i128 {
+        let sum = |tallies: &BTreeMap<u128, u64>| -> i128 {
+            tallies.values().map(|tally| i128::from(*tally)).sum()
+        };
+        sum(&self.increments) - sum(&self.decrements)
+    }
+
+    /// Add `delta` on the node `id`. Returns false, leaving the tally as it
+    /// is, if it would overflow: neither wrapping, which would lose every
+    /// merge by maximum, nor saturating, which would drop counts silently.
+    fn add(&mut self, id: u128, delta: i64) -> bool {
+        let tallies = if delta >= 0 {
+            &mut self.increments
+        } else {
+            &mut self.decrements
+        };
+        let tally = tallies.entry(id).or_default();
+        match tally.checked_add(delta.unsigned_abs()) {
+            Some(sum) => {
+                *tally = sum;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Merge another counter's tallies. Returns true if the counter changed.
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (own, theirs) in [
+            (&mut self.increments, &other.increments),
+            (&mut self.decrements, &other.decrements),
+        ] {
+            for (id, tally) in theirs {
+                let own = own.entry(*id).or_default();
+                if *tally > *own {
+                    *own = *tally;
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// A counter delta as sent to servers, which must fit an `i64`.
+fn counter_delta(delta: u64) -> io::Result<i64> {
+    i64::try_from(delta).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("counter delta {delta} exceeds {}", i64::MAX),
+        )
+    })
+}
+
+/// Serialize provenance ordered by item and source, so that, with the clock
+/// and the other fields serialized in order as well, equal states always
+/// encode to the same bytes.
+fn serialize_sorted_provenance<S: serde::Serializer>(
+    provenance: &HashMap<String, HashSet<String>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let sorted: BTreeMap<&String, BTreeSet<&String>> = provenance
+        .iter()
+        .map(|(item, sources)| (item, sources.iter().collect()))
+        .collect();
+    sorted.serialize(serializer)
+}
+
+impl ServerState {
+    /// Create a new server state.
+    fn new(id: u128) -> Self {
+        Self {
+            clock: Clock::new(),
+            id,
+            items: BTreeSet::new(),
+            stamps: BTreeMap::new(),
+            provenance: HashMap::new(),
+            added_at: BTreeMap::new(),
+            inputs: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            set: AddWinsSet::new(),
+        }
+    }
+
+    /// Record `item` as disseminated through this node. Returns true if
+    /// resulting in a new state.
+    fn add_input(&mut self, item: String) -> bool {
+        let new_input = self.inputs.entry(self.id).or_default().insert(item);
+        if new_input {
+            self.clock.inc(self.id);
+        }
+        new_input
+    }
+
+    /// Add `item` to the add-wins set. Returns true if resulting in a new
+    /// state.
+    fn add_wins(&mut self, item: &str) -> bool {
+        if self.set.contains(item) {
+            return false;
+        }
+        self.set.add(self.id, item);
+        self.clock.inc(self.id);
+        true
+    }
+
+    /// Remove `item` from the add-wins set. Returns true if resulting in a
+    /// new state.
+    fn remove(&mut self, item: &str) -> bool {
+        if !self.set.contains(item) {
+            return false;
+        }
+        self.set.remove(item);
+        self.clock.inc(self.id);
+        true
+    }
+
+    /// Hold the items disseminated through every one of the nodes `0..n`.
+    fn intersect(&mut self, n: usize) {
+        let mut inputs = (0..n as u128).map(|id| self.inputs.get(&id));
+        let Some(Some(first)) = inputs.next() else {
+            self.items.clear();
+            return;
+        };
+        let mut items = first.clone();
+        for other in inputs {
+            match other {
+                Some(other) => items.retain(|item| other.contains(item)),
+                None => items.clear(),
+            }
+        }
+        self.items = items;
+    }
+
+    /// Add `delta` to the counter of `key`. Returns true if resulting in a
+    /// new state, or the reason the state is unchanged if the tally of this
+    /// node would overflow.
+    fn increment(&mut self, key: &str, delta: i64) -> Result<bool, String> {
+        if delta == 0 {
+            return Ok(false);
+        }
+        let id = self.id;
+        let counter = self.counters.entry(key.to_string()).or_default();
+        if !counter.add(id, delta) {
+            return Err(format!("the tally of node {id} would overflow"));
+        }
+        self.clock.inc(self.id);
+        Ok(true)
+    }
+
+    /// Add items into the state. Returns true if resulting in a new state.
+    fn add(&mut self, items: BTreeSet<String>) -> bool {
+        if items.is_subset(&self.items) {
+            false
+        } else {
+            self.items.extend(items);
+            self.clock.inc(self.id);
+            true
+        }
+    }
+
+    /// Record that `item` was disseminated from `source`. Returns true if the
+    /// source is new for the item, which is a new state even if the item was
+    /// held already, so that the source spreads to other nodes.
+    fn add_source(&mut self, item: &str, source: String) -> bool {
+        let new_source = self
+            .provenance
+            .entry(item.to_string())
+            .or_default()
+            .insert(source);
+        if new_source && self.items.contains(item) {
+            self.clock.inc(self.id);
+        }
+        new_source
+    }
+
+    /// Record the current clock as the time `item` was added, unless it was
+    /// added before.
+    fn stamp_added(&mut self, item: &str) {
+        if !self.added_at.contains_key(item) {
+            let clock = OrdinaryClock::try_from(&self.clock).unwrap_or_default();
+            self.added_at.insert(item.to_string(), clock);
+        }
+    }
+
+    /// Add an item, or refresh the stamp of an existing one, at time `now`.
+    /// Always results in a new state.
+    fn touch(&mut self, item: String, now: u64) {
+        self.stamps.insert(item.clone(), now);
+        self.items.insert(item);
+        self.clock.inc(self.id);
+    }
+
+    /// Drop the items stamped `ttl` or longer before `now`, and stamp the
+    /// items that have no stamp yet. Returns true if any item was dropped.
+    fn expire(&mut self, ttl: Duration, now: u64) -> bool {
+        let ttl = ttl.as_millis() as u64;
+        for item in &self.items {
+            self.stamps.entry(item.clone()).or_insert(now);
+        }
+        let expired: Vec<String> = self
+            .stamps
+            .iter()
+            .filter(|(_, stamp)| now.saturating_sub(**stamp) >= ttl)
+            .map(|(item, _)| item.clone())
+            .collect();
+        for item in &expired {
+            self.stamps.remove(item);
+            self.items.remove(item);
+            self.provenance.remove(item);
+            self.added_at.remove(item);
+        }
+        !expired.is_empty()
+    }
+
+    /// Merge another ServerState into the current state. Returns true if
+    /// resulting in a new state (different from current and received
+    /// state).
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut new_input = false;
+        for (id, items) in &other.inputs {
+            let own = self.inputs.entry(*id).or_default();
+            let len = own.len();
+            own.extend(items.iter().cloned());
+            new_input |= own.len() > len;
+        }
+        for (key, counter) in &other.counters {
+            new_input |= self.counters.entry(key.clone()).or_default().merge(counter);
+        }
+        new_input |= self.set.merge(&other.set);
+        for (item, sources) in &other.provenance {
+            let own = self.provenance.entry(item.clone()).or_default();
+            own.extend(sources.iter().cloned());
+        }
+        // an item added concurrently on several nodes keeps the first clock,
+        // in the same order on every node
+        for (item, clock) in &other.added_at {
+            match self.added_at.get(item) {
+                Some(own) if causal_key(own) <= causal_key(clock) => {}
+                _ => {
+                    self.added_at.insert(item.clone(), clock.clone());
+                }
+            }
+        }
+        match self.clock.partial_cmp(&other.clock) {
+            Some(cmp::Ordering::Equal) => false,
+            Some(cmp::Ordering::Greater) => false,
+            Some(cmp::Ordering::Less) => {
+                self.clock = other.clock.clone();
+                self.items = other.items.clone();
+                self.stamps = other.stamps.clone();
+                false
+            }
+            None => {
+                self.clock.merge(&vec![&other.clock]);
+                for (item, stamp) in &other.stamps {
+                    let own = self.stamps.entry(item.clone()).or_default();
+                    *own = (*own).max(*stamp);
+                }
+                // concurrent inputs leave a state that differs from both
+                self.add(other.items.clone()) || new_input
+            }
+        }
+    }
+}
+
+/// Total order extending the causal order of clocks: a clock that happened
+/// before another has a smaller sum of counts. Ties are broken by hash.
+fn causal_key(clock: &OrdinaryClock) -> (u64, [u8; 32]) {
+    (clock.reduce(), clock.calculate_sha256())
+}
+
+/// Current wall clock time in milliseconds since the Unix epoch.
+fn unix_millis() -> u64 {
+    SystemTime::UNIX_EPOCH
+        .elapsed()
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A client's item waiting for `w` servers to hold it.
+struct QuorumWrite {
+    /// The item as submitted, and as held, i.e. normalized.
+    item: String,
+    held: String,
+    w: usize,
+    client: SocketAddr,
+    /// Peers that hold the item, by index.
+    acks: HashSet<usize>,
+    deadline: Instant,
+}
+
+impl QuorumWrite {
+    /// Whether `w` servers hold the item, counting the receiving one.
+    fn is_complete(&self) -> bool {
+        self.acks.len() + 1 >= self.w
+    }
+}
+
+/// Builder of a [`Server`], collecting its options before binding the
+/// socket.
+pub struct ServerBuilder {
+    config: Configuration,
+    index: usize,
+    persistence: Option<(PathBuf, PersistencePolicy)>,
+    normalizer: Option<fn(&str) -> String>,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+impl ServerBuilder {
+    /// Start building the server at `index` of `config`.
+    pub fn new(config: &Configuration, index: usize) -> Self {
+        Self {
+            config: config.clone(),
+            index,
+            persistence: None,
+            normalizer: None,
+            transport: None,
+        }
+    }
+
+    /// See [`Configuration::set_recv_timeout`].
+    pub fn recv_timeout(mut self, timeout: Duration) -> Self {
+        self.config.set_recv_timeout(timeout);
+        self
+    }
+
+    /// See [`Configuration::set_fanout`].
+    pub fn fanout(mut self, fanout: usize) -> Self {
+        self.config.set_fanout(fanout);
+        self
+    }
+
+    /// See [`Configuration::set_peer_selector`].
+    pub fn peer_selector(mut self, selector: PeerSelector) -> Self {
+        self.config.set_peer_selector(selector);
+        self
+    }
+
+    /// See [`Configuration::set_broadcast_order`].
+    pub fn broadcast_order(mut self, order: BroadcastOrder) -> Self {
+        self.config.set_broadcast_order(order);
+        self
+    }
+
+    /// See [`Configuration::set_strict_peers`].
+    pub fn strict_peers(mut self, strict: bool) -> Self {
+        self.config.set_strict_peers(strict);
+        self
+    }
+
+    /// See [`Configuration::set_item_ttl`].
+    pub fn item_ttl(mut self, ttl: Duration) -> Self {
+        self.config.set_item_ttl(ttl);
+        self
+    }
+
+    /// See [`Configuration::set_suspect_after`].
+    pub fn suspect_after(mut self, timeout: Duration) -> Self {
+        self.config.set_suspect_after(timeout);
+        self
+    }
+
+    /// See [`Configuration::set_mailbox_capacity`].
+    pub fn mailbox_capacity(mut self, capacity: usize) -> Self {
+        self.config.set_mailbox_capacity(capacity);
+        self
+    }
+
+    /// See [`Configuration::set_track_provenance`].
+    pub fn track_provenance(mut self, track: bool) -> Self {
+        self.config.set_track_provenance(track);
+        self
+    }
+
+    /// See [`Configuration::set_causal_order`].
+    pub fn causal_order(mut self, enabled: bool) -> Self {
+        self.config.set_causal_order(enabled);
+        self
+    }
+
+    /// See [`Configuration::set_merge_mode`].
+    pub fn merge_mode(mut self, mode: MergeMode) -> Self {
+        self.config.set_merge_mode(mode);
+        self
+    }
+
+    /// See [`Configuration::set_chunk_timeout`].
+    pub fn chunk_timeout(mut self, timeout: Duration) -> Self {
+        self.config.set_chunk_timeout(timeout);
+        self
+    }
+
+    /// See [`Configuration::set_max_item_len`].
+    pub fn max_item_len(mut self, len: usize) -> Self {
+        self.config.set_max_item_len(len);
+        self
+    }
+
+    /// See [`Configuration::set_digest_algorithm`].
+    pub fn digest_algorithm(mut self, algorithm: DigestAlgorithm) -> Self {
+        self.config.set_digest_algorithm(algorithm);
+        self
+    }
+
+    /// See [`Server::set_transport`].
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// See [`Server::set_persistence`].
+    pub fn persistence(mut self, path: impl Into<PathBuf>, policy: PersistencePolicy) -> Self {
+        self.persistence = Some((path.into(), policy));
+        self
+    }
+
+    /// See [`Server::set_normalizer`].
+    pub fn normalizer(mut self, normalize: fn(&str) -> String) -> Self {
+        self.normalizer = Some(normalize);
+        self
+    }
+
+    /// Bind the server's socket and restore its persisted state, if any.
+    pub async fn build(self) -> io::Result<Server> {
+        let mut server = Server::new(&self.config, self.index).await?;
+        if let Some(normalize) = self.normalizer {
+            server.set_normalizer(normalize);
+        }
+        if let Some(transport) = self.transport {
+            server.set_transport(transport);
+        }
+        if let Some((path, policy)) = self.persistence {
+            server.set_persistence(path, policy)?;
+        }
+        Ok(server)
+    }
+}
+
+/// Datagrams received by a background task, waiting to be handled. The task
+/// stops when the mailbox is dropped.
+struct Mailbox {
+    receiver: mpsc::Receiver<(Vec<u8>, SocketAddr)>,
+    task: JoinHandle<()>,
+    /// Buffers of handled datagrams, reused for the next ones.
+    spare: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    capacity: usize,
+    /// Buffers allocated because there was no spare one.
+    allocations: Arc<AtomicUsize>,
+}
+
+impl Mailbox {
+    /// Start receiving from `socket` into a mailbox of `capacity` datagrams,
+    /// counting the ones dropped because it was full in `dropped`.
+    fn spawn(socket: Arc<UdpSocket>, capacity: usize, dropped: Arc<AtomicUsize>) -> Self {
+        let capacity = capacity.max(1);
+        let (sender, receiver) = mpsc::channel(capacity);
+        let spare = Arc::new(std::sync::Mutex::new(Vec::<Vec<u8>>::new()));
+        let allocations = Arc::new(AtomicUsize::new(0));
+        let task_spare = spare.clone();
+        let task_allocations = allocations.clone();
+        let task = tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            loop {
+                // transient socket errors are skipped, like in the event loop
+                let Ok((n, src)) = socket.recv_from(&mut buf).await else {
+                    continue;
+                };
+                let mut datagram = task_spare.lock().unwrap().pop().unwrap_or_else(|| {
+                    task_allocations.fetch_add(1, Ordering::Relaxed);
+                    Vec::with_capacity(buf.len())
+                });
+                datagram.clear();
+                datagram.extend_from_slice(&buf[..n]);
+                match sender.try_send((datagram, src)) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => return,
+                }
+            }
+        });
+        Self {
+            receiver,
+            task,
+            spare,
+            capacity,
+            allocations,
+        }
+    }
+
+    /// Hand back the buffer of a handled datagram for reuse.
+    fn recycle(&self, buf: Vec<u8>) {
+        let mut spare = self.spare.lock().unwrap();
+        if spare.len() < self.capacity {
+            spare.push(buf);
+        }
+    }
+}
+
+impl Drop for Mailbox {
+    fn drop(&mut self) {
+        self.task.abort()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.flush()
+    }
+}
+
+/// Chunks of a state sync received so far from one peer.
+struct ChunkBuffer {
+    clock: Clock,
+    total: usize,
+    chunks: BTreeMap<usize, Vec<String>>,
+    /// Bytes of the buffered items.
+    bytes: usize,
+    /// When the last chunk arrived.
+    updated: Instant,
+}
+
+impl ChunkBuffer {
+    fn new(clock: Clock, total: usize) -> Self {
+        Self {
+            clock,
+            total,
+            chunks: BTreeMap::new(),
+            bytes: 0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+/// An accumulator server node. Each node maintains a UDP socket, and a set of
+/// strings as its internal state.
+pub struct Server {
+    config: Configuration,
+    index: usize,
+    socket: Arc<UdpSocket>,
+    transport: Arc<dyn Transport>,
+    state: ServerState,
+    running: Arc<AtomicBool>,
+    chunks: HashMap<u128, ChunkBuffer>,
+    /// Bytes buffered across `chunks`.
+    chunk_bytes: usize,
+    broadcasts: usize,
+    rng: StdRng,
+    spoofed: usize,
+    rejected: usize,
+    incompatible: usize,
+    oversized_items: usize,
+    rejected_joins: usize,
+    persistence: Option<Persistence>,
+    normalizer: Option<fn(&str) -> String>,
+    /// Whether the state is frozen, see `seal`.
+    sealed: bool,
+    /// Read-repairing queries waiting for peer states: item, asker and when
+    /// to answer.
+    repairs: Vec<(String, SocketAddr, Instant)>,
+    quorum_writes: Vec<QuorumWrite>,
+    dropped: Arc<AtomicUsize>,
+    receive_buffers: usize,
+    subscribers: Vec<mpsc::Sender<StateChange>>,
+    lagged: usize,
+    /// When each peer was last heard from, by index. Peers never heard from
+    /// count from when the server was created.
+    last_heard: HashMap<usize, Instant>,
+    /// Peers that announced they were shutting down, by index.
+    left: HashSet<usize>,
+    /// Peers a send failed to since they were last heard from, by index.
+    unreachable: HashSet<usize>,
+    created: Instant,
+}
+
+impl Server {
+    /// Create a new server. Fails if `index` does not name one of the
+    /// configured server addresses.
+    pub async fn new(config: &Configuration, index: usize) -> io::Result<Self> {
+        let Some(addr) = config.server_addrs.get(index) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "server index {index} out of range for {} configured addresses",
+                    config.server_addrs.len()
+                ),
+            ));
+        };
+        let s = UdpSocket::bind(addr).await?;
+        let seed = match config.broadcast_order {
+            BroadcastOrder::Shuffled { seed } => seed,
+            _ => 0,
+        };
+        Ok(Self {
+            config: config.clone(),
+            index,
+            socket: Arc::new(s),
+            transport: Arc::new(UdpTransport),
+            state: ServerState::new(index.try_into().unwrap()),
+            running: Arc::new(AtomicBool::new(false)),
+            chunks: HashMap::new(),
+            chunk_bytes: 0,
+            broadcasts: 0,
+            rng: StdRng::seed_from_u64(seed),
+            spoofed: 0,
+            rejected: 0,
+            incompatible: 0,
+            oversized_items: 0,
+            rejected_joins: 0,
+            persistence: None,
+            normalizer: None,
+            sealed: false,
+            repairs: Vec::new(),
+            quorum_writes: Vec::new(),
+            dropped: Arc::new(AtomicUsize::new(0)),
+            receive_buffers: 0,
+            subscribers: Vec::new(),
+            lagged: 0,
+            last_heard: HashMap::new(),
+            left: HashSet::new(),
+            unreachable: HashSet::new(),
+            created: Instant::now(),
+        })
+    }
+
+    /// Build a server with options beyond the configuration's.
+    pub fn builder(config: &Configuration, index: usize) -> ServerBuilder {
+        ServerBuilder::new(config, index)
+    }
+
+    /// Persist the state to the file at `path`, flushing changes according
+    /// to `policy`. The state saved in the file, if any, is restored first,
+    /// including what is tracked alongside the items, e.g. the inputs of
+    /// intersection mode and the counters.
+    pub fn set_persistence(
+        &mut self,
+        path: impl Into<PathBuf>,
+        policy: PersistencePolicy,
+    ) -> io::Result<()> {
+        let persistence = Persistence::new(path, policy);
+        if let Some(state) = persistence.load()? {
+            self.state = ServerState {
+                id: self.state.id,
+                ..state
+            };
+        }
+        self.persistence = Some(persistence);
+        Ok(())
+    }
+
+    /// Normalize the items received from clients, and the items queried,
+    /// with `normalize`, so that equivalent items collapse into one. Items
+    /// received from peers are taken as they are, so every node must use the
+    /// same deterministic function or states will not converge.
+    pub fn set_normalizer(&mut self, normalize: fn(&str) -> String) {
+        self.normalizer = Some(normalize);
+    }
+
+    /// Send datagrams through `transport` instead of directly, e.g. a
+    /// `FaultyTransport` to test the server on an unreliable network.
+    pub fn set_transport(&mut self, transport: Arc<dyn Transport>) {
+        self.transport = transport;
+    }
+
+    /// `item` as normalized by the normalizer, if any.
+    fn normalize(&self, item: String) -> String {
+        match self.normalizer {
+            Some(normalize) => normalize(&item),
+            None => item,
+        }
+    }
+
+    /// Receive a `StateChange` whenever handling a message adds items to the
+    /// state. Changes are dropped, and counted in `lagged`, while the
+    /// subscriber has `SUBSCRIBER_CAPACITY` changes pending.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<StateChange> {
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Number of state changes dropped because a subscriber lagged behind.
+    pub fn lagged(&self) -> usize {
+        self.lagged
+    }
+
+    /// Notify subscribers of the items added since `before`.
+    fn notify(&mut self, before: &BTreeSet<String>) {
+        let added: Vec<String> = self.state.items.difference(before).cloned().collect();
+        if added.is_empty() {
+            return;
+        }
+        let change = StateChange { added };
+        let mut lagged = 0;
+        self.subscribers
+            .retain(|subscriber| match subscriber.try_send(change.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    lagged += 1;
+                    true
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+        self.lagged += lagged;
+    }
+
+    /// Handle a message
+    async fn handle_msg(&mut self, msg: Message, src: SocketAddr) {
+        let clock = self.persistence.is_some().then(|| self.state.clock.clone());
+        let items = (!self.subscribers.is_empty()).then(|| self.state.items.clone());
+        match msg {
+            // a sealed state takes no more changes
+            Message::FromServer(
+                ServerMessage::State(_)
+                | ServerMessage::StateChunk { .. }
+                | ServerMessage::Delta { .. },
+            ) if self.sealed => {}
+            Message::Increment { key, .. } if self.sealed => {
+                self.reject(key, String::from(SEALED), src).await;
+            }
+            Message::FromClient(msg) => {
+                self.add_client_item(msg, src).await;
+            }
+            Message::DisseminateQuorum { item, w, reply_to } => {
+                let client = reply_addr(reply_to.as_deref(), src);
+                let msg = ClientMessage {
+                    item: item.clone(),
+                    reply_to,
+                };
+                if let Some(held) = self.add_client_item(msg, src).await {
+                    self.start_quorum_write(item, held, w, client).await;
+                }
+            }
+            Message::FromServer(ServerMessage::State(mut state)) => {
+                if let Ok(index) = usize::try_from(state.id) {
+                    self.left.remove(&index);
+                }
+                self.expire_incoming(&mut state);
+                let new_state = self.state.merge(&state);
+                self.derive_items();
+                if new_state {
+                    self.broadcast_state(None).await;
+                }
+                self.expire();
+            }
+            Message::FromServer(ServerMessage::StateChunk {
+                clock,
+                id,
+                seq,
+                total,
+                items,
+            }) => {
+                if let Some(mut state) = self.reassemble(clock, id, seq, total, items) {
+                    self.expire_incoming(&mut state);
+                    let new_state = self.state.merge(&state);
+                    self.derive_items();
+                    if new_state {
+                        self.broadcast_state(None).await;
+                    }
+                    self.expire();
+                }
+            }
+            Message::FromServer(ServerMessage::Delta { clock, id, delta }) => {
+                if let Ok(index) = usize::try_from(id) {
+                    self.left.remove(&index);
+                }
+                if self.config.merge_mode == MergeMode::AddWins && self.state.set.merge(&delta) {
+                    self.state.clock.merge(&vec![&clock]);
+                    self.derive_items();
+                }
+            }
+            Message::FromServer(ServerMessage::Digest {
+                index,
+                digest,
+                algorithm,
+            }) => {
+                let peer = index < self.config.server_addrs.len() && self.is_peer(index);
+                if peer && digest != self.digest_with(algorithm) {
+                    let msg = Message::FromServer(ServerMessage::State(self.state.clone()));
+                    self.send_and_note(&msg, &[index]).await;
+                }
+            }
+            Message::FromServer(ServerMessage::Ping { index }) => {
+                if index < self.config.server_addrs.len() && self.is_peer(index) {
+                    self.heard_from(index);
+                    let pong = Message::FromServer(ServerMessage::Pong { index: self.index });
+                    self.send_and_note(&pong, &[index]).await;
+                }
+            }
+            Message::FromServer(ServerMessage::Pong { index }) => {
+                if index < self.config.server_addrs.len() && self.is_peer(index) {
+                    self.heard_from(index);
+                }
+            }
+            Message::FromServer(ServerMessage::Leave { index }) => {
+                if index < self.config.server_addrs.len() && self.is_peer(index) {
+                    self.left.insert(index);
+                }
+            }
+            Message::Query {
+                item,
+                repair,
+                reply_to,
+            } => {
+                let item = self.normalize(item);
+                let src = reply_addr(reply_to.as_deref(), src);
+                if repair && self.config.server_addrs.len() > 1 {
+                    // peers holding a different state answer with it
+                    let msg = Message::FromServer(ServerMessage::Digest {
+                        index: self.index,
+                        digest: self.state_digest(),
+                        algorithm: self.config.digest_algorithm,
+                    });
+                    self.broadcast(&msg).await;
+                    self.repairs
+                        .push((item, src, Instant::now() + READ_REPAIR_WAIT));
+                } else {
+                    self.answer_query(item, src).await;
+                }
+            }
+            Message::ListItems => {
+                self.expire();
+                let items = self.state.items.iter().cloned().collect();
+                if let Err(err) = self.send_message(&Message::ItemList { items }, src).await {
+                    eprintln!("Error: failed to reply to {src}: {err}");
+                }
+            }
+            Message::Remove { item } => {
+                self.remove_item(item, src).await;
+            }
+            Message::Increment { key, delta } => match self.state.increment(&key, delta) {
+                Ok(changed) => {
+                    if changed {
+                        self.broadcast_state(Some(&key)).await;
+                    }
+                    let reply = Message::Counted { key };
+                    if let Err(err) = self.send_message(&reply, src).await {
+                        eprintln!("Error: failed to reply to {src}: {err}");
+                    }
+                }
+                Err(reason) => self.reject(key, reason, src).await,
+            },
+            // peers answering a quorum write
+            Message::QueryReply {
+                item,
+                present: true,
+            } => {
+                self.quorum_ack(&item, src).await;
+            }
+            // only meant for clients
+            Message::QueryReply { .. }
+            | Message::QuorumAck { .. }
+            | Message::Counted { .. }
+            | Message::Rejected { .. }
+            | Message::ItemList { .. } => {}
+            Message::Config(config) => {
+                self.update_config(config);
+            }
+            Message::Join { addr } => {
+                self.join(&addr);
+            }
+            Message::Terminate => {
+                self.running.store(false, Ordering::SeqCst);
+            }
+        }
+        if clock.is_some_and(|clock| clock != self.state.clock) {
+            self.state_changed();
+        }
+        if let Some(items) = items {
+            self.notify(&items);
+        }
+    }
+
+    /// Freeze the state, e.g. to finalize an epoch: client items are
+    /// rejected with the reason "sealed", peer states are not merged and
+    /// the state is not broadcast anymore, until `unseal`.
+    pub fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    /// Accept changes again after `seal`.
+    pub fn unseal(&mut self) {
+        self.sealed = false;
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// Merge a snapshot of items, e.g. to bootstrap a node, like the state
+    /// of a peer. Broadcasts the state and returns true if it grew. A sealed
+    /// state is left as it is.
+    pub async fn import_state(&mut self, state: HashSet<String>) -> bool {
+        if self.sealed {
+            return false;
+        }
+        let before = self.state.items.clone();
+        let items: BTreeSet<String> = state.into_iter().map(|item| self.normalize(item)).collect();
+        let grew = match self.config.merge_mode {
+            MergeMode::Union => self.state.add(items),
+            MergeMode::Intersection => {
+                let mut grew = false;
+                for item in items {
+                    grew |= self.state.add_input(item);
+                }
+                self.derive_items();
+                grew
+            }
+            MergeMode::AddWins => {
+                let mut grew = false;
+                for item in items {
+                    grew |= self.state.add_wins(&item);
+                }
+                self.derive_items();
+                grew
+            }
+        };
+        if grew {
+            self.broadcast_change(None).await;
+            self.state_changed();
+            self.notify(&before);
+        }
+        grew
+    }
+
+    /// Add an item received from a client at `src`. Returns the item as
+    /// held, i.e. normalized, unless it was rejected.
+    async fn add_client_item(&mut self, mut msg: ClientMessage, src: SocketAddr) -> Option<String> {
+        let source = reply_addr(msg.reply_to.as_deref(), src);
+        if self.sealed {
+            self.reject(msg.item, String::from(SEALED), source).await;
+            return None;
+        }
+        // rejections name the item as submitted
+        let submitted = self.normalizer.is_some().then(|| msg.item.clone());
+        msg.item = self.normalize(msg.item);
+        if let Some(max) = self.config.max_item_len {
+            if msg.item.len() > max {
+                self.oversized_items += 1;
+                let item = submitted.unwrap_or(msg.item);
+                let reason = format!("item longer than {max} bytes");
+                self.reject(item, reason, source).await;
+                return None;
+            }
+        }
+        let new_source =
+            self.config.track_provenance && self.state.add_source(&msg.item, source.to_string());
+        if self.config.merge_mode == MergeMode::Intersection {
+            if self.state.add_input(msg.item.clone()) || new_source {
+                self.derive_items();
+                self.broadcast_state(Some(&msg.item)).await;
+            }
+        } else if self.config.merge_mode == MergeMode::AddWins {
+            if self.state.add_wins(&msg.item) {
+                self.derive_items();
+                self.broadcast_change(Some(&msg.item)).await;
+            } else if new_source {
+                self.broadcast_state(Some(&msg.item)).await;
+            }
+        } else if let Some(ttl) = self.config.item_ttl {
+            let now = unix_millis();
+            self.state.touch(msg.item.clone(), now);
+            if self.config.causal_order {
+                self.state.stamp_added(&msg.item);
+            }
+            self.state.expire(ttl, now);
+            self.broadcast_state(Some(&msg.item)).await;
+        } else if self.state.add(BTreeSet::from_iter(vec![msg.item.clone()])) || new_source {
+            if self.config.causal_order {
+                self.state.stamp_added(&msg.item);
+            }
+            self.broadcast_state(Some(&msg.item)).await;
+        }
+        Some(msg.item)
+    }
+
+    /// Remove an item on request of a client at `src`, which is told if
+    /// removes are not accepted.
+    async fn remove_item(&mut self, item: String, src: SocketAddr) {
+        if self.sealed {
+            self.reject(item, String::from(SEALED), src).await;
+            return;
+        }
+        if self.config.merge_mode != MergeMode::AddWins {
+            let reason = String::from("items are only removed in add-wins mode");
+            self.reject(item, reason, src).await;
+            return;
+        }
+        let item = self.normalize(item);
+        if self.state.remove(&item) {
+            self.derive_items();
+            self.broadcast_change(Some(&item)).await;
+        }
+    }
+
+    /// Wait for `w` servers, this one included, to hold the client's `item`,
+    /// held here as `held`, asking the peers whether they do.
+    async fn start_quorum_write(
+        &mut self,
+        item: String,
+        held: String,
+        w: usize,
+        client: SocketAddr,
+    ) {
+        let write = QuorumWrite {
+            item,
+            held,
+            w,
+            client,
+            acks: HashSet::new(),
+            deadline: Instant::now() + self.config.recv_timeout * QUORUM_WRITE_ROUNDS,
+        };
+        if write.is_complete() {
+            self.ack_quorum_write(write).await;
+        } else {
+            self.query_quorum_write(&write).await;
+            self.quorum_writes.push(write);
+        }
+    }
+
+    /// Ask the peers that did not acknowledge `write` yet whether they hold
+    /// its item. They answer with a `QueryReply`, see `quorum_ack`.
+    async fn query_quorum_write(&mut self, write: &QuorumWrite) {
+        let query = Message::Query {
+            item: write.held.clone(),
+            repair: false,
+            reply_to: None,
+        };
+        let peers: Vec<usize> = (0..self.config.server_addrs.len())
+            .filter(|i| self.is_peer(*i) && !write.acks.contains(i))
+            .collect();
+        self.send_and_note(&query, &peers).await;
+    }
+
+    /// Count the peer at `src` as holding `item` for the quorum writes of
+    /// it, and acknowledge the writes that reached their quorum.
+    async fn quorum_ack(&mut self, item: &str, src: SocketAddr) {
+        let Some(index) = (0..self.config.server_addrs.len())
+            .find(|i| self.is_peer(*i) && same_node(self.config.server_addrs[*i], src))
+        else {
+            return;
+        };
+        let mut complete = Vec::new();
+        for mut write in std::mem::take(&mut self.quorum_writes) {
+            if write.held == item {
+                write.acks.insert(index);
+            }
+            if write.is_complete() {
+                complete.push(write);
+            } else {
+                self.quorum_writes.push(write);
+            }
+        }
+        for write in complete {
+            self.ack_quorum_write(write).await;
+        }
+    }
+
+    async fn ack_quorum_write(&mut self, write: QuorumWrite) {
+        let ack = Message::QuorumAck { item: write.item };
+        if let Err(err) = self.send_message(&ack, write.client).await {
+            eprintln!("Error: failed to reply to {}: {err}", write.client);
+        }
+    }
+
+    /// Drop the quorum writes that timed out, and ask again for the others.
+    async fn requery_quorum_writes(&mut self) {
+        let now = Instant::now();
+        self.quorum_writes.retain(|write| write.deadline > now);
+        for write in std::mem::take(&mut self.quorum_writes) {
+            self.query_quorum_write(&write).await;
+            self.quorum_writes.push(write);
+        }
+    }
+
+    /// Tell the client at `src` that `item` was not accepted, and why.
+    async fn reject(&mut self, item: String, reason: String, src: SocketAddr) {
+        let reply = Message::Rejected { item, reason };
+        if let Err(err) = self.send_message(&reply, src).await {
+            eprintln!("Error: failed to reply to {src}: {err}");
+        }
+    }
+
+    /// Reply to a query with whether `item` is currently held.
+    async fn answer_query(&mut self, item: String, src: SocketAddr) {
+        self.expire();
+        let present = self.state.items.contains(&item);
+        let reply = Message::QueryReply { item, present };
+        if let Err(err) = self.send_message(&reply, src).await {
+            eprintln!("Error: failed to reply to {src}: {err}");
+        }
+    }
+
+    /// Answer the read-repairing queries whose wait for peer states is over.
+    async fn answer_repaired_queries(&mut self) {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.repairs)
+            .into_iter()
+            .partition(|(_, _, deadline)| *deadline <= now);
+        self.repairs = waiting;
+        for (item, src, _) in due {
+            self.answer_query(item, src).await;
+        }
+    }
+
+    /// Recompute the held items from what the merge mode derives them from:
+    /// the inputs of all nodes in intersection mode, the add-wins set in
+    /// add-wins mode.
+    fn derive_items(&mut self) {
+        match self.config.merge_mode {
+            MergeMode::Union => {}
+            MergeMode::Intersection => self.state.intersect(self.config.server_addrs.len()),
+            MergeMode::AddWins => self.state.items = self.state.set.items().cloned().collect(),
+        }
+    }
+
+    /// The configured item TTL, unless items do not expire in the merge mode.
+    fn item_ttl(&self) -> Option<Duration> {
+        self.config
+            .item_ttl
+            .filter(|_| self.config.merge_mode == MergeMode::Union)
+    }
+
+    /// Drop expired items from the state, if items expire.
+    fn expire(&mut self) {
+        if let Some(ttl) = self.item_ttl() {
+            self.state.expire(ttl, unix_millis());
+        }
+    }
+
+    /// Drop expired items from a received state before merging it, so that
+    /// they are not revived by a peer that has not expired them yet.
+    fn expire_incoming(&self, state: &mut ServerState) {
+        if let Some(ttl) = self.item_ttl() {
+            state.expire(ttl, unix_millis());
+        }
+    }
+
+    /// Record a change of the state, flushing it if the persistence policy
+    /// asks to.
+    fn state_changed(&mut self) {
+        if let Some(persistence) = &mut self.persistence {
+            if persistence.changed() {
+                self.flush();
+            }
+        }
+    }
+
+    /// Write pending state changes to the persistence file, if any.
+    fn flush(&mut self) {
+        if let Err(err) = self.try_flush() {
+            eprintln!("Error: failed to persist state: {err}");
+        }
+    }
+
+    fn try_flush(&mut self) -> io::Result<()> {
+        match &mut self.persistence {
+            Some(persistence) => persistence.flush(&mut self.state),
+            None => Ok(()),
+        }
+    }
+
+    /// Shut the server down gracefully: flush the persisted state, and tell
+    /// the peers it is leaving so that they stop broadcasting to it. Call it
+    /// once `run` returned. Dropping a server only flushes, on a best-effort
+    /// basis.
+    pub async fn close(mut self) -> io::Result<()> {
+        self.try_flush()?;
+        let peers: Vec<usize> = (0..self.config.server_addrs.len())
+            .filter(|i| self.is_peer(*i))
+            .collect();
+        let leave = Message::FromServer(ServerMessage::Leave { index: self.index });
+        self.send_to_peers(&leave, &peers).await;
+        Ok(())
+    }
+
+    /// Whether a message received from `src` should be handled. Under strict
+    /// peer checking only client messages may come from unlisted addresses.
+    fn accepts(&self, msg: &Message, src: SocketAddr) -> bool {
+        !self.config.strict_peers
+            || matches!(
+                msg,
+                Message::FromClient(_)
+                    | Message::DisseminateQuorum { .. }
+                    | Message::Query { .. }
+                    | Message::ListItems
+                    | Message::Remove { .. }
+                    | Message::Increment { .. }
+            )
+            || self.config.server_addrs.contains(&src)
+    }
+
+    /// Number of messages dropped because they claimed to come from a peer
+    /// but were sent from an unlisted address.
+    pub fn spoofed(&self) -> usize {
+        self.spoofed
+    }
+
+    /// Decode a received datagram, dropping it if it is malformed, of
+    /// another protocol version or exceeds the message limits.
+    fn decode(&mut self, buf: &[u8]) -> Option<Message> {
+        match decode(buf) {
+            Ok(msg) if msg.within_limits() => Some(msg),
+            Err(DecodeError::Incompatible(version)) => {
+                if self.incompatible == 0 {
+                    eprintln!(
+                        "Error: dropping messages of protocol version {version}, \
+                         this node speaks version {PROTOCOL_VERSION}"
+                    );
+                }
+                self.incompatible += 1;
+                None
+            }
+            _ => {
+                self.rejected += 1;
+                None
+            }
+        }
+    }
+
+    /// Number of messages dropped because they were of another protocol
+    /// version, e.g. sent by nodes not upgraded yet during a rolling upgrade.
+    pub fn incompatible(&self) -> usize {
+        self.incompatible
+    }
+
+    /// Number of messages dropped because they were malformed or exceeded
+    /// the message limits.
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    /// Number of client items dropped because they were longer than the
+    /// configured maximum item length.
+    pub fn oversized_items(&self) -> usize {
+        self.oversized_items
+    }
+
+    /// Adopt a pushed configuration. It is rejected, returning false, if it
+    /// fails validation, or unless it still lists this server's address at
+    /// this server's index, since a running server can neither rebind nor
+    /// change its clock id.
+    fn update_config(&mut self, config: Configuration) -> bool {
+        if let Err(err) = config.validate() {
+            eprintln!("Error: rejected configuration: {err}");
+            return false;
+        }
+        let own = self.config.server_addrs[self.index];
+        if config.server_addrs.get(self.index) != Some(&own) {
+            return false;
+        }
+        self.config = config;
+        true
+    }
+
+    /// Add the server at `addr` to the peer list. Joins of listed servers
+    /// are ignored, while malformed addresses and joins beyond the maximum
+    /// peer count are rejected, counted and logged. Returns whether the
+    /// server was added.
+    fn join(&mut self, addr: &str) -> bool {
+        let reason = match addr.parse::<SocketAddr>() {
+            Err(_) => "malformed address",
+            Ok(addr) if self.config.server_addrs.contains(&addr) => return false,
+            Ok(_)
+                if self
+                    .config
+                    .max_peers
+                    .is_some_and(|max| self.config.server_addrs.len() > max) =>
+            {
+                "too many peers"
+            }
+            Ok(addr) => {
+                self.config.server_addrs.push(addr);
+                return true;
+            }
+        };
+        eprintln!("Error: rejected join of {addr:?}: {reason}");
+        self.rejected_joins += 1;
+        false
+    }
+
+    /// Number of joins rejected because of a malformed address or because
+    /// the maximum peer count was reached.
+    pub fn rejected_joins(&self) -> usize {
+        self.rejected_joins
+    }
+
+    /// Store a received state chunk. Returns the full state once all chunks
+    /// of the sync have arrived. A chunk carrying a different clock than the
+    /// buffered ones starts a new sync and discards the stale chunks.
+    /// Incomplete syncs are discarded once they time out, or when buffering
+    /// the chunk exceeds `MAX_REASSEMBLY_BYTES`, oldest first.
+    fn reassemble(
+        &mut self,
+        clock: Clock,
+        id: u128,
+        seq: usize,
+        total: usize,
+        items: Vec<String>,
+    ) -> Option<ServerState> {
+        self.reap_chunks();
+        let buffer = self
+            .chunks
+            .entry(id)
+            .or_insert_with(|| ChunkBuffer::new(clock.clone(), total));
+        if buffer.clock != clock || buffer.total != total {
+            self.chunk_bytes -= buffer.bytes;
+            *buffer = ChunkBuffer::new(clock, total);
+        }
+        let bytes = items.iter().map(String::len).sum::<usize>();
+        if let Some(replaced) = buffer.chunks.insert(seq, items) {
+            let replaced = replaced.iter().map(String::len).sum::<usize>();
+            buffer.bytes -= replaced;
+            self.chunk_bytes -= replaced;
+        }
+        buffer.bytes += bytes;
+        buffer.updated = Instant::now();
+        self.chunk_bytes += bytes;
+        if buffer.chunks.len() < buffer.total {
+            while self.chunk_bytes > MAX_REASSEMBLY_BYTES {
+                let (&oldest, _) = self
+                    .chunks
+                    .iter()
+                    .min_by_key(|(_, buffer)| buffer.updated)
+                    .unwrap();
+                self.discard_chunks(oldest);
+            }
+            return None;
+        }
+        let buffer = self.discard_chunks(id).unwrap();
+        Some(ServerState {
+            clock: buffer.clock,
+            id,
+            items: buffer.chunks.into_values().flatten().collect(),
+            stamps: BTreeMap::new(),
+            provenance: HashMap::new(),
+            added_at: BTreeMap::new(),
+            inputs: BTreeMap::new(),
+            counters: BTreeMap::new(),
+            set: AddWinsSet::new(),
+        })
+    }
+
+    /// Send current state to the peers chosen by the configured selector,
+    /// after a change of `item` if the change is about a single one.
+    async fn broadcast_state(&mut self, item: Option<&str>) {
+        let msg = Message::FromServer(ServerMessage::State(self.state.clone()));
+        self.send_change(&msg, item).await;
+    }
+
+    /// Send the changes of the add-wins set since the last broadcast as a
+    /// delta, to the peers chosen by the configured selector, or the full
+    /// state outside of add-wins mode.
+    async fn broadcast_change(&mut self, item: Option<&str>) {
+        if self.config.merge_mode != MergeMode::AddWins {
+            return self.broadcast_state(item).await;
+        }
+        let Some(delta) = self.state.set.take_delta() else {
+            return;
+        };
+        let msg = Message::FromServer(ServerMessage::Delta {
+            clock: self.state.clock.clone(),
+            id: self.state.id,
+            delta,
+        });
+        self.send_change(&msg, item).await;
+    }
+
+    /// Send a state change to the peers chosen by the configured selector.
+    async fn send_change(&mut self, msg: &Message, item: Option<&str>) {
+        if self.sealed {
+            return;
+        }
+        match self.config.peer_selector {
+            PeerSelector::All => self.broadcast(msg).await,
+            PeerSelector::RandomFanout { fanout } => self.gossip(msg, fanout).await,
+            PeerSelector::ConsistentHash { fanout } => {
+                let round = self.broadcasts.to_le_bytes();
+                self.broadcasts = self.broadcasts.wrapping_add(1);
+                let key = item.map_or(&round[..], str::as_bytes);
+                let peers = self.hashed_peers(key, fanout);
+                self.send_and_note(msg, &peers).await
+            }
+        };
+    }
+
+    /// The `fanout` live peers ranking highest for `key`, ranking each peer
+    /// by the hash of the key and the peer's address.
+    fn hashed_peers(&self, key: &[u8], fanout: usize) -> Vec<usize> {
+        let mut peers: Vec<([u8; 32], usize)> = (0..self.config.server_addrs.len())
+            .filter(|i| self.is_peer(*i) && self.peer_status(*i) == PeerStatus::Alive)
+            .map(|i| {
+                let mut hasher = Sha256::new();
+                hasher.update((key.len() as u64).to_le_bytes());
+                hasher.update(key);
+                hasher.update(self.config.server_addrs[i].to_string().as_bytes());
+                (hasher.finalize().into(), i)
+            })
+            .collect();
+        peers.sort_unstable_by(|a, b| b.cmp(a));
+        peers.into_iter().take(fanout).map(|(_, i)| i).collect()
+    }
+
+    /// Send the digest of current state to one random peer, which replies
+    /// with its state if they differ, so that nodes missed by gossip
+    /// eventually converge without sending full states every round.
+    async fn anti_entropy(&mut self) {
+        let msg = Message::FromServer(ServerMessage::Digest {
+            index: self.index,
+            digest: self.state_digest(),
+            algorithm: self.config.digest_algorithm,
+        });
+        self.gossip(&msg, 1).await;
+    }
+
+    /// Hash of the state's items in sorted order, and of the inputs of each
+    /// node in intersection mode or the dots and context of the add-wins set
+    /// in add-wins mode, with the configured digest algorithm. Two servers
+    /// holding the same items (and inputs or set) have the same digest,
+    /// whatever their clocks.
+    pub fn state_digest(&self) -> Vec<u8> {
+        self.digest_with(self.config.digest_algorithm)
+    }
+
+    fn digest_with(&self, algorithm: DigestAlgorithm) -> Vec<u8> {
+        match algorithm {
+            DigestAlgorithm::Sha256 | DigestAlgorithm::TruncatedSha256 { .. } => {
+                let mut hasher = Sha256::new();
+                self.hash_state(&mut |bytes| hasher.update(bytes));
+                let mut digest = hasher.finalize().to_vec();
+                if let DigestAlgorithm::TruncatedSha256 { len } = algorithm {
+                    digest.truncate(len);
+                }
+                digest
+            }
+            DigestAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                self.hash_state(&mut |bytes| {
+                    hasher.update(bytes);
+                });
+                hasher.finalize().as_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Feed what `state_digest` covers to `update`.
+    fn hash_state(&self, update: &mut dyn FnMut(&[u8])) {
+        for item in &self.state.items {
+            // length prefix, so that e.g. ["ab"] and ["a", "b"] differ
+            update(&(item.len() as u64).to_le_bytes());
+            update(item.as_bytes());
+        }
+        // in intersection mode, states with the same items may still miss
+        // each other's inputs
+        for (id, items) in &self.state.inputs {
+            update(&id.to_le_bytes());
+            for item in items {
+                update(&(item.len() as u64).to_le_bytes());
+                update(item.as_bytes());
+            }
+        }
+        // in add-wins mode, states with the same items may still miss each
+        // other's dots, and removes
+        if !is_default(&self.state.set) {
+            // maps serialize in key order, so equal sets encode identically
+            let set = serde_json::to_vec(&self.state.set).expect("set serializes");
+            update(&set);
+        }
+        for (key, counter) in &self.state.counters {
+            update(&(key.len() as u64).to_le_bytes());
+            update(key.as_bytes());
+            for tallies in [&counter.increments, &counter.decrements] {
+                update(&(tallies.len() as u64).to_le_bytes());
+                for (id, tally) in tallies {
+                    update(&id.to_le_bytes());
+                    update(&tally.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    /// Liveness of the server at `index`. Without failure detection, or for
+    /// this server itself, always `Alive`.
+    pub fn peer_status(&self, index: usize) -> PeerStatus {
+        if self.left.contains(&index) {
+            return PeerStatus::Left;
+        }
+        let Some(timeout) = self.config.suspect_after else {
+            return PeerStatus::Alive;
+        };
+        let last_heard = self.last_heard.get(&index).unwrap_or(&self.created);
+        if index != self.index
+            && (last_heard.elapsed() > timeout || self.unreachable.contains(&index))
+        {
+            PeerStatus::Suspected
+        } else {
+            PeerStatus::Alive
+        }
+    }
+
+    /// Ping all peers, suspected ones included so that they can recover.
+    async fn ping_peers(&mut self) {
+        let peers: Vec<usize> = (0..self.config.server_addrs.len())
+            .filter(|i| self.is_peer(*i))
+            .collect();
+        let ping = Message::FromServer(ServerMessage::Ping { index: self.index });
+        self.send_and_note(&ping, &peers).await;
+    }
+
+    /// Record that the peer at `index` was heard from.
+    fn heard_from(&mut self, index: usize) {
+        self.last_heard.insert(index, Instant::now());
+        self.left.remove(&index);
+        self.unreachable.remove(&index);
+    }
+
+    /// Log the failed sends of `report`, and have the failure detector, if
+    /// enabled, suspect the peers they were to.
+    fn note_failures(&mut self, report: &BroadcastReport) {
+        for (addr, err) in &report.failed {
+            eprintln!("Error: failed to send to {addr}: {err}");
+            if self.config.suspect_after.is_some() {
+                let index = self
+                    .config
+                    .server_addrs
+                    .iter()
+                    .position(|peer| peer.to_string() == *addr);
+                self.unreachable.extend(index);
+            }
+        }
+    }
+
+    /// Send a message to the given peers, noting the failures.
+    async fn send_and_note(&mut self, msg: &Message, peers: &[usize]) -> BroadcastReport {
+        let report = self.send_to_peers(msg, peers).await;
+        self.note_failures(&report);
+        report
+    }
+
+    /// Discard the chunks of the sync of `id`, returning them.
+    fn discard_chunks(&mut self, id: u128) -> Option<ChunkBuffer> {
+        let buffer = self.chunks.remove(&id)?;
+        self.chunk_bytes -= buffer.bytes;
+        Some(buffer)
+    }
+
+    /// Discard the incomplete syncs no chunk arrived for within the chunk
+    /// timeout.
+    fn reap_chunks(&mut self) {
+        let timeout = self.config.chunk_timeout;
+        let expired: Vec<_> = self
+            .chunks
+            .iter()
+            .filter(|(_, buffer)| buffer.updated.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.discard_chunks(id);
+        }
+    }
+
+    /// Number of chunks buffered for incomplete chunked state syncs.
+    pub fn pending_chunks(&self) -> usize {
+        self.chunks.values().map(|buffer| buffer.chunks.len()).sum()
+    }
+
+    /// Periodic work, run at most once per receive timeout.
+    async fn tick(&mut self) {
+        self.reap_chunks();
+        self.requery_quorum_writes().await;
+        if self.config.suspect_after.is_some() {
+            self.ping_peers().await;
+        }
+        if self.config.peer_selector != PeerSelector::All
+            || self.config.merge_mode == MergeMode::AddWins
+        {
+            self.anti_entropy().await;
+        }
+        if self.persistence.as_ref().is_some_and(Persistence::due) {
+            self.flush();
+        }
+    }
+
+    /// Broadcast current state split into messages of at most `chunk_size`
+    /// items each, so that a large state (e.g. the initial sync of a joining
+    /// node) is not sent as one oversized datagram. Returns the number of
+    /// chunks sent to each peer.
+    pub async fn broadcast_chunked(&mut self, chunk_size: usize) -> usize {
+        let chunk_size = chunk_size.max(1);
+        let items: Vec<String> = self.state.items.iter().cloned().collect();
+        let total = items.len().div_ceil(chunk_size).max(1);
+        for seq in 0..total {
+            let start = (seq * chunk_size).min(items.len());
+            let end = (start + chunk_size).min(items.len());
+            let msg = Message::FromServer(ServerMessage::StateChunk {
+                clock: self.state.clock.clone(),
+                id: self.state.id,
+                seq,
+                total,
+                items: items[start..end].to_vec(),
+            });
+            self.broadcast(&msg).await;
+        }
+        total
+    }
+
+    /// Send a message to all other nodes in the network, in the configured
+    /// broadcast order. Returns which sends failed and the number of peers the
+    /// message was sent to, which is zero (a no-op) for a single-node
+    /// cluster. Failures are logged and fed to the failure detector.
+    async fn broadcast(&mut self, msg: &Message) -> BroadcastReport {
+        let peers = self.broadcast_peers();
+        self.send_and_note(msg, &peers).await
+    }
+
+    /// All other nodes, in the order the next broadcast should reach them.
+    fn broadcast_peers(&mut self) -> Vec<usize> {
+        let mut peers: Vec<usize> = (0..self.config.server_addrs.len())
+            .filter(|i| self.is_peer(*i) && self.peer_status(*i) == PeerStatus::Alive)
+            .collect();
+        match self.config.broadcast_order {
+            BroadcastOrder::Sequential => {}
+            BroadcastOrder::RoundRobin => {
+                if !peers.is_empty() {
+                    let n = peers.len();
+                    peers.rotate_left(self.broadcasts % n);
+                }
+            }
+            BroadcastOrder::Shuffled { .. } => peers.shuffle(&mut self.rng),
+        }
+        self.broadcasts = self.broadcasts.wrapping_add(1);
+        peers
+    }
+
+    /// Whether the server at `index` is another node, and not this one
+    /// listed again under an equivalent address.
+    fn is_peer(&self, index: usize) -> bool {
+        let own = self.config.server_addrs[self.index];
+        index != self.index && !same_node(self.config.server_addrs[index], own)
+    }
+
+    /// Send a message to at most `fanout` other nodes chosen at random, like
+    /// `broadcast`.
+    async fn gossip(&mut self, msg: &Message, fanout: usize) -> BroadcastReport {
+        let peers: Vec<usize> = (0..self.config.server_addrs.len())
+            .filter(|i| self.is_peer(*i) && self.peer_status(*i) == PeerStatus::Alive)
+            .choose_multiple(&mut rand::thread_rng(), fanout);
+        self.send_and_note(msg, &peers).await
+    }
+
+    /// Send a message to `addr`.
+    async fn send_message(&self, msg: &Message, addr: SocketAddr) -> io::Result<()> {
+        let buf = encode(msg)?;
+        self.transport.send(&self.socket, &buf, addr).await
+    }
+
+    /// Send a message to the given peers by index. A failed send does not
+    /// keep the message from the other peers.
+    async fn send_to_peers(&self, msg: &Message, peers: &[usize]) -> BroadcastReport {
+        let mut report = BroadcastReport::default();
+        let buf = match encode(msg) {
+            Ok(buf) => buf,
+            Err(err) => {
+                for &i in peers {
+                    let err = io::Error::new(io::ErrorKind::InvalidData, err.to_string());
+                    report
+                        .failed
+                        .push((self.config.server_addrs[i].to_string(), err));
+                }
+                return report;
+            }
+        };
+        for &i in peers {
+            let addr = self.config.server_addrs[i];
+            match self.transport.send(&self.socket, &buf, addr).await {
+                Ok(()) => report.sent += 1,
+                Err(err) => report.failed.push((addr.to_string(), err)),
+            }
+        }
+        report
+    }
+
+    /// A handle to the running flag. Storing `false` into it stops the main
+    /// event loop within one receive timeout, even if no message arrives.
+    pub fn running(&self) -> Arc<AtomicBool> {
+        self.running.clone()
+    }
+
+    /// Addresses of the clients `item` was disseminated from, through any
+    /// server, if provenance is tracked and the item is known.
+    pub fn provenance(&self, item: &str) -> Option<&HashSet<String>> {
+        self.state.provenance.get(item)
+    }
+
+    /// Value of the counter of `key`, the sum of the increments minus the sum
+    /// of the decrements seen from all nodes. Counters never incremented are
+    /// zero.
+    pub fn count(&self, key: &str) -> i128 {
+        self.state.counters.get(key).map_or(0, PnCounter::value)
+    }
+
+    /// Items ordered by the clock at which they were added, so that an item
+    /// added after another was seen comes later. Concurrent items are ordered
+    /// by their clocks' sums, then by the hash of their content, so all
+    /// converged servers agree on the order. Items without a recorded clock,
+    /// e.g. when causal ordering is disabled, come first.
+    pub fn items_in_causal_order(&self) -> Vec<String> {
+        let genesis = OrdinaryClock::default();
+        let mut items: Vec<_> = self
+            .state
+            .items
+            .iter()
+            .map(|item| {
+                let clock = self.state.added_at.get(item).unwrap_or(&genesis);
+                let content: [u8; 32] = Sha256::digest(item.as_bytes()).into();
+                ((clock.reduce(), content), item)
+            })
+            .collect();
+        items.sort();
+        items.into_iter().map(|(_, item)| item.clone()).collect()
+    }
+
+    /// Number of received datagrams dropped because the mailbox was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of buffers allocated for received datagrams by past runs. They
+    /// are reused once handled, so this stays around the mailbox capacity
+    /// per run rather than growing with the datagrams received.
+    pub fn receive_buffers(&self) -> usize {
+        self.receive_buffers
+    }
+
+    /// Main event loop. Datagrams are received by a background task into a
+    /// bounded mailbox, so that receiving continues while a message is
+    /// handled.
+    pub async fn run(&mut self) {
+        self.running.store(true, Ordering::SeqCst);
+        let mut mailbox = Mailbox::spawn(
+            self.socket.clone(),
+            self.config.mailbox_capacity,
+            self.dropped.clone(),
+        );
+        let mut last_tick = Instant::now();
+        while self.running.load(Ordering::SeqCst) {
+            // wake up in time for pending read repairs
+            let timeout = self
+                .repairs
+                .iter()
+                .map(|(_, _, deadline)| deadline.saturating_duration_since(Instant::now()))
+                .fold(self.config.recv_timeout, cmp::min);
+            // on timeout, just check the running flag and periodic work again
+            let recv = mailbox.receiver.recv();
+            if let Ok(Some((buf, src))) = tokio::time::timeout(timeout, recv).await {
+                if let Some(msg) = self.decode(&buf) {
+                    if self.accepts(&msg, src) {
+                        self.handle_msg(msg, src).await;
+                    } else {
+                        self.spoofed += 1;
+                    }
+                }
+                mailbox.recycle(buf);
+            }
+            self.answer_repaired_queries().await;
+            if last_tick.elapsed() >= self.config.recv_timeout {
+                self.tick().await;
+                last_tick = Instant::now();
+            }
+        }
+        self.receive_buffers += mailbox.allocations.load(Ordering::Relaxed);
+        drop(mailbox);
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time;
+
+    use super::*;
+
+    fn local_config(n_server: usize) -> Configuration {
+        // let the OS pick free ports, and release them for the servers to bind
+        let sockets: Vec<_> = (0..n_server)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        Configuration::new(sockets.iter().map(|s| s.local_addr().unwrap()).collect())
+    }
+
+    fn spawn_server(config: &Configuration, index: usize) -> JoinHandle<BTreeSet<String>> {
+        let c = config.clone();
+        tokio::spawn(async move {
+            let mut server = Server::new(&c, index).await.unwrap();
+            server.run().await;
+            std::mem::take(&mut server.state.items)
+        })
+    }
+
+    async fn start_servers(n_server: usize) -> (Configuration, Vec<JoinHandle<BTreeSet<String>>>) {
+        let config = local_config(n_server);
+        let handles = spawn_servers(&config);
+        (config, handles)
+    }
+
+    fn spawn_servers(config: &Configuration) -> Vec<JoinHandle<BTreeSet<String>>> {
+        (0..config.server_addrs.len())
+            .map(|i| spawn_server(config, i))
+            .collect()
+    }
+
+    async fn collect_states(handles: Vec<JoinHandle<BTreeSet<String>>>) -> Vec<BTreeSet<String>> {
+        let mut states = Vec::new();
+        for handle in handles {
+            states.push(handle.await.unwrap());
+        }
+        states
+    }
+
+    async fn terminate(config: &Configuration) {
+        let mut client = Client::new(config).await.unwrap();
+        for (addr, result) in client.terminate_all(&config.server_addrs).await {
+            if let Err(err) = result {
+                eprintln!("Error: failed to terminate {addr}: {err}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn single_server() {
+        // Start server
+        let (config, handles) = start_servers(1).await;
+        // Run client
+        let mut client = Client::new(&config).await.unwrap();
+        client.disseminate("hello").await.unwrap();
+        // End test
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        terminate(&config).await;
+        let states = collect_states(handles).await;
+        assert!(states[0].contains("hello"));
+    }
+
+    #[tokio::test]
+    async fn multi_servers() {
+        // Start servers
+        let (config, handles) = start_servers(3).await;
+        // Run client
+        let mut client = Client::new(&config).await.unwrap();
+        client.disseminate("hello").await.unwrap();
+        client.disseminate("world").await.unwrap();
+        // End test
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        terminate(&config).await;
+        let states = collect_states(handles).await;
+        assert!(states.iter().all(|s| s.contains("hello")));
+        assert!(states.iter().all(|s| s.contains("world")));
+        assert!(states.iter().all(|s| s.len() == 2));
+    }
+
+    #[tokio::test]
+    async fn disseminate_confirmed() {
+        let (config, handles) = start_servers(2).await;
+        let mut client = Client::new(&config).await.unwrap();
+        let timeout = time::Duration::from_secs(1);
+        client
+            .disseminate_confirmed("hello", timeout)
+            .await
+            .unwrap();
+        // already present
+        client
+            .disseminate_confirmed("hello", timeout)
+            .await
+            .unwrap();
+        terminate(&config).await;
+        let states = collect_states(handles).await;
+        assert!(states[0].contains("hello"));
+
+        // nobody listens anymore
+        let timeout = time::Duration::from_millis(200);
+        let err = client
+            .disseminate_confirmed("world", timeout)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn read_repair_on_query() {
+        let mut config = local_config(3);
+        config.set_recv_timeout(time::Duration::from_millis(50));
+        let mut handles = vec![spawn_server(&config, 0), spawn_server(&config, 1)];
+        let mut client = Client::new(&config).await.unwrap();
+        client.disseminate("a").await.unwrap();
+        client.disseminate("b").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+
+        // server 2 starts late and missed both items
+        handles.push(spawn_server(&config, 2));
+        let mut lagging = Client::new(&Configuration::new(vec![config.server_addrs[2]]))
+            .await
+            .unwrap();
+        let timeout = time::Duration::from_secs(1);
+        assert!(!lagging.query("a", false, timeout).await.unwrap());
+        assert!(lagging.query("a", true, timeout).await.unwrap());
+        // repaired as a whole, not only the queried item
+        assert!(lagging.query("b", false, timeout).await.unwrap());
+
+        terminate(&config).await;
+        let states = collect_states(handles).await;
+        assert_eq!(states[2], ["a", "b"].map(String::from).into());
+        assert_eq!(states[0], states[2]);
+    }
+
+    #[tokio::test]
+    async fn terminate_partial_cluster() {
+        let config = local_config(3);
+        // the second server never starts
+        let handles = [spawn_server(&config, 0), spawn_server(&config, 2)];
+        let mut addrs = config.server_addrs.clone();
+        addrs.push("127.0.0.1:0".parse().unwrap());
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+
+        let mut client = Client::new(&config).await.unwrap();
+        let results = client.terminate_all(&addrs).await;
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].0, config.server_addrs[0].to_string());
+        assert!(results[..3].iter().all(|(_, result)| result.is_ok()));
+        assert!(results[3].1.is_err());
+        for handle in handles {
+            tokio::time::timeout(time::Duration::from_secs(1), handle)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn quorum_query() {
+        let config = local_config(4);
+        // independent single node clusters, the last of which is not running
+        let singles: Vec<_> = config.server_addrs[..3]
+            .iter()
+            .map(|addr| Configuration::new(vec![*addr]))
+            .collect();
+        let handles: Vec<_> = singles.iter().map(|c| spawn_server(c, 0)).collect();
+        for (single, items) in singles.iter().zip([&["x", "y"][..], &["x"], &["z"]]) {
+            let mut client = Client::new(single).await.unwrap();
+            for item in items {
+                client.disseminate(item).await.unwrap();
+            }
+        }
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+
+        let mut config = config;
+        config.set_recv_timeout(time::Duration::from_millis(200));
+        let mut client = Client::new(&config).await.unwrap();
+        let addrs = config.server_addrs.clone();
+        let majority = client.query_quorum(&addrs, 2).await.unwrap();
+        assert_eq!(majority, HashSet::from(["x".to_string()]));
+        let any = client.query_quorum(&addrs, 1).await.unwrap();
+        assert_eq!(any.len(), 3);
+        assert!(client.query_quorum(&addrs, 4).await.unwrap().is_empty());
+
+        for single in &singles {
+            terminate(single).await;
+        }
+        collect_states(handles).await;
+    }
+
+    #[tokio::test]
+    async fn diff_between_servers() {
+        let mut config = local_config(3);
+        config.set_recv_timeout(time::Duration::from_millis(200));
+        let [a, b, down] = [0, 1, 2].map(|i| config.server_addrs[i]);
+        // independent single node clusters that cannot converge by themselves
+        let singles = [a, b].map(|addr| Configuration::new(vec![addr]));
+        let handles: Vec<_> = singles.iter().map(|c| spawn_server(c, 0)).collect();
+        let mut clients = Vec::new();
+        for (single, items) in singles.iter().zip([["x", "y"], ["y", "z"]]) {
+            let mut client = Client::new(single).await.unwrap();
+            for item in items {
+                client.disseminate(item).await.unwrap();
+            }
+            clients.push(client);
+        }
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+
+        let mut client = Client::new(&config).await.unwrap();
+        let (only_a, only_b) = client.diff(a, b).await.unwrap();
+        assert_eq!(only_a, HashSet::from(["x".to_string()]));
+        assert_eq!(only_b, HashSet::from(["z".to_string()]));
+
+        clients[0].disseminate("z").await.unwrap();
+        clients[1].disseminate("x").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+        let (only_a, only_b) = client.diff(a, b).await.unwrap();
+        assert!(only_a.is_empty() && only_b.is_empty());
+        let err = client.diff(a, down).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        for single in &singles {
+            terminate(single).await;
+        }
+        collect_states(handles).await;
+    }
+
+    #[tokio::test]
+    async fn round_robin_targets() {
+        // stand-in servers, only recording what they receive
+        let sockets: Vec<_> = (0..3)
+            .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let config = Configuration::new(sockets.iter().map(|s| s.local_addr().unwrap()).collect());
+        let mut client = Client::with_policy(&config, ClientPolicy::RoundRobin)
+            .await
+            .unwrap();
+        for i in 0..6 {
+            client.disseminate(&i.to_string()).await.unwrap();
+        }
+        let mut buf = [0; 1500];
+        for (index, socket) in sockets.iter().enumerate() {
+            socket
+                .set_read_timeout(Some(time::Duration::from_millis(100)))
+                .unwrap();
+            let mut received = Vec::new();
+            while let Ok(n) = socket.recv(&mut buf) {
+                let Ok(Message::FromClient(msg)) = decode(&buf[..n]) else {
+                    panic!("unexpected datagram")
+                };
+                received.push(msg.item);
+            }
+            assert_eq!(received, [index.to_string(), (index + 3).to_string()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn quorum_writes() {
+        let mut config = local_config(3);
+        config.set_recv_timeout(time::Duration::from_millis(50));
+        // the third server is not running
+        let handles: Vec<_> = (0..2).map(|i| spawn_server(&config, i)).collect();
+        let mut client = Client::new(&config).await.unwrap();
+        client.disseminate_quorum("x", 2).await.unwrap();
+        client.disseminate_quorum("x", 1).await.unwrap();
+        let err = client.disseminate_quorum("y", 3).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        client.terminate(1).await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        let err = client.disseminate_quorum("z", 2).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        client.disseminate_quorum("z", 1).await.unwrap();
+
+        client.terminate(0).await.unwrap();
+        let states = collect_states(handles).await;
+        assert!(states[1].contains("x"));
+        assert!(["x", "y", "z"].iter().all(|item| states[0].contains(*item)));
+    }
+
+    #[tokio::test]
+    async fn query_reply_to() {
+        let config = local_config(1);
+        let handle = spawn_server(&config, 0);
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = Client::new(&config).await.unwrap();
+        client.set_reply_to(Some(relay.local_addr().unwrap()));
+        client.disseminate("x").await.unwrap();
+
+        let timeout = time::Duration::from_millis(100);
+        let err = client.query("x", false, timeout).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let mut buf = [0; 1500];
+        let (n, _) = tokio::time::timeout(timeout, relay.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = decode(&buf[..n]).unwrap();
+        assert!(matches!(
+            reply,
+            Message::QueryReply { item, present: true } if item == "x"
+        ));
+
+        // back to the datagram source
+        client.set_reply_to(None);
+        assert!(client.query("x", false, timeout).await.unwrap());
+        terminate(&config).await;
+        collect_states(vec![handle]).await;
+    }
+
+    #[tokio::test]
+    async fn send_errors_are_returned() {
+        let config = local_config(1);
+        let client = Client::new(&config).await.unwrap();
+        // nothing can listen on port 0
+        let closed = "127.0.0.1:0".parse().unwrap();
+        assert!(client
+            .send_message(&Message::Terminate, closed)
+            .await
+            .is_err());
+        // larger than any datagram
+        let msg = Message::FromClient(ClientMessage {
+            item: "x".repeat(1 << 16),
+            reply_to: None,
+        });
+        let err = client
+            .send_message(&msg, config.server_addrs[0])
+            .await
+            .unwrap_err();
+        assert_ne!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[tokio::test]
+    async fn item_ttl_expires_across_nodes() {
+        let mut config = local_config(2);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        config.set_item_ttl(time::Duration::from_millis(300));
+        let handles = spawn_servers(&config);
+        let mut client = Client::new(&config).await.unwrap();
+        client.disseminate("stale").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(150)).await;
+        // a heartbeat keeps an item alive
+        client.disseminate("alive").await.unwrap();
+        client.disseminate("alive").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(200)).await;
+        // expiry is lazy, the next merge drops the stale item everywhere
+        client.disseminate("fresh").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+        terminate(&config).await;
+        let states = collect_states(handles).await;
+        for state in states {
+            assert_eq!(state, ["alive", "fresh"].map(String::from).into());
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_state_sync() {
+        let config = local_config(2);
+        let handle = spawn_server(&config, 1);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        server
+            .state
+            .add((0..1000).map(|i| format!("item-{i:04}")).collect());
+        // Broadcast in chunks
+        assert_eq!(server.broadcast_chunked(50).await, 20);
+        // End test
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        terminate(&config).await;
+        let states = collect_states(vec![handle]).await;
+        assert_eq!(states[0], server.state.items);
+    }
+
+    #[tokio::test]
+    async fn incomplete_chunk_sync_times_out() {
+        let mut config = local_config(1);
+        config.set_chunk_timeout(time::Duration::from_millis(50));
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let mut clock = Clock::new();
+        clock.inc(1);
+        let chunk = |seq: usize| vec![format!("item-{seq}")];
+
+        // a peer that never sends the rest
+        assert!(server
+            .reassemble(clock.clone(), 1, 0, 3, chunk(0))
+            .is_none());
+        assert!(server
+            .reassemble(clock.clone(), 1, 2, 3, chunk(2))
+            .is_none());
+        assert_eq!(server.pending_chunks(), 2);
+        assert_eq!(server.chunk_bytes, 12);
+        tokio::time::sleep(time::Duration::from_millis(60)).await;
+        server.reap_chunks();
+        assert_eq!(server.pending_chunks(), 0);
+        assert_eq!(server.chunk_bytes, 0);
+
+        // the late chunk does not complete the discarded sync
+        assert!(server
+            .reassemble(clock.clone(), 1, 1, 3, chunk(1))
+            .is_none());
+        assert!(server
+            .reassemble(clock.clone(), 1, 0, 3, chunk(0))
+            .is_none());
+        let state = server.reassemble(clock.clone(), 1, 2, 3, chunk(2)).unwrap();
+        assert_eq!(state.items, (0..3).flat_map(chunk).collect());
+        assert_eq!(state.clock, clock);
+        assert_eq!(server.pending_chunks(), 0);
+        assert_eq!(server.chunk_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn stop_without_inbound_messages() {
+        let mut config = local_config(1);
+        config.set_recv_timeout(time::Duration::from_millis(50));
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let running = server.running();
+        let handle = tokio::spawn(async move { server.run().await });
+        tokio::time::sleep(time::Duration::from_millis(20)).await;
+        running.store(false, Ordering::SeqCst);
+        // no message is ever sent, the loop must notice on its own
+        tokio::time::timeout(time::Duration::from_millis(200), handle)
+            .await
+            .expect("server loop did not exit")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_configuration() {
+        let config = local_config(0);
+        let err = Client::new(&config).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = Server::new(&config, 0).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn single_server_broadcast_is_noop() {
+        let config = local_config(1);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let msg = Message::FromServer(ServerMessage::State(server.state.clone()));
+        assert_eq!(server.broadcast(&msg).await.sent, 0);
+    }
+
+    #[tokio::test]
+    async fn no_broadcast_to_self_alias() {
+        let mut config = local_config(2);
+        let own = config.server_addrs[0];
+        // the first server listed again, as IPv4-mapped and IPv6 loopback
+        for alias in ["[::ffff:127.0.0.1]", "[::1]"] {
+            let alias = format!("{alias}:{}", own.port()).parse().unwrap();
+            assert!(same_node(alias, own));
+            config.server_addrs.push(alias);
+        }
+        assert!(!same_node(own, config.server_addrs[1]));
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let msg = Message::FromServer(ServerMessage::State(server.state.clone()));
+        assert_eq!(server.broadcast_peers(), [1]);
+        assert_eq!(server.broadcast(&msg).await.sent, 1);
+        assert_eq!(server.gossip(&msg, 3).await.sent, 1);
+    }
+
+    #[tokio::test]
+    async fn broadcast_reports_failed_peers() {
+        let mut config = local_config(3);
+        config.set_suspect_after(time::Duration::from_secs(60));
+        // not reachable from the IPv4 socket of the server
+        let down: SocketAddr = format!("[::1]:{}", config.server_addrs[2].port())
+            .parse()
+            .unwrap();
+        config.server_addrs[2] = down;
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let msg = Message::FromServer(ServerMessage::State(server.state.clone()));
+        let report = server.broadcast(&msg).await;
+        assert_eq!(report.sent, 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, down.to_string());
+        // the failure detector suspects the peer until it is heard from
+        assert_eq!(server.peer_status(1), PeerStatus::Alive);
+        assert_eq!(server.peer_status(2), PeerStatus::Suspected);
+        assert_eq!(server.broadcast(&msg).await.failed.len(), 0);
+        server.heard_from(2);
+        assert_eq!(server.peer_status(2), PeerStatus::Alive);
+    }
+
+    #[tokio::test]
+    async fn failure_detector() {
+        let mut config = local_config(3);
+        config.set_recv_timeout(time::Duration::from_millis(20));
+        config.set_suspect_after(time::Duration::from_millis(100));
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let handle = spawn_server(&config, 1);
+        assert_eq!(server.peer_status(2), PeerStatus::Alive);
+
+        // server 1 answers pings, server 2 is down
+        let mut buf = [0; 1500];
+        let deadline = Instant::now() + time::Duration::from_millis(200);
+        while Instant::now() < deadline {
+            server.tick().await;
+            let recv = tokio::time::timeout_at(deadline, server.socket.recv_from(&mut buf));
+            if let Ok(Ok((n, src))) = recv.await {
+                let msg = server.decode(&buf[..n]).unwrap();
+                server.handle_msg(msg, src).await;
+            }
+        }
+        assert_eq!(server.peer_status(0), PeerStatus::Alive);
+        assert_eq!(server.peer_status(1), PeerStatus::Alive);
+        assert_eq!(server.peer_status(2), PeerStatus::Suspected);
+        assert_eq!(server.broadcast_peers(), [1]);
+
+        // server 2 comes back and answers the next ping
+        let mut revived = Server::new(&config, 2).await.unwrap();
+        server.ping_peers().await;
+        let (n, src) = revived.socket.recv_from(&mut buf).await.unwrap();
+        let msg = revived.decode(&buf[..n]).unwrap();
+        revived.handle_msg(msg, src).await;
+        loop {
+            let (n, src) = server.socket.recv_from(&mut buf).await.unwrap();
+            let msg = server.decode(&buf[..n]).unwrap();
+            let pong = matches!(msg, Message::FromServer(ServerMessage::Pong { index: 2 }));
+            server.handle_msg(msg, src).await;
+            if pong {
+                break;
+            }
+        }
+        assert_eq!(server.peer_status(2), PeerStatus::Alive);
+        assert_eq!(server.broadcast_peers(), [1, 2]);
+
+        Client::new(&config)
+            .await
+            .unwrap()
+            .terminate(1)
+            .await
+            .unwrap();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mailbox_overflow() {
+        let config = local_config(1);
+        let server = Server::new(&config, 0).await.unwrap();
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for capacity in [16, 4] {
+            let dropped = Arc::new(AtomicUsize::new(0));
+            let mut mailbox = Mailbox::spawn(server.socket.clone(), capacity, dropped.clone());
+            // a burst while nothing is handled
+            for i in 0..10u8 {
+                client.send_to(&[i], config.server_addrs[0]).unwrap();
+            }
+            tokio::time::sleep(time::Duration::from_millis(100)).await;
+            let expected = capacity.min(10);
+            for i in 0..expected {
+                let (buf, _) = mailbox.receiver.recv().await.unwrap();
+                assert_eq!(buf, [i as u8]);
+            }
+            assert!(mailbox.receiver.try_recv().is_err());
+            assert_eq!(dropped.load(Ordering::Relaxed), 10 - expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn mailbox_reuses_buffers() {
+        let config = local_config(1);
+        let server = Server::new(&config, 0).await.unwrap();
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let mut mailbox = Mailbox::spawn(server.socket.clone(), 4, dropped);
+        for i in 0..10u8 {
+            // longer and shorter datagrams in turn
+            let datagram = vec![i; 1 + 100 * (i as usize % 2)];
+            client.send_to(&datagram, config.server_addrs[0]).unwrap();
+            let (buf, _) = mailbox.receiver.recv().await.unwrap();
+            assert_eq!(buf, datagram);
+            mailbox.recycle(buf);
+        }
+        assert_eq!(mailbox.allocations.load(Ordering::Relaxed), 1);
+
+        // the decoded messages are unchanged
+        let msg = Message::Query {
+            item: String::from("x"),
+            repair: false,
+            reply_to: None,
+        };
+        let buf = encode(&msg).unwrap();
+        assert!(matches!(decode(&buf), Ok(Message::Query { item, .. }) if item == "x"));
+        let newer = PROTOCOL_VERSION + 1;
+        let newer_msg = serde_json::to_vec(&Envelope {
+            version: newer,
+            msg: &msg,
+        })
+        .unwrap();
+        assert!(matches!(decode(&newer_msg), Err(DecodeError::Incompatible(v)) if v == newer));
+        assert!(matches!(
+            decode(b"{\"version\":1}"),
+            Err(DecodeError::Malformed)
+        ));
+        assert!(matches!(decode(b"junk"), Err(DecodeError::Malformed)));
+    }
+
+    #[tokio::test]
+    async fn burst_is_handled_in_order() {
+        let mut config = local_config(1);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let running = server.running();
+        let dropped = server.dropped.clone();
+        let handle = tokio::spawn(async move {
+            server.run().await;
+            (server.state.clone(), server.receive_buffers())
+        });
+        let client = Client::new(&config).await.unwrap();
+        for i in 0..100 {
+            let msg = Message::FromClient(ClientMessage {
+                item: format!("{i:03}"),
+                reply_to: None,
+            });
+            client
+                .send_message(&msg, config.server_addrs[0])
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(time::Duration::from_millis(200)).await;
+        running.store(false, Ordering::SeqCst);
+        let (state, buffers) = handle.await.unwrap();
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+        // buffers are in the mailbox, being handled or being filled
+        assert!(buffers <= config.mailbox_capacity + 2);
+        assert_eq!(state.items.len(), 100);
+        // one increment per new item
+        assert_eq!(state.clock.clone().get(0), 100);
+    }
+
+    #[tokio::test]
+    async fn imported_state_converges() {
+        let mut config = local_config(3);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        let mut importer = Server::new(&config, 0).await.unwrap();
+        let handles: Vec<_> = (1..3).map(|i| spawn_server(&config, i)).collect();
+        let snapshot = HashSet::from([String::from("a"), String::from("b")]);
+        assert!(importer.import_state(snapshot.clone()).await);
+        assert!(!importer.import_state(snapshot).await);
+        assert_eq!(importer.state.clock.clone().get(0), 1);
+
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        let mut client = Client::new(&config).await.unwrap();
+        client.terminate(1).await.unwrap();
+        client.terminate(2).await.unwrap();
+        for state in collect_states(handles).await {
+            assert_eq!(state, importer.state.items);
+        }
+    }
+
+    #[tokio::test]
+    async fn provenance_is_merged() {
+        let mut config = local_config(2);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        config.set_track_provenance(true);
+        let handles: Vec<_> = (0..2)
+            .map(|index| {
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let mut server = Server::new(&config, index).await.unwrap();
+                    server.run().await;
+                    server
+                })
+            })
+            .collect();
+        // two clients add the same item through different servers
+        let mut first = Client::new(&config).await.unwrap();
+        let reversed = config.server_addrs.iter().rev().copied().collect();
+        let mut second = Client::new(&Configuration::new(reversed)).await.unwrap();
+        first.disseminate("x").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(50)).await;
+        second.disseminate("x").await.unwrap();
+        first.disseminate("y").await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+
+        terminate(&config).await;
+        let port = |client: &Client| client.socket.local_addr().unwrap().port();
+        for handle in handles {
+            let server = handle.await.unwrap();
+            let mut ports: Vec<_> = server
+                .provenance("x")
+                .unwrap()
+                .iter()
+                .map(|source| source.parse::<SocketAddr>().unwrap().port())
+                .collect();
+            ports.sort();
+            let mut expected = vec![port(&first), port(&second)];
+            expected.sort();
+            assert_eq!(ports, expected);
+            assert_eq!(server.provenance("y").unwrap().len(), 1);
+            assert_eq!(server.provenance("z"), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn causal_item_order() {
+        let mut config = local_config(2);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        config.set_causal_order(true);
+        let handles: Vec<_> = (0..2)
+            .map(|index| {
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let mut server = Server::new(&config, index).await.unwrap();
+                    server.run().await;
+                    server
+                })
+            })
+            .collect();
+        let mut first = Client::new(&config).await.unwrap();
+        let reversed = config.server_addrs.iter().rev().copied().collect();
+        let mut second = Client::new(&Configuration::new(reversed)).await.unwrap();
+        let settle = || tokio::time::sleep(time::Duration::from_millis(50));
+        first.disseminate("a").await.unwrap();
+        settle().await;
+        // seen "a" before
+        second.disseminate("b").await.unwrap();
+        settle().await;
+        // concurrent adds on both servers
+        for item in ["c", "d", "e"] {
+            first.disseminate(item).await.unwrap();
+        }
+        for item in ["f", "g", "e"] {
+            second.disseminate(item).await.unwrap();
+        }
+        settle().await;
+
+        terminate(&config).await;
+        let mut orders = Vec::new();
+        for handle in handles {
+            orders.push(handle.await.unwrap().items_in_causal_order());
+        }
+        assert_eq!(orders[0], orders[1]);
+        assert_eq!(orders[0].len(), 7);
+        assert_eq!(orders[0][..2], ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn subscribe_to_changes() {
+        let mut config = local_config(1);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let mut changes = server.subscribe();
+        let src = config.server_addrs[0];
+        let add = |item: String| {
+            Message::FromClient(ClientMessage {
+                item,
+                reply_to: None,
+            })
+        };
+
+        // a lagging subscriber
+        for i in 0..=SUBSCRIBER_CAPACITY {
+            server.handle_msg(add(i.to_string()), src).await;
+        }
+        // not a change
+        server.handle_msg(add("0".to_string()), src).await;
+        assert_eq!(server.lagged(), 1);
+        for i in 0..SUBSCRIBER_CAPACITY {
+            let change = changes.try_recv().unwrap();
+            assert_eq!(change.added, [i.to_string()]);
+        }
+        assert!(changes.try_recv().is_err());
+
+        let running = server.running();
+        let handle = tokio::spawn(async move { server.run().await });
+        let mut client = Client::new(&config).await.unwrap();
+        client.disseminate("x").await.unwrap();
+        let change = tokio::time::timeout(time::Duration::from_secs(1), changes.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            change,
+            StateChange {
+                added: vec!["x".to_string()]
+            }
+        );
+        running.store(false, Ordering::SeqCst);
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn counter_overflow() {
+        let mut counter = PnCounter::default();
+        assert!(counter.add(0, i64::MAX));
+        assert!(counter.add(0, i64::MAX));
+        // the tally is refused rather than wrapped or saturated
+        assert!(!counter.add(0, 2));
+        assert_eq!(counter.increments[&0], u64::MAX - 1);
+        assert!(counter.add(0, 1));
+        assert!(!counter.add(0, 1));
+        // sums beyond i64 are reported in full
+        assert!(counter.add(1, i64::MAX));
+        assert_eq!(counter.value(), i128::from(u64::MAX) + i128::from(i64::MAX));
+        for id in 2..6 {
+            assert!(counter.add(id, i64::MIN));
+            assert!(counter.add(id, i64::MIN + 1));
+        }
+        assert_eq!(
+            counter.value(),
+            i128::from(i64::MAX) - 3 * i128::from(u64::MAX)
+        );
+
+        // deltas that do not fit the message are refused
+        let mut config = local_config(1);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        let mut client = Client::new(&config).await.unwrap();
+        for delta in [1 << 63, u64::MAX] {
+            let err = client.increment("hits", delta).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            let err = client.decrement("hits", delta).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        // unconfirmed without a server
+        let err = client.increment("hits", 1).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // and so are adds that would overflow the server's tally
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let handle = tokio::spawn(async move {
+            server.run().await;
+            server.count("hits")
+        });
+        for _ in 0..2 {
+            client.decrement("hits", i64::MAX as u64).await.unwrap();
+        }
+        client.decrement("hits", 1).await.unwrap();
+        let err = client.decrement("hits", 1).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        client.increment("hits", 1).await.unwrap();
+        terminate(&config).await;
+        assert_eq!(handle.await.unwrap(), 1 - i128::from(u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn concurrent_counter_increments() {
+        let mut config = local_config(2);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        let mut servers = Vec::new();
+        for index in 0..2 {
+            servers.push(Server::new(&config, index).await.unwrap());
+        }
+        // both nodes count before hearing from each other
+        let client = config.server_addrs[0];
+        for (server, deltas) in servers.iter_mut().zip([[5, -1], [3, 2]]) {
+            for delta in deltas {
+                let msg = Message::Increment {
+                    key: "hits".into(),
+                    delta,
+                };
+                server.handle_msg(msg, client).await;
+            }
+        }
+        assert_eq!((servers[0].count("hits"), servers[1].count("hits")), (4, 5));
+        let states: Vec<_> = servers.iter().map(|s| s.state.clone()).collect();
+        let [a, b] = &mut servers[..] else {
+            unreachable!()
+        };
+        for (server, state) in [(a, &states[1]), (b, &states[0])] {
+            let msg = Message::FromServer(ServerMessage::State(state.clone()));
+            server.handle_msg(msg, client).await;
+        }
+        for server in &servers {
+            assert_eq!(server.count("hits"), 9);
+            assert_eq!(server.count("misses"), 0);
+        }
+        assert_eq!(servers[0].state_digest(), servers[1].state_digest());
+        // merging again does not count twice
+        let msg = Message::FromServer(ServerMessage::State(states[1].clone()));
+        servers[0].handle_msg(msg, client).await;
+        assert_eq!(servers[0].count("hits"), 9);
+
+        // through the network
+        drop(servers);
+        let handles: Vec<_> = (0..2)
+            .map(|index| {
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let mut server = Server::new(&config, index).await.unwrap();
+                    server.run().await;
+                    server.count("hits")
+                })
+            })
+            .collect();
+        let mut client = Client::new(&config).await.unwrap();
+        client.increment("hits", 2).await.unwrap();
+        client.decrement("hits", 7).await.unwrap();
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        terminate(&config).await;
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), -5);
+        }
+    }
+
+    #[tokio::test]
+    async fn intersection_merge() {
+        let mut config = local_config(3);
+        config.set_merge_mode(MergeMode::Intersection);
+        let handles = spawn_servers(&config);
+        let inputs = [["a", "b", "c"], ["b", "c", "d"], ["c", "b", "e"]];
+        for (addr, items) in config.server_addrs.iter().zip(inputs) {
+            let mut client = Client::new(&Configuration::new(vec![*addr])).await.unwrap();
+            for item in items {
+                client.disseminate(item).await.unwrap();
+            }
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        terminate(&config).await;
+        let states = collect_states(handles).await;
+        for state in states {
+            assert_eq!(state, ["b", "c"].map(String::from).into());
+        }
+    }
+
+    #[tokio::test]
+    async fn add_wins_merge() {
+        let mut config = local_config(3);
+        config.set_merge_mode(MergeMode::AddWins);
+        let handles = spawn_servers(&config);
+        let inputs = [["a", "b"], ["b", "c"], ["d", "e"]];
+        for (addr, items) in config.server_addrs.iter().zip(inputs) {
+            let mut client = Client::new(&Configuration::new(vec![*addr])).await.unwrap();
+            for item in items {
+                client.disseminate(item).await.unwrap();
+            }
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        // removed through a server that saw both adds
+        let mut client = Client::new(&Configuration::new(vec![config.server_addrs[2]]))
+            .await
+            .unwrap();
+        for item in ["b", "e"] {
+            client.remove(item).await.unwrap();
+        }
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        terminate(&config).await;
+        let states = collect_states(handles).await;
+        for state in states {
+            assert_eq!(state, ["a", "c", "d"].map(String::from).into());
+        }
+    }
+
+    #[tokio::test]
+    async fn add_wins_deltas() {
+        let mut config = local_config(2);
+        config.set_merge_mode(MergeMode::AddWins);
+        let mut a = Server::new(&config, 0).await.unwrap();
+        let mut b = Server::new(&config, 1).await.unwrap();
+        let client = "127.0.0.1:9".parse().unwrap();
+        let add = |item: &str| {
+            Message::FromClient(ClientMessage {
+                item: item.to_string(),
+                reply_to: None,
+            })
+        };
+        let remove = |item: &str| Message::Remove {
+            item: item.to_string(),
+        };
+        // what a server broadcast, handed to the other one
+        async fn relay(from: &Server, to: &mut Server) -> usize {
+            let mut buf = [0; 1500];
+            let (n, src) = to.socket.recv_from(&mut buf).await.unwrap();
+            let msg = to.decode(&buf[..n]).unwrap();
+            let Message::FromServer(ServerMessage::Delta { ref delta, .. }) = msg else {
+                panic!("not a delta: {msg:?}");
+            };
+            let dots = delta.dots();
+            assert_eq!(src, from.config.server_addrs[from.index]);
+            to.handle_msg(msg, src).await;
+            dots
+        }
+
+        for i in 0..10 {
+            a.handle_msg(add(&i.to_string()), client).await;
+            relay(&a, &mut b).await;
+        }
+        // a delta carries the new dot only, not the state
+        a.handle_msg(add("x"), client).await;
+        assert_eq!(relay(&a, &mut b).await, 1);
+        assert_eq!(a.state.items, b.state.items);
+        assert_eq!(b.state.items.len(), 11);
+
+        // b removes x while a removes and adds it back: the new add wins
+        b.handle_msg(remove("x"), client).await;
+        a.handle_msg(remove("x"), client).await;
+        a.handle_msg(add("x"), client).await;
+        relay(&b, &mut a).await;
+        relay(&a, &mut b).await;
+        relay(&a, &mut b).await;
+        assert!(a.state.items.contains("x"));
+        assert_eq!(a.state.items, b.state.items);
+        assert_eq!(a.state_digest(), b.state_digest());
+
+        // an observed add is removed for good
+        b.handle_msg(remove("x"), client).await;
+        assert_eq!(relay(&b, &mut a).await, 0);
+        assert!(!a.state.items.contains("x"));
+        assert_eq!(
+            a.state,
+            ServerState {
+                id: 0,
+                ..b.state.clone()
+            }
+        );
+
+        // removes are rejected in the other modes
+        let mut union = Server::new(&local_config(1), 0).await.unwrap();
+        union.handle_msg(add("y"), client).await;
+        union.handle_msg(remove("y"), client).await;
+        assert!(union.state.items.contains("y"));
+    }
+
+    #[test]
+    fn equal_states_encode_identically() {
+        let state = |ids: Vec<u128>| {
+            let mut state = ServerState::new(0);
+            state.clock = ids.iter().map(|id| (*id, id + 1)).collect();
+            for id in ids {
+                state.add_source("x", format!("10.0.0.{id}:8000"));
+                state.add_source(&format!("item-{id}"), "10.0.0.1:8000".into());
+            }
+            state
+        };
+        let ids: Vec<u128> = (0..64).collect();
+        let a = state(ids.clone());
+        let b = state(ids.into_iter().rev().collect());
+        let msg = |state: &ServerState| Message::FromServer(ServerMessage::State(state.clone()));
+        assert_eq!(encode(&msg(&a)).unwrap(), encode(&msg(&b)).unwrap());
+        assert_eq!(encode(&msg(&a)).unwrap(), encode(&msg(&a.clone())).unwrap());
+    }
+
+    #[tokio::test]
+    async fn state_digest() {
+        let config = local_config(3);
+        let mut a = Server::new(&config, 0).await.unwrap();
+        let mut b = Server::new(&config, 1).await.unwrap();
+        let mut c = Server::new(&config, 2).await.unwrap();
+        assert_eq!(a.state_digest(), b.state_digest());
+
+        // converged through different histories
+        a.state.add(BTreeSet::from(["hello".to_string()]));
+        a.state.add(BTreeSet::from(["world".to_string()]));
+        b.state
+            .add(BTreeSet::from(["world".to_string(), "hello".to_string()]));
+        assert_eq!(a.state_digest(), b.state_digest());
+
+        b.state.add(BTreeSet::from(["!".to_string()]));
+        assert_ne!(a.state_digest(), b.state_digest());
+        c.state.add(BTreeSet::from(["helloworld".to_string()]));
+        assert_ne!(a.state_digest(), c.state_digest());
+    }
+
+    #[tokio::test]
+    async fn digest_algorithms() {
+        for algorithm in [
+            DigestAlgorithm::Sha256,
+            DigestAlgorithm::TruncatedSha256 { len: 8 },
+            DigestAlgorithm::Blake3,
+        ] {
+            let mut config = local_config(2);
+            config.set_digest_algorithm(algorithm);
+            let mut a = Server::new(&config, 0).await.unwrap();
+            let mut b = Server::new(&config, 1).await.unwrap();
+            a.state.add(BTreeSet::from(["hello".to_string()]));
+            b.state.add(BTreeSet::from(["hello".to_string()]));
+            assert_eq!(a.state_digest(), b.state_digest());
+            let len = if algorithm == (DigestAlgorithm::TruncatedSha256 { len: 8 }) {
+                8
+            } else {
+                32
+            };
+            assert_eq!(a.state_digest().len(), len);
+
+            b.state.add(BTreeSet::from(["world".to_string()]));
+            assert_ne!(a.state_digest(), b.state_digest());
+        }
+
+        // a converged peer configured differently does not answer a digest
+        let config = local_config(2);
+        let mut a = Server::builder(&config, 0)
+            .digest_algorithm(DigestAlgorithm::Blake3)
+            .build()
+            .await
+            .unwrap();
+        let mut b = Server::new(&config, 1).await.unwrap();
+        let digest = |server: &Server| {
+            Message::FromServer(ServerMessage::Digest {
+                index: 0,
+                digest: server.state_digest(),
+                algorithm: DigestAlgorithm::Blake3,
+            })
+        };
+        b.handle_msg(digest(&a), config.server_addrs[0]).await;
+        let mut buf = [0; 1500];
+        let recv = tokio::time::timeout(time::Duration::from_millis(100), a.socket.recv(&mut buf));
+        assert!(recv.await.is_err());
+        a.state.add(BTreeSet::from(["hello".to_string()]));
+        b.handle_msg(digest(&a), config.server_addrs[0]).await;
+        let n = a.socket.recv(&mut buf).await.unwrap();
+        assert!(matches!(
+            decode(&buf[..n]),
+            Ok(Message::FromServer(ServerMessage::State(_)))
+        ));
+    }
+
+    #[test]
+    fn truncated_digest_lengths() {
+        let mut config = local_config(2);
+        for len in [4, 32] {
+            config.set_digest_algorithm(DigestAlgorithm::TruncatedSha256 { len });
+        }
+        for len in [0, 3, 33] {
+            let algorithm = DigestAlgorithm::TruncatedSha256 { len };
+            let set = std::panic::catch_unwind(|| local_config(2).set_digest_algorithm(algorithm));
+            assert!(set.is_err());
+            let built = std::panic::catch_unwind(|| {
+                Server::builder(&local_config(2), 0).digest_algorithm(algorithm)
+            });
+            assert!(built.is_err());
+        }
+
+        // digests and configurations received with a bad length are dropped
+        let digest = |len| {
+            format!(
+                "{{\"FromServer\":{{\"Digest\":{{\"index\":0,\"digest\":[],\"algorithm\":{{\"TruncatedSha256\":{{\"len\":{len}}}}}}}}}}}"
+            )
+        };
+        assert!(serde_json::from_str::<Message>(&digest(16)).is_ok());
+        assert!(serde_json::from_str::<Message>(&digest(2)).is_err());
+        let mut json = serde_json::to_value(&config).unwrap();
+        json["digest_algorithm"]["TruncatedSha256"]["len"] = 64.into();
+        assert!(serde_json::from_value::<Configuration>(json).is_err());
+    }
+
+    async fn first_peers(config: &Configuration, rounds: usize) -> Vec<usize> {
+        let mut server = Server::new(config, 0).await.unwrap();
+        (0..rounds).map(|_| server.broadcast_peers()[0]).collect()
+    }
+
+    fn first_counts(first: &[usize]) -> BTreeMap<usize, usize> {
+        let mut counts = BTreeMap::new();
+        for &i in first {
+            *counts.entry(i).or_default() += 1;
+        }
+        counts
+    }
+
+    #[tokio::test]
+    async fn broadcast_order_fairness() {
+        let mut config = local_config(4);
+        // peers are always served in configuration order by default
+        let first = first_peers(&config, 30).await;
+        assert!(first.iter().all(|&i| i == 1));
+
+        config.set_broadcast_order(BroadcastOrder::RoundRobin);
+        let first = first_peers(&config, 300).await;
+        assert_eq!(&first[..4], &[1, 2, 3, 1]);
+        assert!(first_counts(&first).values().all(|&n| n == 100));
+
+        config.set_broadcast_order(BroadcastOrder::Shuffled { seed: 42 });
+        let first = first_peers(&config, 300).await;
+        // reproducible for the same seed
+        assert_eq!(first, first_peers(&config, 300).await);
+        let counts = first_counts(&first);
+        assert_eq!(counts.len(), 3);
+        assert!(
+            counts.values().all(|&n| (70..=130).contains(&n)),
+            "{counts:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn gossip_fanout_converges() {
+        // Start servers
+        let mut config = local_config(8);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        config.set_fanout(2);
+        let handles = spawn_servers(&config);
+        // Run client
+        let mut client = Client::new(&config).await.unwrap();
+        client.disseminate("hello").await.unwrap();
+        client.disseminate("world").await.unwrap();
+        // End test, after at most 50 anti-entropy rounds
+        tokio::time::sleep(time::Duration::from_millis(500)).await;
+        terminate(&config).await;
+        let states = collect_states(handles).await;
+        assert!(states.iter().all(|s| s.contains("hello")));
+        assert!(states.iter().all(|s| s.contains("world")));
+        assert!(states.iter().all(|s| s.len() == 2));
+    }
+
+    #[tokio::test]
+    async fn consistent_hash_selection() {
+        let mut config = local_config(6);
+        config.set_peer_selector(PeerSelector::ConsistentHash { fanout: 2 });
+        let server = Server::new(&config, 0).await.unwrap();
+        let peers = server.hashed_peers(b"item", 2);
+        assert_eq!(peers.len(), 2);
+        assert!(!peers.contains(&0));
+        for _ in 0..10 {
+            assert_eq!(server.hashed_peers(b"item", 2), peers);
+        }
+        // the top peer of a fanout of two is the one of a fanout of one
+        assert_eq!(server.hashed_peers(b"item", 1), peers[..1]);
+        // other items spread over other peers
+        let covered: BTreeSet<usize> = (0..100)
+            .flat_map(|i| server.hashed_peers(i.to_string().as_bytes(), 2))
+            .collect();
+        assert_eq!(covered, (1..6).collect());
+    }
+
+    #[tokio::test]
+    async fn other_protocol_versions_are_rejected() {
+        let config = local_config(1);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let envelope = |version: u8, msg: serde_json::Value| {
+            serde_json::to_vec(&serde_json::json!({ "version": version, "msg": msg })).unwrap()
+        };
+
+        // a newer node, with a message this one does not know
+        let newer = envelope(PROTOCOL_VERSION + 1, serde_json::json!({"Rebalance": {}}));
+        assert!(server.decode(&newer).is_none());
+        let older = envelope(0, serde_json::json!("Terminate"));
+        assert!(server.decode(&older).is_none());
+        // unversioned, as sent before versioning
+        assert!(server.decode(br#""Terminate""#).is_none());
+        assert_eq!(server.incompatible(), 2);
+        assert_eq!(server.rejected(), 1);
+
+        let current = envelope(PROTOCOL_VERSION, serde_json::json!("Terminate"));
+        assert!(matches!(server.decode(&current), Some(Message::Terminate)));
+        assert_eq!(server.incompatible(), 2);
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_rejected() {
+        let config = local_config(1);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let mut state = ServerState::new(1);
+        state.items = (0..=MAX_MESSAGE_ITEMS).map(|i| i.to_string()).collect();
+        let encode = |msg: &Message| super::encode(msg).unwrap();
+
+        let oversized = Message::FromServer(ServerMessage::State(state.clone()));
+        assert!(server.decode(&encode(&oversized)).is_none());
+        state.items = ["x".repeat(MAX_MESSAGE_BYTES + 1)].into();
+        let oversized = Message::FromServer(ServerMessage::State(state.clone()));
+        assert!(server.decode(&encode(&oversized)).is_none());
+        let chunk = |seq, total| {
+            Message::FromServer(ServerMessage::StateChunk {
+                clock: Clock::new(),
+                id: 1,
+                seq,
+                total,
+                items: Vec::new(),
+            })
+        };
+        assert!(server.decode(&encode(&chunk(0, usize::MAX))).is_none());
+        assert!(server.decode(&encode(&chunk(2, 2))).is_none());
+        assert!(server.decode(b"{\"FromClient\":").is_none());
+        assert_eq!(server.rejected(), 5);
+
+        state.items = (0..MAX_MESSAGE_ITEMS).map(|i| i.to_string()).collect();
+        let within = Message::FromServer(ServerMessage::State(state));
+        assert!(server.decode(&encode(&within)).is_some());
+        assert!(server.decode(&encode(&chunk(1, 2))).is_some());
+        assert_eq!(server.rejected(), 5);
+    }
+
+    #[tokio::test]
+    async fn oversized_items_are_dropped() {
+        let config = local_config(1);
+        let mut server = Server::builder(&config, 0)
+            .max_item_len(4)
+            .build()
+            .await
+            .unwrap();
+        for item in ["four", "fives"] {
+            let msg = Message::FromClient(ClientMessage {
+                item: item.to_string(),
+                reply_to: None,
+            });
+            server.handle_msg(msg, config.server_addrs[0]).await;
+        }
+        assert_eq!(server.state.items, BTreeSet::from(["four".to_string()]));
+        assert_eq!(server.oversized_items(), 1);
+
+        // the client learns why
+        let handle = tokio::spawn(async move {
+            server.run().await;
+            server.oversized_items()
+        });
+        let mut client = Client::new(&config).await.unwrap();
+        let wait = time::Duration::from_secs(1);
+        client.disseminate_confirmed("many", wait).await.unwrap();
+        let err = client
+            .disseminate_confirmed("longer", wait)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("longer than 4 bytes"), "{err}");
+        terminate(&config).await;
+        assert_eq!(handle.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn lossy_network_converges() {
+        let mut config = local_config(3);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        // anti-entropy repairs what gossip loses
+        config.set_fanout(1);
+        let mut handles = Vec::new();
+        for index in 0..3 {
+            let transport = FaultyTransport::new(index as u64)
+                .drop_probability(0.5)
+                .duplicate_probability(0.1)
+                .delay_probability(0.1, time::Duration::from_millis(5));
+            let mut server = Server::builder(&config, index)
+                .transport(Arc::new(transport))
+                .build()
+                .await
+                .unwrap();
+            handles.push(tokio::spawn(async move {
+                server.run().await;
+                server.state.items.clone()
+            }));
+        }
+        let mut client = Client::with_policy(&config, ClientPolicy::RoundRobin)
+            .await
+            .unwrap();
+        let items: BTreeSet<String> = (0..6).map(|i| i.to_string()).collect();
+        for item in &items {
+            client.disseminate(item).await.unwrap();
+        }
+        tokio::time::sleep(time::Duration::from_millis(500)).await;
+        terminate(&config).await;
+        for state in collect_states(handles).await {
+            assert_eq!(state, items);
+        }
+    }
+
+    #[tokio::test]
+    async fn sealed_server_rejects_changes() {
+        let config = local_config(2);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let mut peer = ServerState::new(1);
+        peer.add(BTreeSet::from(["peer".to_string()]));
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(time::Duration::from_secs(1)))
+            .unwrap();
+        let src = client.local_addr().unwrap();
+        let add = |item: &str| {
+            Message::FromClient(ClientMessage {
+                item: item.to_string(),
+                reply_to: None,
+            })
+        };
+
+        server.seal();
+        server.handle_msg(add("x"), src).await;
+        let state = Message::FromServer(ServerMessage::State(peer.clone()));
+        server.handle_msg(state, config.server_addrs[1]).await;
+        assert!(server.state.items.is_empty());
+        let mut buf = [0; 1500];
+        let n = client.recv(&mut buf).unwrap();
+        let Ok(Message::Rejected { item, reason }) = decode(&buf[..n]) else {
+            panic!("not rejected")
+        };
+        assert_eq!((item.as_str(), reason.as_str()), ("x", SEALED));
+
+        server.unseal();
+        server.handle_msg(add("x"), src).await;
+        let state = Message::FromServer(ServerMessage::State(peer));
+        server.handle_msg(state, config.server_addrs[1]).await;
+        assert_eq!(
+            server.state.items,
+            BTreeSet::from(["peer".to_string(), "x".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn joins_are_validated() {
+        let mut config = local_config(2);
+        config.set_max_peers(2);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let src = config.server_addrs[1];
+        let join = |addr: &str| Message::Join {
+            addr: addr.to_string(),
+        };
+        server.handle_msg(join(&src.to_string()), src).await;
+        assert_eq!(server.config.server_addrs.len(), 2);
+        assert_eq!(server.rejected_joins(), 0);
+
+        server.handle_msg(join("not an address"), src).await;
+        assert_eq!(server.rejected_joins(), 1);
+
+        server.handle_msg(join("127.0.0.1:1"), src).await;
+        server.handle_msg(join("127.0.0.1:1"), src).await;
+        assert_eq!(server.config.server_addrs.len(), 3);
+        assert_eq!(server.rejected_joins(), 1);
+
+        // two peers already
+        server.handle_msg(join("127.0.0.1:2"), src).await;
+        assert_eq!(server.config.server_addrs.len(), 3);
+        assert_eq!(server.rejected_joins(), 2);
+    }
+
+    #[tokio::test]
+    async fn strict_peers_drop_spoofed_messages() {
+        let mut config = local_config(2);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        config.set_strict_peers(true);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let running = server.running();
+        let handle = tokio::spawn(async move {
+            server.run().await;
+            server
+        });
+
+        let send = |socket: std::net::UdpSocket, msg: Message| {
+            let buf = encode(&msg).unwrap();
+            socket.send_to(&buf, config.server_addrs[0]).unwrap();
+        };
+        let state = |item: &str| {
+            let mut state = ServerState::new(1);
+            state.add(BTreeSet::from([item.to_string()]));
+            Message::FromServer(ServerMessage::State(state))
+        };
+        let unlisted = || std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        send(unlisted(), state("spoofed"));
+        send(unlisted(), Message::Terminate);
+        // a listed peer and any client are still heard
+        send(
+            std::net::UdpSocket::bind(config.server_addrs[1]).unwrap(),
+            state("peer"),
+        );
+        let item = String::from("client");
+        send(
+            unlisted(),
+            Message::FromClient(ClientMessage {
+                item,
+                reply_to: None,
+            }),
+        );
+
+        tokio::time::sleep(time::Duration::from_millis(100)).await;
+        running.store(false, Ordering::SeqCst);
+        let server = handle.await.unwrap();
+        assert_eq!(server.spoofed(), 2);
+        assert_eq!(
+            server.state.items,
+            BTreeSet::from(["client".to_string(), "peer".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn server_builder() {
+        let config = local_config(2);
+        let path = std::env::temp_dir().join(format!("{}-builder.state", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = Server::builder(&config, 1)
+            .recv_timeout(time::Duration::from_millis(10))
+            .fanout(1)
+            .strict_peers(true)
+            .item_ttl(time::Duration::from_secs(60))
+            .persistence(&path, PersistencePolicy::OnEveryMerge)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(server.index, 1);
+        assert_eq!(server.config.recv_timeout, time::Duration::from_millis(10));
+        assert_eq!(server.config.peer_selector.fanout(), Some(1));
+        assert!(server.config.strict_peers);
+        assert_eq!(server.config.item_ttl, Some(time::Duration::from_secs(60)));
+        // the options are not written back into the shared configuration
+        assert_eq!(config.peer_selector.fanout(), None);
+
+        let item = Message::FromClient(ClientMessage {
+            item: "a".to_string(),
+            reply_to: None,
+        });
+        server.handle_msg(item, config.server_addrs[0]).await;
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(Server::builder(&config, 2).build().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn normalized_items_collapse() {
+        let config = local_config(1);
+        let mut server = Server::builder(&config, 0)
+            .normalizer(|item| item.trim().to_lowercase())
+            .build()
+            .await
+            .unwrap();
+        let client = config.server_addrs[0];
+        for item in ["Item", "item ", "ITEM"] {
+            let msg = Message::FromClient(ClientMessage {
+                item: item.to_string(),
+                reply_to: None,
+            });
+            server.handle_msg(msg, client).await;
+        }
+        assert_eq!(server.state.items, BTreeSet::from(["item".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn close_flushes_and_leaves() {
+        let config = local_config(2);
+        let path = std::env::temp_dir().join(format!("{}-close.state", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let mut leaving = Server::builder(&config, 1)
+            .persistence(&path, PersistencePolicy::EveryN(100))
+            .build()
+            .await
+            .unwrap();
+        let item = Message::FromClient(ClientMessage {
+            item: "a".to_string(),
+            reply_to: None,
+        });
+        leaving.handle_msg(item, config.server_addrs[0]).await;
+        assert!(!path.exists());
+        leaving.close().await.unwrap();
+        let persisted = Persistence::new(&path, PersistencePolicy::OnEveryMerge).load();
+        assert_eq!(persisted.unwrap().unwrap().items, ["a".to_string()].into());
+        std::fs::remove_file(&path).unwrap();
+
+        // the state broadcast, then the leave
+        let mut buf = [0; 1500];
+        for _ in 0..2 {
+            let (n, src) = server.socket.recv_from(&mut buf).await.unwrap();
+            let msg = server.decode(&buf[..n]).unwrap();
+            server.handle_msg(msg, src).await;
+        }
+        assert_eq!(server.peer_status(1), PeerStatus::Left);
+        assert!(server.broadcast_peers().is_empty());
+
+        // back once it is heard from again
+        let mut state = ServerState::new(1);
+        state.clock.inc(1);
+        let msg = Message::FromServer(ServerMessage::State(state));
+        server.handle_msg(msg, config.server_addrs[1]).await;
+        assert_eq!(server.peer_status(1), PeerStatus::Alive);
+    }
+
+    #[tokio::test]
+    async fn persistence_every_n() {
+        let mut config = local_config(1);
+        config.set_recv_timeout(time::Duration::from_millis(10));
+        let path = std::env::temp_dir().join(format!("{}-every-n.state", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        server
+            .set_persistence(&path, PersistencePolicy::EveryN(3))
+            .unwrap();
+        let src = config.server_addrs[0];
+        let add = |item: &str| {
+            Message::FromClient(ClientMessage {
+                item: item.to_string(),
+                reply_to: None,
+            })
+        };
+        for item in ["a", "b"] {
+            server.handle_msg(add(item), src).await;
+            assert!(!path.exists());
+        }
+        // not a change
+        server.handle_msg(add("a"), src).await;
+        assert!(!path.exists());
+        server.handle_msg(add("c"), src).await;
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(saved.lines().count(), 4);
+        server.handle_msg(add("d"), src).await;
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), saved);
+
+        // flushed on shutdown
+        let running = server.running();
+        let handle = tokio::spawn(async move { server.run().await });
+        tokio::time::sleep(time::Duration::from_millis(10)).await;
+        running.store(false, Ordering::SeqCst);
+        handle.await.unwrap();
+        let mut server = Server::new(&config, 0).await.unwrap();
+        server
+            .set_persistence(&path, PersistencePolicy::OnEveryMerge)
+            .unwrap();
+        assert_eq!(
+            server.state.items,
+            ["a", "b", "c", "d"].map(String::from).into()
+        );
+        assert_eq!(server.state.clock.get(0), 4);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn persistence_restores_full_state() {
+        let mut config = local_config(2);
+        config.set_merge_mode(MergeMode::Intersection);
+        let path = std::env::temp_dir().join(format!("{}-full.state", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut server = Server::builder(&config, 0)
+            .persistence(&path, PersistencePolicy::OnEveryMerge)
+            .build()
+            .await
+            .unwrap();
+        let client = config.server_addrs[0];
+        for item in ["a", "b"] {
+            let msg = Message::FromClient(ClientMessage {
+                item: item.to_string(),
+                reply_to: None,
+            });
+            server.handle_msg(msg, client).await;
+        }
+        let mut peer = ServerState::new(1);
+        peer.clock.inc(1);
+        peer.inputs.insert(1, ["a".to_string()].into());
+        let msg = Message::FromServer(ServerMessage::State(peer));
+        server.handle_msg(msg, config.server_addrs[1]).await;
+        let key = "visits".to_string();
+        server
+            .handle_msg(Message::Increment { key, delta: 3 }, client)
+            .await;
+        assert_eq!(server.state.items, ["a".to_string()].into());
+        let saved = server.state.clone();
+        drop(server);
+
+        let mut server = Server::builder(&config, 0)
+            .persistence(&path, PersistencePolicy::OnEveryMerge)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(server.state, saved);
+        assert_eq!(server.count("visits"), 3);
+        // the restored inputs keep the intersection
+        server.derive_items();
+        assert_eq!(server.state.items, ["a".to_string()].into());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn configuration_serde() {
+        let mut config = local_config(3);
+        config.set_fanout(2);
+        config.set_broadcast_order(BroadcastOrder::Shuffled { seed: 7 });
+        let json = serde_json::to_string(&Message::Config(config.clone())).unwrap();
+        let Message::Config(decoded) = serde_json::from_str(&json).unwrap() else {
+            panic!("not a config message: {json}");
+        };
+        assert_eq!(decoded, config);
+    }
+
+    #[tokio::test]
+    async fn push_config_updates_peers() {
+        let config = local_config(2);
+        let mut server = Server::new(&config, 0).await.unwrap();
+        let mut grown = local_config(3);
+        grown.server_addrs[0] = config.server_addrs[0];
+        let src = config.server_addrs[1];
+        server.handle_msg(Message::Config(grown.clone()), src).await;
+        assert_eq!(server.config.server_addrs, grown.server_addrs);
+
+        // a config that moves this server elsewhere is rejected
+        let moved = local_config(3);
+        server.handle_msg(Message::Config(moved), src).await;
+        assert_eq!(server.config.server_addrs, grown.server_addrs);
+
+        // and so is one that fails validation
+        let mut invalid = grown.clone();
+        invalid.mailbox_capacity = 0;
+        server.handle_msg(Message::Config(invalid), src).await;
+        assert_eq!(server.config, grown);
+    }
+
+    #[test]
+    fn configuration_is_validated() {
+        let config = local_config(3);
+        assert!(config.validate().is_ok());
+        let json = serde_json::to_value(&config).unwrap();
+        for (field, value, expected) in [
+            (
+                "recv_timeout",
+                serde_json::json!({"secs": 0, "nanos": 0}),
+                "recv_timeout",
+            ),
+            (
+                "peer_selector",
+                serde_json::json!({"RandomFanout": {"fanout": 0}}),
+                "fanout",
+            ),
+            ("mailbox_capacity", 0.into(), "mailbox_capacity"),
+            ("max_item_len", 0.into(), "max_item_len"),
+            ("max_peers", 1.into(), "max_peers"),
+        ] {
+            let mut invalid = json.clone();
+            invalid[field] = value;
+            let err = serde_json::from_value::<Configuration>(invalid).unwrap_err();
+            assert!(err.to_string().contains(expected), "{err}");
+        }
+        let mut twice = json.clone();
+        twice["server_addrs"][1] = twice["server_addrs"][0].clone();
+        assert!(serde_json::from_value::<Configuration>(twice).is_err());
+
+        for set in [
+            |config: &mut Configuration| config.set_recv_timeout(Duration::ZERO),
+            |config: &mut Configuration| config.set_fanout(0),
+            |config: &mut Configuration| config.set_mailbox_capacity(0),
+            |config: &mut Configuration| config.set_max_item_len(0),
+            |config: &mut Configuration| config.set_max_peers(1),
+        ] {
+            let mut config = config.clone();
+            assert!(std::panic::catch_unwind(move || set(&mut config)).is_err());
+        }
+    }
+
+    fn write_temp(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn toml_configuration() {
+        let path = write_temp(
+            "accumulator.toml",
+            r#"
+            server_addrs = ["127.0.0.1:8000", "localhost:8001"]
+            recv_timeout_ms = 50
+            fanout = 1
+            "#,
+        );
+        let config = Configuration::from_toml_path(&path).unwrap();
+        assert_eq!(config.server_addrs.len(), 2);
+        assert_eq!(config.server_addrs[0], "127.0.0.1:8000".parse().unwrap());
+        assert_eq!(config.server_addrs[1].port(), 8001);
+        assert_eq!(config.recv_timeout, time::Duration::from_millis(50));
+        assert_eq!(config.peer_selector.fanout(), Some(1));
+
+        // defaults
+        let path = write_temp("defaults.toml", r#"server_addrs = ["127.0.0.1:8000"]"#);
+        let config = Configuration::from_toml_path(&path).unwrap();
+        assert_eq!(config.recv_timeout, DEFAULT_RECV_TIMEOUT);
+        assert_eq!(config.peer_selector.fanout(), None);
+        assert_eq!(config, Configuration::new(config.server_addrs.clone()));
+
+        // every option
+        let path = write_temp(
+            "options.toml",
+            r#"
+            server_addrs = ["127.0.0.1:8000", "127.0.0.1:8001"]
+            peer_selector = "consistent_hash"
+            fanout = 1
+            broadcast_order = "shuffled"
+            broadcast_seed = 7
+            strict_peers = true
+            item_ttl_ms = 60000
+            suspect_after_ms = 3000
+            mailbox_capacity = 8
+            track_provenance = true
+            causal_order = true
+            merge_mode = "intersection"
+            chunk_timeout_ms = 100
+            max_item_len = 64
+            max_peers = 4
+            digest_algorithm = "truncated_sha256"
+            digest_len = 16
+            "#,
+        );
+        let config = Configuration::from_toml_path(&path).unwrap();
+        let mut expected = Configuration::new(config.server_addrs.clone());
+        expected.set_peer_selector(PeerSelector::ConsistentHash { fanout: 1 });
+        expected.set_broadcast_order(BroadcastOrder::Shuffled { seed: 7 });
+        expected.set_strict_peers(true);
+        expected.set_item_ttl(time::Duration::from_secs(60));
+        expected.set_suspect_after(time::Duration::from_secs(3));
+        expected.set_mailbox_capacity(8);
+        expected.set_track_provenance(true);
+        expected.set_causal_order(true);
+        expected.set_merge_mode(MergeMode::Intersection);
+        expected.set_chunk_timeout(time::Duration::from_millis(100));
+        expected.set_max_item_len(64);
+        expected.set_max_peers(4);
+        expected.set_digest_algorithm(DigestAlgorithm::TruncatedSha256 { len: 16 });
+        assert_eq!(config, expected);
+
+        // the line format, unless the extension says TOML
+        let path = write_temp("accumulator.conf", "127.0.0.1:8000\n127.0.0.1:8001\n");
+        let config = Configuration::load(&path).unwrap();
+        assert_eq!(config, Configuration::from_file(path.to_str().unwrap()));
+        let path = write_temp("load.toml", r#"server_addrs = ["127.0.0.1:8000"]"#);
+        assert_eq!(Configuration::load(&path).unwrap().server_addrs.len(), 1);
+        let path = write_temp("broken.conf", "127.0.0.1:8000\nnowhere\n");
+        let err = Configuration::load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_toml_configuration() {
+        for (name, contents, expected) in [
+            ("syntax.toml", "server_addrs = [", "server_addrs"),
+            (
+                "unknown.toml",
+                "server_addrs = []\nport = 1",
+                "unknown field",
+            ),
+            ("empty.toml", "server_addrs = []", "must not be empty"),
+            (
+                "addr.toml",
+                r#"server_addrs = ["nowhere"]"#,
+                "invalid server address",
+            ),
+            (
+                "fanout.toml",
+                "server_addrs = [\"127.0.0.1:8000\"]\nfanout = 0",
+                "fanout",
+            ),
+            (
+                "selector.toml",
+                "server_addrs = [\"127.0.0.1:8000\"]\npeer_selector = \"consistent_hash\"",
+                "requires fanout",
+            ),
+            (
+                "order.toml",
+                "server_addrs = [\"127.0.0.1:8000\"]\nbroadcast_order = \"random\"",
+                "unknown broadcast_order",
+            ),
+            (
+                "ttl.toml",
+                "server_addrs = [\"127.0.0.1:8000\"]\nitem_ttl_ms = 0",
+                "item_ttl",
+            ),
+            (
+                "twice.toml",
+                "server_addrs = [\"127.0.0.1:8000\", \"127.0.0.1:8000\"]",
+                "listed twice",
+            ),
+            (
+                "mailbox.toml",
+                "server_addrs = [\"127.0.0.1:8000\"]\nmailbox_capacity = 0",
+                "mailbox_capacity",
+            ),
+            (
+                "merge.toml",
+                "server_addrs = [\"127.0.0.1:8000\"]\nmerge_mode = \"all\"",
+                "unknown merge_mode",
+            ),
+            (
+                "peers.toml",
+                "server_addrs = [\"127.0.0.1:8000\", \"127.0.0.1:8001\"]\nmax_peers = 0",
+                "max_peers",
+            ),
+            (
+                "digest.toml",
+                "server_addrs = [\"127.0.0.1:8000\"]\ndigest_algorithm = \"truncated_sha256\"\ndigest_len = 64",
+                "truncated digest length",
+            ),
+            (
+                "short.toml",
+                "server_addrs = [\"127.0.0.1:8000\"]\ndigest_algorithm = \"truncated_sha256\"\ndigest_len = 2",
+                "truncated digest length",
+            ),
+        ] {
+            let path = write_temp(name, contents);
+            let err = Configuration::from_toml_path(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            let msg = err.to_string();
+            assert!(msg.contains(name), "{msg}");
+            assert!(msg.contains(expected), "{msg}");
+        }
     }
 }
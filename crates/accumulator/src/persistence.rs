@@ -0,0 +1,222 @@
+//! Persistence of a server's state to a local file.
+//!
+//! The file holds JSON lines: the state without its items on the first
+//! line, i.e. its clock, item stamps, provenance, per-node inputs and
+//! counters, followed by one line per item. Flushes write a temporary file
+//! next to it and rename it over the old one, so a crash leaves either the
+//! old or the new state, never a mix or a truncated file.
+
+use crate::ServerState;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When a server writes its changed state to the persistence file. Pending
+/// changes are always flushed when the server shuts down.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistencePolicy {
+    /// Flush after every change of the state.
+    OnEveryMerge,
+    /// Flush once every `n` changes.
+    EveryN(usize),
+    /// Flush changes at most once per interval.
+    Interval(Duration),
+}
+
+/// The persistence file of a server, and its pending changes.
+pub struct Persistence {
+    path: PathBuf,
+    policy: PersistencePolicy,
+    dirty: usize,
+    last_flush: Instant,
+}
+
+impl Persistence {
+    pub fn new(path: impl Into<PathBuf>, policy: PersistencePolicy) -> Self {
+        Self {
+            path: path.into(),
+            policy,
+            dirty: 0,
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Read the persisted state, if the file exists. A file cut short, e.g.
+    /// when it was copied to a full disk, is recovered up to its last
+    /// complete record: a record is a full, newline terminated line, and
+    /// reading stops at the first record that is not. Without a complete
+    /// state record nothing is recovered.
+    pub(crate) fn load(&self) -> io::Result<Option<ServerState>> {
+        let buf = match std::fs::read(&self.path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut records = buf.split_inclusive(|b| *b == b'\n');
+        let total = records.clone().count();
+        let Some(mut state) = next_record::<ServerState>(&mut records) else {
+            if total > 0 {
+                eprintln!(
+                    "Error: {}: no complete state record, starting empty",
+                    self.path.display()
+                );
+            }
+            return Ok(None);
+        };
+        while let Some(item) = next_record(&mut records) {
+            state.items.insert(item);
+        }
+        if state.items.len() + 1 < total {
+            eprintln!(
+                "Error: {}: recovered {} of {} records, the rest is incomplete",
+                self.path.display(),
+                state.items.len() + 1,
+                total
+            );
+        }
+        Ok(Some(state))
+    }
+
+    /// Record a change of the state. Returns true if the policy asks for a
+    /// flush now.
+    pub(crate) fn changed(&mut self) -> bool {
+        self.dirty += 1;
+        match self.policy {
+            PersistencePolicy::OnEveryMerge => true,
+            PersistencePolicy::EveryN(n) => self.dirty >= n,
+            PersistencePolicy::Interval(interval) => self.last_flush.elapsed() >= interval,
+        }
+    }
+
+    /// Whether pending changes are due under an interval policy, for periodic
+    /// checks without a new change.
+    pub(crate) fn due(&self) -> bool {
+        match self.policy {
+            PersistencePolicy::Interval(interval) => {
+                self.dirty > 0 && self.last_flush.elapsed() >= interval
+            }
+            _ => false,
+        }
+    }
+
+    /// Write the state if it has pending changes. The items are taken out of
+    /// `state` while the rest of it is written, and put back before
+    /// returning.
+    pub(crate) fn flush(&mut self, state: &mut ServerState) -> io::Result<()> {
+        if self.dirty == 0 {
+            return Ok(());
+        }
+        let items = std::mem::take(&mut state.items);
+        let written = self.write(state, &items);
+        state.items = items;
+        written?;
+        self.dirty = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// Write `state`, whose items are `items`, to a temporary file and
+    /// rename it over the persistence file.
+    fn write(&self, state: &ServerState, items: &BTreeSet<String>) -> io::Result<()> {
+        let tmp = self.tmp_path();
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, state)?;
+        writeln!(writer)?;
+        for item in items {
+            serde_json::to_writer(&mut writer, item)?;
+            writeln!(writer)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        // make the rename itself durable
+        #[cfg(unix)]
+        File::open(self.dir())?.sync_all()?;
+        Ok(())
+    }
+
+    /// File a flush is written to before it replaces the persistence file.
+    fn tmp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+
+    /// Directory holding the persistence file.
+    #[cfg(unix)]
+    fn dir(&self) -> &Path {
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+}
+
+/// Parse the next record, if it is complete.
+fn next_record<'a, T: DeserializeOwned>(records: &mut impl Iterator<Item = &'a [u8]>) -> Option<T> {
+    let record = records.next()?.strip_suffix(b"\n")?;
+    serde_json::from_slice(record).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recover_truncated_file() {
+        let path = std::env::temp_dir().join(format!("{}-truncated.state", std::process::id()));
+        let mut persistence = Persistence::new(&path, PersistencePolicy::OnEveryMerge);
+        let mut state = ServerState::new(0);
+        state.clock.inc(0);
+        state.items = ["first", "second", "third"].map(String::from).into();
+        persistence.changed();
+        persistence.flush(&mut state).unwrap();
+        assert_eq!(persistence.load().unwrap().unwrap(), state);
+
+        // cut into the last record
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        let loaded = persistence.load().unwrap().unwrap();
+        assert_eq!(loaded.clock, state.clock);
+        assert_eq!(loaded.items, ["first", "second"].map(String::from).into());
+
+        // only the newline of the last record is missing
+        file.set_len(len - 1).unwrap();
+        let loaded = persistence.load().unwrap().unwrap();
+        assert_eq!(loaded.items.len(), 2);
+
+        // nothing but a partial state
+        file.set_len(3).unwrap();
+        assert!(persistence.load().unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flush_replaces_file() {
+        let path = std::env::temp_dir().join(format!("{}-replaced.state", std::process::id()));
+        let mut persistence = Persistence::new(&path, PersistencePolicy::OnEveryMerge);
+        let mut state = ServerState::new(0);
+        state.clock.inc(0);
+        state.items = ["first"].map(String::from).into();
+        persistence.changed();
+        persistence.flush(&mut state).unwrap();
+        assert!(!persistence.tmp_path().exists());
+
+        // a flush that crashed midway leaves the old state in place
+        std::fs::write(persistence.tmp_path(), "{\"partial").unwrap();
+        assert_eq!(persistence.load().unwrap().unwrap(), state);
+
+        // and is overwritten by the next one
+        state.clock.inc(0);
+        state.items = ["second", "third"].map(String::from).into();
+        persistence.changed();
+        persistence.flush(&mut state).unwrap();
+        assert!(!persistence.tmp_path().exists());
+        assert_eq!(persistence.load().unwrap().unwrap(), state);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
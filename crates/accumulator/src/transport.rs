@@ -0,0 +1,173 @@
+//! How servers send datagrams, so that tests can inject network faults.
+
+use crate::send_datagram;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+pub type SendFuture<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+
+/// Sends the datagrams of a server, see `Server::set_transport`.
+pub trait Transport: Send + Sync {
+    /// Send `buf` from `socket` to `addr`.
+    fn send<'a>(
+        &'a self,
+        socket: &'a Arc<UdpSocket>,
+        buf: &'a [u8],
+        addr: SocketAddr,
+    ) -> SendFuture<'a>;
+}
+
+/// Sends datagrams as they are, the transport servers use by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UdpTransport;
+
+impl Transport for UdpTransport {
+    fn send<'a>(
+        &'a self,
+        socket: &'a Arc<UdpSocket>,
+        buf: &'a [u8],
+        addr: SocketAddr,
+    ) -> SendFuture<'a> {
+        Box::pin(send_datagram(socket, buf, addr))
+    }
+}
+
+/// Drops, duplicates and delays datagrams at random, for testing that the
+/// servers cope with an unreliable network. Delayed datagrams are sent
+/// from a background task after up to `max_delay`, so they may arrive out
+/// of order. The faults are drawn from a seeded generator, so a run with the
+/// same seed injects the same faults into the same sequence of sends.
+#[derive(Debug)]
+pub struct FaultyTransport {
+    drop: f64,
+    duplicate: f64,
+    delay: f64,
+    max_delay: Duration,
+    rng: Mutex<StdRng>,
+}
+
+impl FaultyTransport {
+    /// A transport injecting no faults until configured otherwise.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            drop: 0.,
+            duplicate: 0.,
+            delay: 0.,
+            max_delay: Duration::from_millis(10),
+            rng: Mutex::new(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    /// Drop datagrams with probability `p`.
+    ///
+    /// # Panics
+    ///
+    /// If `p` is not between 0 and 1.
+    pub fn drop_probability(mut self, p: f64) -> Self {
+        self.drop = probability("drop", p);
+        self
+    }
+
+    /// Send datagrams twice with probability `p`.
+    ///
+    /// # Panics
+    ///
+    /// If `p` is not between 0 and 1.
+    pub fn duplicate_probability(mut self, p: f64) -> Self {
+        self.duplicate = probability("duplicate", p);
+        self
+    }
+
+    /// Delay datagrams by up to `max` with probability `p`.
+    ///
+    /// # Panics
+    ///
+    /// If `p` is not between 0 and 1.
+    pub fn delay_probability(mut self, p: f64, max: Duration) -> Self {
+        self.delay = probability("delay", p);
+        self.max_delay = max;
+        self
+    }
+
+    /// Delays of the copies of a datagram to send, none if it is dropped.
+    fn faults(&self) -> Vec<Option<Duration>> {
+        let mut rng = self.rng.lock().unwrap();
+        if rng.gen_bool(self.drop) {
+            return Vec::new();
+        }
+        let copies = if rng.gen_bool(self.duplicate) { 2 } else { 1 };
+        (0..copies)
+            .map(|_| {
+                rng.gen_bool(self.delay)
+                    .then(|| self.max_delay.mul_f64(rng.gen()))
+            })
+            .collect()
+    }
+}
+
+/// Check a fault probability when it is configured, rather than have
+/// `gen_bool` panic on it at send time.
+fn probability(fault: &str, p: f64) -> f64 {
+    assert!(
+        (0.0..=1.0).contains(&p),
+        "{fault} probability {p} is not between 0 and 1"
+    );
+    p
+}
+
+impl Transport for FaultyTransport {
+    fn send<'a>(
+        &'a self,
+        socket: &'a Arc<UdpSocket>,
+        buf: &'a [u8],
+        addr: SocketAddr,
+    ) -> SendFuture<'a> {
+        let faults = self.faults();
+        Box::pin(async move {
+            for delay in faults {
+                match delay {
+                    Some(delay) => {
+                        let socket = socket.clone();
+                        let buf = buf.to_vec();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            // lost like any datagram if it fails
+                            let _ = send_datagram(&socket, &buf, addr).await;
+                        });
+                    }
+                    None => send_datagram(socket, buf, addr).await?,
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probabilities_are_validated() {
+        let max = Duration::from_millis(1);
+        for p in [0., 1.] {
+            FaultyTransport::new(0)
+                .drop_probability(p)
+                .duplicate_probability(p)
+                .delay_probability(p, max);
+        }
+        for p in [-0.1, 1.5, f64::NAN] {
+            let transport = || FaultyTransport::new(0);
+            assert!(std::panic::catch_unwind(|| transport().drop_probability(p)).is_err());
+            assert!(std::panic::catch_unwind(|| transport().duplicate_probability(p)).is_err());
+            assert!(std::panic::catch_unwind(|| transport().delay_probability(p, max)).is_err());
+        }
+    }
+}
@@ -3,8 +3,9 @@ use hex::FromHex;
 use rand::rngs::OsRng;
 use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
 use sha3::{Digest, Keccak256};
+use std::collections::{BTreeMap, BTreeSet};
 
-pub fn public_key_to_address(public_key_hex: &str) -> anyhow::Result<String, anyhow::Error>  {
+pub fn public_key_to_address(public_key_hex: &str) -> anyhow::Result<String, anyhow::Error> {
     let public_key_bytes = hex::decode(public_key_hex)?;
 
     let mut hasher = Keccak256::new();
@@ -72,7 +73,6 @@ pub fn verify_secp256k1_recovery_pk_bytes(
     signature_bytes: Vec<u8>,
     message_bytes: [u8; 32],
 ) -> anyhow::Result<secp256k1::PublicKey, anyhow::Error> {
-
     let secp = secp256k1::Secp256k1::new();
 
     let recovery_id = RecoveryId::from_i32(i32::from(signature_bytes[64]))?;
@@ -84,6 +84,115 @@ pub fn verify_secp256k1_recovery_pk_bytes(
     Ok(pub_key)
 }
 
+/// Serialize a recoverable signature as the 64 compact bytes followed by the
+/// recovery id, the layout `verify_secp256k1_recovery_pk_bytes` expects.
+pub fn recoverable_signature_bytes(signature: &RecoverableSignature) -> Vec<u8> {
+    let (recovery_id, compact) = signature.serialize_compact();
+    let mut bytes = compact.to_vec();
+    bytes.push(recovery_id.to_i32() as u8);
+    bytes
+}
+
+/// Signatures over one message hash by a known set of N signers, collected
+/// until M distinct signers are reached, for the threshold signatures of a
+/// clock.
+#[derive(Debug, Clone)]
+pub struct ThresholdSignatures {
+    message: [u8; 32],
+    signers: Vec<secp256k1::PublicKey>,
+    threshold: usize,
+    signatures: BTreeMap<usize, Vec<u8>>,
+}
+
+impl ThresholdSignatures {
+    /// Fails unless `threshold` is between 1 and the number of `signers`,
+    /// which must be distinct.
+    pub fn new(
+        message: [u8; 32],
+        signers: Vec<secp256k1::PublicKey>,
+        threshold: usize,
+    ) -> anyhow::Result<Self> {
+        check_threshold(&signers, threshold)?;
+        Ok(Self {
+            message,
+            signers,
+            threshold,
+            signatures: BTreeMap::new(),
+        })
+    }
+
+    /// Add a signature in the layout of `recoverable_signature_bytes`.
+    /// Returns true if it is valid and from a signer not collected yet, and
+    /// an error if it does not recover to any of the known signers.
+    pub fn add(&mut self, signature: Vec<u8>) -> anyhow::Result<bool> {
+        let signer = signer_index(&signature, self.message, &self.signers)?;
+        if self.signatures.contains_key(&signer) {
+            return Ok(false);
+        }
+        self.signatures.insert(signer, signature);
+        Ok(true)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.signatures.len() >= self.threshold
+    }
+
+    /// The collected signatures, ordered by signer.
+    pub fn signatures(&self) -> Vec<Vec<u8>> {
+        self.signatures.values().cloned().collect()
+    }
+}
+
+/// Check that `threshold` of `signers` can be reached, and is not reached
+/// without any signature.
+fn check_threshold(signers: &[secp256k1::PublicKey], threshold: usize) -> anyhow::Result<()> {
+    let distinct: BTreeSet<_> = signers.iter().collect();
+    anyhow::ensure!(distinct.len() == signers.len(), "signers are not distinct");
+    anyhow::ensure!(
+        (1..=signers.len()).contains(&threshold),
+        "threshold {threshold} is not between 1 and the {} signers",
+        signers.len()
+    );
+    Ok(())
+}
+
+/// Index in `signers` of the key `signature` over `message` recovers to.
+fn signer_index(
+    signature: &[u8],
+    message: [u8; 32],
+    signers: &[secp256k1::PublicKey],
+) -> anyhow::Result<usize> {
+    anyhow::ensure!(signature.len() == 65, "signature is not 65 bytes long");
+    let public_key = verify_secp256k1_recovery_pk_bytes(signature.to_vec(), message)?;
+    signers
+        .iter()
+        .position(|signer| *signer == public_key)
+        .ok_or_else(|| anyhow::anyhow!("signature is not from a known signer"))
+}
+
+/// Verify that at least `threshold` distinct `signers` signed `message`
+/// among `signatures`. Invalid signatures, signatures by unknown keys and
+/// repeated signers do not count. Fails like `ThresholdSignatures::new` for
+/// a threshold that is out of range or signers that are not distinct.
+pub fn verify_threshold(
+    signatures: &[Vec<u8>],
+    message: [u8; 32],
+    signers: &[secp256k1::PublicKey],
+    threshold: usize,
+) -> anyhow::Result<(), anyhow::Error> {
+    check_threshold(signers, threshold)?;
+    let valid: BTreeSet<usize> = signatures
+        .iter()
+        .filter_map(|signature| signer_index(signature, message, signers).ok())
+        .collect();
+    anyhow::ensure!(
+        valid.len() >= threshold,
+        "{} of {threshold} required signatures",
+        valid.len()
+    );
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::core::DigestHash;
@@ -118,10 +227,50 @@ mod tests {
             sig_hex.len(),
             msg_hex
         );
-        
+
         let ret = verify_secp256k1_recovery_pk(&sig_hex, &msg_hex);
         let recover_pubkey = recover_public_key(&secp, &signature_recover, &msg).unwrap();
         assert!(ret.is_ok());
         assert_eq!(recover_pubkey, public_key);
     }
+
+    #[test]
+    fn threshold_signatures() {
+        let secp = secp256k1::Secp256k1::new();
+        let keys: Vec<_> = (0..4).map(|_| secp.generate_keypair(&mut OsRng)).collect();
+        let signers: Vec<_> = keys.iter().map(|(_, public_key)| *public_key).collect();
+        let msg = "clock".sha256().to_fixed_bytes();
+        let sign = |secret_key| {
+            recoverable_signature_bytes(&sign_message_recover_pk(&secp, secret_key, &msg).unwrap())
+        };
+        let (outsider, _) = secp.generate_keypair(&mut OsRng);
+
+        let mut collected = ThresholdSignatures::new(msg, signers.clone(), 3).unwrap();
+        assert!(collected.add(sign(&keys[0].0)).unwrap());
+        assert!(!collected.add(sign(&keys[0].0)).unwrap());
+        assert!(collected.add(sign(&outsider)).is_err());
+        assert!(collected.add(vec![0; 3]).is_err());
+        assert!(collected.add(sign(&keys[1].0)).unwrap());
+        assert!(!collected.is_complete());
+        // M - 1 distinct signers, padded with a repeat and an outsider
+        let mut signatures = collected.signatures();
+        signatures.extend([sign(&keys[1].0), sign(&outsider)]);
+        assert!(verify_threshold(&signatures, msg, &signers, 3).is_err());
+
+        assert!(collected.add(sign(&keys[3].0)).unwrap());
+        assert!(collected.is_complete());
+        assert!(verify_threshold(&collected.signatures(), msg, &signers, 3).is_ok());
+        let other = "other clock".sha256().to_fixed_bytes();
+        assert!(verify_threshold(&collected.signatures(), other, &signers, 3).is_err());
+
+        // thresholds that are met by nothing or by no one, and repeated
+        // signers, are refused
+        for threshold in [0, 5] {
+            assert!(ThresholdSignatures::new(msg, signers.clone(), threshold).is_err());
+            assert!(verify_threshold(&collected.signatures(), msg, &signers, threshold).is_err());
+        }
+        let repeated = [&signers[..], &signers[..1]].concat();
+        assert!(ThresholdSignatures::new(msg, repeated.clone(), 3).is_err());
+        assert!(verify_threshold(&collected.signatures(), msg, &repeated, 3).is_err());
+    }
 }
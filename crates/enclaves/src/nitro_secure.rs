@@ -1,6 +1,10 @@
+use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{future::Future, pin::Pin};
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Semaphore;
 use tracing::warn;
 
 /// HandleCallbackFn is running handler behind in vsock.
@@ -16,6 +20,110 @@ pub type HandleFn = Arc<
         + Sync,
 >;
 
+/// Leading bytes of the handshake both ends of a vsock connection send before
+/// any frame.
+pub const FRAMING_MAGIC: [u8; 4] = *b"VLCF";
+
+/// Version of the framing that follows the handshake. Version 1 frames are a
+/// little-endian `u64` length followed by that many bytes of payload.
+pub const FRAMING_VERSION: u8 = 1;
+
+/// Time a peer has to complete the handshake before its connection is
+/// dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send our handshake and validate the peer's, refusing a peer that does not
+/// speak this framing version.
+pub async fn handshake<R, W>(read_half: &mut R, write_half: &mut W) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    write_half.write_all(&FRAMING_MAGIC).await?;
+    write_half.write_u8(FRAMING_VERSION).await?;
+    write_half.flush().await?;
+
+    let mut magic = [0; FRAMING_MAGIC.len()];
+    read_half.read_exact(&mut magic).await?;
+    anyhow::ensure!(
+        magic == FRAMING_MAGIC,
+        "peer is not a clock endpoint: unexpected handshake magic {magic:02x?}"
+    );
+    let version = read_half.read_u8().await?;
+    anyhow::ensure!(
+        version == FRAMING_VERSION,
+        "peer speaks framing version {version}, expected {FRAMING_VERSION}"
+    );
+    Ok(())
+}
+
+/// `handshake`, failing if the peer does not complete it within `timeout`,
+/// so that a silent peer cannot hold a connection open forever.
+pub async fn handshake_within<R, W>(
+    read_half: &mut R,
+    write_half: &mut W,
+    timeout: Duration,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    tokio::time::timeout(timeout, handshake(read_half, write_half))
+        .await
+        .map_err(|_| anyhow::anyhow!("peer sent no handshake within {timeout:?}"))?
+}
+
+/// Runs the handlers of one connection concurrently, at most `concurrency`
+/// at a time, while forwarding their replies in the order the requests were
+/// dispatched.
+pub struct OrderedDispatcher {
+    permits: Arc<Semaphore>,
+    order: UnboundedSender<UnboundedReceiver<Vec<u8>>>,
+}
+
+impl OrderedDispatcher {
+    /// A dispatcher forwarding replies to `write_sender`.
+    ///
+    /// # Panics
+    ///
+    /// If `concurrency` exceeds `Semaphore::MAX_PERMITS`.
+    pub fn new(concurrency: NonZeroUsize, write_sender: UnboundedSender<Vec<u8>>) -> Self {
+        let (order, mut order_receiver) = unbounded_channel::<UnboundedReceiver<Vec<u8>>>();
+        tokio::spawn(async move {
+            while let Some(mut replies) = order_receiver.recv().await {
+                while let Some(buf) = replies.recv().await {
+                    if write_sender.send(buf).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency.get())),
+            order,
+        }
+    }
+
+    /// Spawn `handle` with its own reply sender, waiting first until less
+    /// than `concurrency` handlers are running.
+    pub async fn dispatch<F, Fut>(&self, handle: F)
+    where
+        F: FnOnce(UnboundedSender<Vec<u8>>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let permit = self.permits.clone().acquire_owned().await.unwrap();
+        let (reply_sender, replies) = unbounded_channel();
+        let _ = self.order.send(replies);
+        let task = handle(reply_sender);
+        tokio::spawn(async move {
+            if let Err(err) = task.await {
+                eprintln!("Error: {:?}", err);
+            }
+            drop(permit)
+        });
+    }
+}
+
 #[derive(Debug)]
 pub struct NitroSecureModule(pub i32);
 
@@ -61,17 +169,18 @@ impl NitroSecureModule {
         }
     }
 
-    pub async fn run(port: u32, handler: HandleFn) -> anyhow::Result<()> {
+    /// Serve updates on vsock `port`. Each connection runs up to
+    /// `concurrency` handlers in parallel, and replies in request order.
+    pub async fn run(
+        port: u32,
+        concurrency: NonZeroUsize,
+        handler: HandleFn,
+    ) -> anyhow::Result<()> {
         use std::os::fd::AsRawFd;
 
         use nix::sys::socket::{
             bind, listen, socket, AddressFamily, Backlog, SockFlag, SockType, VsockAddr,
         };
-        use tokio::{
-            io::{AsyncReadExt as _, AsyncWriteExt as _},
-            sync::mpsc::unbounded_channel,
-        };
-
         let nsm = std::sync::Arc::new(Self::new()?);
         let pcrs = Arc::new([
             nsm.describe_pcr(0)?,
@@ -95,56 +204,65 @@ impl NitroSecureModule {
 
         loop {
             let (stream, _) = socket.accept().await?;
-            let (mut read_half, mut write_half) = stream.into_split();
-            let (write_sender, mut write_receiver) = unbounded_channel::<Vec<_>>();
-
-            let mut write_session = tokio::spawn(async move {
-                while let Some(buf) = write_receiver.recv().await {
-                    write_half.write_u64_le(buf.len() as _).await?;
-                    write_half.write_all(&buf).await?;
-                }
-                anyhow::Ok(())
-            });
             let nsm = nsm.clone();
             let pcrs = pcrs.clone();
-            let handler = handler.clone(); 
-            let mut read_session = tokio::spawn(async move {
+            let handler = handler.clone();
+            // served by its own task, so that a peer that is slow to
+            // handshake does not hold up accepting the others
+            tokio::spawn(async move {
+                let (mut read_half, mut write_half) = stream.into_split();
+                if let Err(err) =
+                    handshake_within(&mut read_half, &mut write_half, HANDSHAKE_TIMEOUT).await
+                {
+                    warn!("refusing connection: {err}");
+                    return;
+                }
+                let (write_sender, mut write_receiver) = unbounded_channel::<Vec<_>>();
+
+                let mut write_session = tokio::spawn(async move {
+                    while let Some(buf) = write_receiver.recv().await {
+                        write_half.write_u64_le(buf.len() as _).await?;
+                        write_half.write_all(&buf).await?;
+                    }
+                    anyhow::Ok(())
+                });
+                let mut read_session = tokio::spawn(async move {
+                    let dispatcher = OrderedDispatcher::new(concurrency, write_sender);
+                    loop {
+                        let task = async {
+                            let len = read_half.read_u64_le().await?;
+                            let mut buf = vec![0; len as _];
+                            read_half.read_exact(&mut buf).await?;
+                            anyhow::Ok(buf)
+                        };
+                        let buf = match task.await {
+                            Ok(buf) => buf,
+                            Err(err) => {
+                                warn!("{err}");
+                                return anyhow::Ok(());
+                            }
+                        };
+                        let nsm_clone = nsm.clone();
+                        let pcrs_clone = pcrs.clone();
+                        dispatcher
+                            .dispatch(|write_sender| {
+                                handler(buf, nsm_clone, pcrs_clone, write_sender)
+                            })
+                            .await;
+                    }
+                });
+                // this loop keeps one connect, and still works when meets some error in only connect.
                 loop {
-                    let task = async {
-                        let len = read_half.read_u64_le().await?;
-                        let mut buf = vec![0; len as _];
-                        read_half.read_exact(&mut buf).await?;
-                        anyhow::Ok(buf)
-                    };
-                    let buf = match task.await {
-                        Ok(buf) => buf,
-                        Err(err) => {
-                            warn!("{err}");
-                            return anyhow::Ok(());
-                        }
+                    let result = tokio::select! {
+                        result = &mut read_session, if !read_session.is_finished() => result,
+                        result = &mut write_session, if !write_session.is_finished() => result,
+                        else => break,
                     };
-                    let nsm_clone = nsm.clone();
-                    let pcrs_clone = pcrs.clone();
-                    let write_sender = write_sender.clone();
-                    let handler = handler.clone();
-                    tokio::spawn(async move {
-                        if let Err(err) = handler(buf, nsm_clone, pcrs_clone, write_sender).await {
-                            eprintln!("Error: {:?}", err);
-                        }
-                    });
+                    if let Err(err) = result.map_err(Into::into).and_then(std::convert::identity) {
+                        warn!("{err}")
+                    }
                 }
             });
-            // this loop keeps one connect, and still works when meets some error in only connect.
-            loop {
-                let result = tokio::select! {
-                    result = &mut read_session, if !read_session.is_finished() => result,
-                    result = &mut write_session, if !write_session.is_finished() => result,
-                    else => break,
-                };
-                if let Err(err) = result.map_err(Into::into).and_then(std::convert::identity) {
-                    warn!("{err}")
-                }
-            }
         }
     }
 }
@@ -155,3 +273,81 @@ impl Drop for NitroSecureModule {
         aws_nitro_enclaves_nsm_api::driver::nsm_exit(self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    async fn dispatch_all(n: u64, concurrency: usize) -> (Vec<u64>, Duration) {
+        let (write_sender, mut write_receiver) = unbounded_channel();
+        let concurrency = NonZeroUsize::new(concurrency).unwrap();
+        let dispatcher = OrderedDispatcher::new(concurrency, write_sender);
+        let start = Instant::now();
+        for i in 0..n {
+            dispatcher
+                .dispatch(|reply| async move {
+                    // later requests finish first
+                    tokio::time::sleep(Duration::from_millis(10 * (n - i))).await;
+                    reply.send(i.to_le_bytes().to_vec())?;
+                    Ok(())
+                })
+                .await;
+        }
+        drop(dispatcher);
+        let mut replies = Vec::new();
+        while let Some(buf) = write_receiver.recv().await {
+            replies.push(u64::from_le_bytes(buf.try_into().unwrap()));
+        }
+        (replies, start.elapsed())
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_mismatched_peer() -> anyhow::Result<()> {
+        // a well-behaved peer
+        let (local, remote) = tokio::io::duplex(64);
+        let peer = tokio::spawn(async move {
+            let (mut read_half, mut write_half) = tokio::io::split(remote);
+            handshake(&mut read_half, &mut write_half).await
+        });
+        let (mut read_half, mut write_half) = tokio::io::split(local);
+        handshake(&mut read_half, &mut write_half).await?;
+        peer.await??;
+
+        for bad in [*b"XXXX\x01", *b"VLCF\x02"] {
+            let (local, mut remote) = tokio::io::duplex(64);
+            remote.write_all(&bad).await?;
+            let (mut read_half, mut write_half) = tokio::io::split(local);
+            let err = handshake(&mut read_half, &mut write_half)
+                .await
+                .unwrap_err();
+            assert!(err.to_string().starts_with("peer "), "{err}");
+        }
+
+        // a peer that never sends its handshake
+        let (local, _remote) = tokio::io::duplex(64);
+        let (mut read_half, mut write_half) = tokio::io::split(local);
+        let start = Instant::now();
+        let err = handshake_within(&mut read_half, &mut write_half, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("peer sent no handshake"),
+            "{err}"
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_dispatch_keeps_reply_order() {
+        // serial processing takes 10 + 20 + ... + 80 = 360ms
+        let (replies, elapsed) = dispatch_all(8, 8).await;
+        assert_eq!(replies, (0..8).collect::<Vec<_>>());
+        assert!(elapsed < Duration::from_millis(200), "{elapsed:?}");
+
+        let (replies, elapsed) = dispatch_all(8, 1).await;
+        assert_eq!(replies, (0..8).collect::<Vec<_>>());
+        assert!(elapsed >= Duration::from_millis(360), "{elapsed:?}");
+    }
+}
@@ -1,2 +1,3 @@
+pub mod configuration;
 pub mod raw_wrapper;
-pub mod configuration;
\ No newline at end of file
+pub mod retry;
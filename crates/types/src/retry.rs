@@ -0,0 +1,184 @@
+//! Retry timing shared by the transports: resends, reconnects and retried
+//! queries all back off by a `RetryPolicy`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// Exponential backoff with jitter. The n-th retry (from zero) waits
+/// `base_delay * 2^n`, capped at `max_delay`, shortened by up to a `jitter`
+/// fraction at random, and at most `max_attempts` attempts are made in total,
+/// the first one included.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RetryPolicyRepr")]
+pub struct RetryPolicy {
+    base_delay: Duration,
+    max_delay: Duration,
+    max_attempts: usize,
+    /// Fraction of each delay, between 0 and 1, that is randomized.
+    jitter: f64,
+}
+
+/// Why `RetryPolicy::new` rejected a policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RetryPolicyError {
+    /// The first delay is longer than the cap on all delays.
+    BaseDelayAboveMax {
+        base_delay: Duration,
+        max_delay: Duration,
+    },
+    /// The jitter is not a fraction between 0 and 1.
+    JitterOutOfRange(f64),
+}
+
+impl fmt::Display for RetryPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BaseDelayAboveMax {
+                base_delay,
+                max_delay,
+            } => write!(
+                f,
+                "base delay {base_delay:?} exceeds max delay {max_delay:?}"
+            ),
+            Self::JitterOutOfRange(jitter) => {
+                write!(f, "jitter {jitter} is not between 0 and 1")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RetryPolicyError {}
+
+/// Wire form of `RetryPolicy`, validated on deserialization.
+#[derive(Deserialize)]
+struct RetryPolicyRepr {
+    base_delay: Duration,
+    max_delay: Duration,
+    max_attempts: usize,
+    jitter: f64,
+}
+
+impl TryFrom<RetryPolicyRepr> for RetryPolicy {
+    type Error = RetryPolicyError;
+
+    fn try_from(repr: RetryPolicyRepr) -> Result<Self, RetryPolicyError> {
+        Self::new(
+            repr.base_delay,
+            repr.max_delay,
+            repr.max_attempts,
+            repr.jitter,
+        )
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            max_attempts: 5,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy, rejected if `base_delay` exceeds `max_delay` or `jitter` is
+    /// not between 0 and 1.
+    pub const fn new(
+        base_delay: Duration,
+        max_delay: Duration,
+        max_attempts: usize,
+        jitter: f64,
+    ) -> Result<Self, RetryPolicyError> {
+        if base_delay.as_nanos() > max_delay.as_nanos() {
+            return Err(RetryPolicyError::BaseDelayAboveMax {
+                base_delay,
+                max_delay,
+            });
+        }
+        // also rejects NaN
+        if !(jitter >= 0. && jitter <= 1.) {
+            return Err(RetryPolicyError::JitterOutOfRange(jitter));
+        }
+        Ok(Self {
+            base_delay,
+            max_delay,
+            max_attempts,
+            jitter,
+        })
+    }
+
+    /// The delay before retry `retry`, before jitter.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let factor = 1u32.checked_shl(retry as u32).unwrap_or(u32::MAX);
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+
+    /// The delay before retry `retry` given a uniform random `sample` in
+    /// `[0, 1]`, between `1 - jitter` and all of `backoff(retry)`, or `None`
+    /// once `max_attempts` are used up.
+    pub fn delay(&self, retry: usize, sample: f64) -> Option<Duration> {
+        if retry + 1 >= self.max_attempts {
+            return None;
+        }
+        let jitter = self.jitter * sample.clamp(0., 1.);
+        let backoff = self.backoff(retry);
+        // rounding may overflow near `Duration::MAX`
+        Some(Duration::try_from_secs_f64(backoff.as_secs_f64() * (1. - jitter)).unwrap_or(backoff))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_sequence() {
+        let policy =
+            RetryPolicy::new(Duration::from_millis(10), Duration::from_millis(50), 5, 0.5).unwrap();
+        let delays: Vec<_> = (0..).map_while(|retry| policy.delay(retry, 0.)).collect();
+        assert_eq!(delays, [10, 20, 40, 50].map(Duration::from_millis));
+        for retry in 0..4 {
+            let longest = policy.backoff(retry);
+            assert_eq!(policy.delay(retry, 1.), Some(longest / 2));
+            let delay = policy.delay(retry, 0.3).unwrap();
+            assert!(longest / 2 <= delay && delay <= longest);
+        }
+        assert_eq!(policy.delay(4, 0.), None);
+        assert_eq!(policy.backoff(200), policy.max_delay);
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        let ms = Duration::from_millis;
+        assert_eq!(
+            RetryPolicy::new(ms(20), ms(10), 3, 0.5),
+            Err(RetryPolicyError::BaseDelayAboveMax {
+                base_delay: ms(20),
+                max_delay: ms(10),
+            })
+        );
+        for jitter in [-0.1, 1.5, f64::NAN] {
+            assert!(matches!(
+                RetryPolicy::new(ms(10), ms(20), 3, jitter),
+                Err(RetryPolicyError::JitterOutOfRange(_))
+            ));
+        }
+
+        let json = serde_json::to_value(RetryPolicy::default()).unwrap();
+        assert_eq!(
+            serde_json::from_value::<RetryPolicy>(json.clone()).unwrap(),
+            RetryPolicy::default()
+        );
+        for (field, value) in [
+            ("jitter", serde_json::json!(2.0)),
+            ("base_delay", serde_json::json!({"secs": 60, "nanos": 0})),
+        ] {
+            let mut invalid = json.clone();
+            invalid[field] = value;
+            assert!(serde_json::from_value::<RetryPolicy>(invalid).is_err());
+        }
+    }
+}
@@ -0,0 +1,191 @@
+//! Throughput benchmarks for the ordinary clock.
+//!
+//! These are the library counterparts of the ignored stress tests, returning
+//! structured results instead of printing them, so clock performance can be
+//! measured programmatically on the target hardware.
+
+use crate::ordinary_clock::OrdinaryClock;
+use futures::future::join_all;
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::runtime::Builder;
+
+/// Throughput measured for one clock size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    /// Number of components in the updated clock.
+    pub key_size: usize,
+    /// Number of clocks merged on every update.
+    pub merged: usize,
+    /// Updates per second, summed over all workers.
+    pub tps: f64,
+}
+
+/// Time a throughput loop is measured against.
+pub trait TimeSource: Send + Sync {
+    /// Time elapsed since the start of the measurement.
+    fn elapsed(&self) -> Duration;
+}
+
+/// Wall clock time since the instant.
+impl TimeSource for Instant {
+    fn elapsed(&self) -> Duration {
+        Instant::elapsed(self)
+    }
+}
+
+/// Time source for deterministic runs, advancing by a fixed step every time
+/// it is read.
+#[derive(Debug, Default)]
+pub struct FakeClock {
+    step: Duration,
+    reads: AtomicU32,
+}
+
+impl FakeClock {
+    pub fn new(step: Duration) -> Self {
+        Self {
+            step,
+            reads: AtomicU32::new(0),
+        }
+    }
+}
+
+impl TimeSource for FakeClock {
+    fn elapsed(&self) -> Duration {
+        self.step * self.reads.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// When an update loop stops: once `duration` elapsed, or after
+/// `iterations` updates, whichever comes first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunLimit {
+    pub duration: Option<Duration>,
+    pub iterations: Option<usize>,
+}
+
+impl RunLimit {
+    /// Run for `duration` of wall clock time, for real benchmarking.
+    pub fn timed(duration: Duration) -> Self {
+        Self {
+            duration: Some(duration),
+            iterations: None,
+        }
+    }
+
+    /// Run exactly `iterations` updates.
+    pub fn iterations(iterations: usize) -> Self {
+        Self {
+            duration: None,
+            iterations: Some(iterations),
+        }
+    }
+}
+
+/// Keep updating `clock` by merging `merged` into it until `limit` is
+/// reached, measured against `time`. Returns the final clock and the number
+/// of updates.
+pub fn update_loop(
+    clock: OrdinaryClock,
+    merged: &[OrdinaryClock],
+    limit: RunLimit,
+    time: &dyn TimeSource,
+) -> (OrdinaryClock, usize) {
+    let mut current_clock = clock;
+    let mut count = 0;
+    while limit.iterations.is_none_or(|n| count < n)
+        && limit.duration.is_none_or(|d| time.elapsed() < d)
+    {
+        current_clock = current_clock.update(merged.iter(), 0);
+        count += 1;
+    }
+    (current_clock, count)
+}
+
+/// Measure raw `OrdinaryClock::update` throughput, once for every size in
+/// `sizes`. `cores` worker threads each keep updating their own clock for
+/// `duration`, and the total number of updates is reported per second.
+///
+/// This builds and drives its own multi-threaded runtime, so it must not be
+/// called from within an async context.
+pub fn raw_update_throughput(
+    sizes: &[usize],
+    cores: usize,
+    duration: Duration,
+) -> std::io::Result<Vec<BenchResult>> {
+    let cores = cores.max(1);
+    let rt = Builder::new_multi_thread().worker_threads(cores).build()?;
+
+    let results = sizes
+        .iter()
+        .map(|&size| {
+            let count = Arc::new(AtomicUsize::new(0));
+            let tasks = (0..cores)
+                .map(|_| {
+                    let count = Arc::clone(&count);
+                    let clock = OrdinaryClock::with_components(0..size as _);
+                    let start_time = Instant::now();
+                    // different clocks in different threads
+                    rt.spawn(async move {
+                        let limit = RunLimit::timed(duration);
+                        let (clock, updates) = update_loop(clock, &[], limit, &start_time);
+                        count.fetch_add(updates, Ordering::Relaxed);
+                        clock
+                    })
+                })
+                .collect::<Vec<_>>();
+            rt.block_on(join_all(tasks));
+
+            BenchResult {
+                key_size: size,
+                merged: 0,
+                tps: count.load(Ordering::Relaxed) as f64 / duration.as_secs_f64(),
+            }
+        })
+        .collect();
+
+    rt.shutdown_background();
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ordinary_clock::Clock as _;
+
+    #[test]
+    fn result_per_size() -> anyhow::Result<()> {
+        let sizes = [1, 4, 16];
+        let results = raw_update_throughput(&sizes, 2, Duration::from_millis(20))?;
+        assert_eq!(results.len(), sizes.len());
+        for (result, size) in results.iter().zip(sizes) {
+            assert_eq!(result.key_size, size);
+            assert!(result.tps > 0.);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fixed_iterations() {
+        let clock = OrdinaryClock::with_components(0..4);
+        let time = FakeClock::new(Duration::from_secs(1));
+        let (updated, count) = update_loop(clock.clone(), &[], RunLimit::iterations(1000), &time);
+        assert_eq!(count, 1000);
+        assert_eq!(updated.get(&0), Some(&1000));
+        assert_eq!(updated.reduce(), clock.reduce() + 1000);
+
+        // a fake deadline stops after as many reads
+        let limit = RunLimit {
+            duration: Some(Duration::from_secs(10)),
+            iterations: Some(1000),
+        };
+        let (_, count) = update_loop(clock, &[], limit, &FakeClock::new(Duration::from_secs(1)));
+        assert_eq!(count, 10);
+    }
+}
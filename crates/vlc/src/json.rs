@@ -0,0 +1,94 @@
+//! JSON schema of `OrdinaryClock` for non-Rust consumers.
+//!
+//! The clock is represented as an array of `{"id": ..., "count": ...}`
+//! objects in ascending id order, with integer ids, e.g.
+//! `[{"id": 0, "count": 3}, {"id": 5, "count": 1}]`. Use the module with
+//! `#[serde(with = "vlc::json")]` on a clock field, or convert explicitly
+//! with `to_json_value` and `from_json_value`.
+
+use crate::ordinary_clock::{ClockKey, OrdinaryClock};
+use crate::VlcError;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "K: ClockKey", deny_unknown_fields)]
+struct Component<K> {
+    id: K,
+    count: u64,
+}
+
+pub fn serialize<K: ClockKey, S: Serializer>(
+    clock: &OrdinaryClock<K>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        clock
+            .to_sorted_pairs()
+            .into_iter()
+            .map(|(id, count)| Component { id, count }),
+    )
+}
+
+pub fn deserialize<'de, K: ClockKey, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<OrdinaryClock<K>, D::Error> {
+    let components = Vec::<Component<K>>::deserialize(deserializer)?;
+    Ok(OrdinaryClock(
+        components
+            .into_iter()
+            .map(|Component { id, count }| (id, count))
+            .collect(),
+    ))
+}
+
+pub fn to_json_value<K: ClockKey>(clock: &OrdinaryClock<K>) -> serde_json::Value {
+    serialize(clock, serde_json::value::Serializer).expect("Failed to serialize data")
+}
+
+pub fn from_json_value<K: ClockKey>(
+    value: serde_json::Value,
+) -> Result<OrdinaryClock<K>, VlcError> {
+    deserialize(value).map_err(|err| VlcError::Malformed(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn json_value_round_trip() -> anyhow::Result<()> {
+        let clock = OrdinaryClock(BTreeMap::from([(5, 1), (0, 3), (2, 0)]));
+        let value = to_json_value(&clock);
+        assert_eq!(
+            value,
+            json!([
+                {"id": 0, "count": 3},
+                {"id": 2, "count": 0},
+                {"id": 5, "count": 1},
+            ])
+        );
+        assert_eq!(from_json_value::<u64>(value)?, clock);
+        assert_eq!(from_json_value::<u64>(json!([]))?, OrdinaryClock::default());
+
+        #[derive(Serialize, Deserialize)]
+        struct Stamped {
+            #[serde(with = "crate::json")]
+            clock: OrdinaryClock,
+        }
+        let stamped = serde_json::to_string(&Stamped {
+            clock: clock.clone(),
+        })?;
+        assert_eq!(serde_json::from_str::<Stamped>(&stamped)?.clock, clock);
+
+        for malformed in [
+            json!({"0": 3}),
+            json!([{"id": "0", "count": 3}]),
+            json!([{"id": 0}]),
+        ] {
+            anyhow::ensure!(from_json_value::<u64>(malformed).is_err());
+        }
+        Ok(())
+    }
+}
@@ -2,35 +2,111 @@
 //!
 //! This crate implements a verifiable logical clock construct. The clock
 //! can be used in a peer-to-peer network to order events. Any node in the
-//! network can verify the correctness of the clock. And HashMap as its core 
+//! network can verify the correctness of the clock. And HashMap as its core
 //! data structure.
+pub mod bench;
+pub mod json;
 pub mod ordinary_clock;
+pub mod registry;
+pub mod sequencer;
+pub mod wide_clock;
+use ordinary_clock::OrdinaryClock;
 use serde::{Deserialize, Serialize};
 use std::cmp;
 use std::collections::HashMap;
 
+/// Errors of decoding and validating clocks.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VlcError {
+    /// The encoding carries a format version this build does not know.
+    #[error("unknown clock encoding version {0}")]
+    UnknownVersion(u8),
+    /// The encoded clock is truncated or otherwise not decodable.
+    #[error("malformed clock encoding: {0}")]
+    Malformed(String),
+    /// A clock component went backward from a previous clock.
+    #[error("clock component {id} went backward from {previous} to {current}")]
+    Regressed {
+        id: u64,
+        previous: u64,
+        current: u64,
+    },
+    /// No node name is registered for a clock component.
+    #[error("no node name registered for clock id {0}")]
+    UnknownNode(u64),
+    /// A clock hash is not 64 hex characters.
+    #[error("malformed clock hash {0:?}")]
+    MalformedHash(String),
+    /// A node name is listed more than once in a persisted registry.
+    #[error("node name {0:?} registered twice")]
+    DuplicateNode(String),
+    /// Incrementing a clock component would overflow it. The clock has to
+    /// be promoted to a `WideClock` to keep counting.
+    #[error("clock component {0} would overflow, promote the clock to a WideClock")]
+    Overflow(String),
+}
+
+/// How two clocks, and so the events they stamp, are causally related. Names
+/// the outcomes of `partial_cmp`, in particular `None` as `Concurrent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CausalRelation {
+    /// Happened before the other clock (`Less`).
+    Before,
+    /// Happened after the other clock (`Greater`).
+    After,
+    /// Same clock value (`Equal`).
+    Equal,
+    /// Neither happened before the other (`None`).
+    Concurrent,
+}
+
+impl From<Option<cmp::Ordering>> for CausalRelation {
+    fn from(ordering: Option<cmp::Ordering>) -> Self {
+        match ordering {
+            Some(cmp::Ordering::Less) => Self::Before,
+            Some(cmp::Ordering::Greater) => Self::After,
+            Some(cmp::Ordering::Equal) => Self::Equal,
+            None => Self::Concurrent,
+        }
+    }
+}
+
+/// Missing ids count as zero when clocks are ordered, so `{1: 0}` and `{}`
+/// compare `Equal`, even though the derived `PartialEq` tells them apart.
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub struct Clock {
+    #[serde(serialize_with = "serialize_sorted")]
     values: HashMap<u128, u128>,
 }
 
+/// Serialize the values of a clock ordered by id, so that equal clocks
+/// always encode to the same bytes.
+fn serialize_sorted<S: serde::Serializer>(
+    values: &HashMap<u128, u128>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(values.iter().collect::<std::collections::BTreeMap<_, _>>())
+}
+
 impl PartialOrd for Clock {
     fn partial_cmp(&self, other: &Clock) -> Option<cmp::Ordering> {
         let mut less = false;
         let mut greater = false;
 
-        for (id, value) in &self.values {
-            let other_value = other.values.get(id);
-            if other_value.is_none() || value > other_value.unwrap() {
-                greater = true;
-            } else if value < other_value.unwrap() {
-                less = true;
-            }
-        }
-
-        for (id, _) in &other.values {
-            if self.values.get(id).is_none() {
-                less = true;
+        // every id of either clock, the ones of both compared once
+        let ids = self.values.keys().chain(
+            other
+                .values
+                .keys()
+                .filter(|id| !self.values.contains_key(id)),
+        );
+        for id in ids {
+            let value = self.values.get(id).copied().unwrap_or(0);
+            let other_value = other.values.get(id).copied().unwrap_or(0);
+            match value.cmp(&other_value) {
+                cmp::Ordering::Greater => greater = true,
+                cmp::Ordering::Less => less = true,
+                cmp::Ordering::Equal => {}
             }
         }
 
@@ -60,6 +136,18 @@ impl Clock {
         *value += 1;
     }
 
+    /// Fast-forward the clock count of `id` to `to`, never moving it
+    /// backward. Returns true if the count changed.
+    pub fn advance(&mut self, id: u128, to: u128) -> bool {
+        let value = self.values.entry(id).or_insert(0);
+        if *value < to {
+            *value = to;
+            true
+        } else {
+            false
+        }
+    }
+
     /// Get the clock count by id
     pub fn get(&mut self, id: u128) -> u128 {
         let value = self.values.entry(id).or_insert(0);
@@ -71,6 +159,11 @@ impl Clock {
         self.values.clear();
     }
 
+    /// Causal relation of this clock to `other`.
+    pub fn relation(&self, other: &Clock) -> CausalRelation {
+        self.partial_cmp(other).into()
+    }
+
     /// Merge the clock with other clocks.
     pub fn merge(&mut self, others: &Vec<&Clock>) {
         for &clock in others {
@@ -81,13 +174,45 @@ impl Clock {
         }
     }
 
+    /// Merge the clock with other clocks in a single pass, returning
+    /// `Greater` if the clock advanced and `Equal` if it is unchanged, i.e.
+    /// how the merged clock compares to the clock before merging. Merging in
+    /// an id with a zero count is not an advance.
+    pub fn merge_cmp(&mut self, others: &[&Clock]) -> cmp::Ordering {
+        let mut ordering = cmp::Ordering::Equal;
+        for &clock in others {
+            for (id, value) in &clock.values {
+                match self.values.get_mut(id) {
+                    Some(v) if *v >= *value => {}
+                    Some(v) => {
+                        *v = *value;
+                        ordering = cmp::Ordering::Greater;
+                    }
+                    None => {
+                        self.values.insert(*id, *value);
+                        if *value > 0 {
+                            ordering = cmp::Ordering::Greater;
+                        }
+                    }
+                }
+            }
+        }
+        ordering
+    }
+
+    /// Merge a single other clock, e.g. one just received. Returns true if
+    /// the clock advanced, see `merge_cmp`.
+    pub fn apply(&mut self, other: &Clock) -> bool {
+        self.merge_cmp(&[other]) == cmp::Ordering::Greater
+    }
+
     /// Diff is local clock minus another clock
     pub fn diff(&self, other: &Clock) -> Clock {
         let mut ret = Clock::new();
         for (id, v1) in &self.values {
             let v2 = other.values.get(id).unwrap_or(&0);
             if v1 > v2 {
-                ret.values.insert(*id, v1-v2);
+                ret.values.insert(*id, v1 - v2);
             } else {
                 ret.values.insert(*id, 0);
             }
@@ -95,6 +220,19 @@ impl Clock {
         ret
     }
 
+    /// The components of this clock that changed since `baseline`, i.e. are
+    /// greater or missing in it. Merging the result into `baseline`
+    /// reconstructs this clock, if it did not move backward.
+    pub fn changed_since(&self, baseline: &Clock) -> Clock {
+        let values = self
+            .values
+            .iter()
+            .filter(|(id, value)| baseline.values.get(id).is_none_or(|v| *value > v))
+            .map(|(id, value)| (*id, *value))
+            .collect();
+        Clock { values }
+    }
+
     /// return index key of clock
     pub fn index_key(&self) -> String {
         let mut key: String = String::new();
@@ -123,15 +261,71 @@ impl Clock {
         let sum: u128 = self.values.values().sum();
         sum == 0
     }
-    
+
+    /// The components of the clock as `(id, count)` pairs, in no particular
+    /// order. Zero counts are included.
+    pub fn iter(&self) -> impl Iterator<Item = (u128, u128)> + '_ {
+        self.values.iter().map(|(id, value)| (*id, *value))
+    }
+
+    /// Number of components, zero counts included.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Build a clock from `(id, count)` pairs. An id given more than once keeps
+/// its highest count, as if the pairs were merged.
+impl FromIterator<(u128, u128)> for Clock {
+    fn from_iter<I: IntoIterator<Item = (u128, u128)>>(pairs: I) -> Self {
+        let mut clock = Clock::new();
+        for (id, value) in pairs {
+            clock.advance(id, value);
+        }
+        clock
+    }
+}
+
+/// Narrowing conversion: `OrdinaryClock` keys and counts are `u64`, so this
+/// fails if any id or count of the clock does not fit.
+impl TryFrom<&Clock> for OrdinaryClock {
+    type Error = anyhow::Error;
+
+    fn try_from(clock: &Clock) -> Result<Self, Self::Error> {
+        let mut values = std::collections::BTreeMap::new();
+        for (id, value) in &clock.values {
+            let id = u64::try_from(*id)
+                .map_err(|_| anyhow::anyhow!("clock id {id} does not fit in u64"))?;
+            let value = u64::try_from(*value).map_err(|_| {
+                anyhow::anyhow!("count {value} of clock id {id} does not fit in u64")
+            })?;
+            values.insert(id, value);
+        }
+        Ok(OrdinaryClock(values))
+    }
+}
+
+impl From<&OrdinaryClock> for Clock {
+    fn from(clock: &OrdinaryClock) -> Self {
+        Self {
+            values: clock
+                .iter()
+                .map(|(id, n)| (u128::from(*id), u128::from(*n)))
+                .collect(),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use bincode::Options;
-    use sha2::Sha256;
     use sha2::Digest;
+    use sha2::Sha256;
 
     #[test]
     fn clock_inc() {
@@ -141,6 +335,141 @@ mod tests {
         assert_eq!(c.values.get(&0), Some(&2));
     }
 
+    #[test]
+    fn clock_advance() {
+        let mut c = Clock::new();
+        c.inc(0);
+        c.inc(0);
+        assert!(!c.advance(0, 1));
+        assert!(!c.advance(0, 2));
+        assert_eq!(c.get(0), 2);
+        assert!(c.advance(0, 5));
+        assert_eq!(c.get(0), 5);
+        assert!(c.advance(1, 3));
+        assert_eq!(c.get(1), 3);
+    }
+
+    #[test]
+    fn clock_merge_cmp() {
+        let mut c1 = Clock::new();
+        c1.inc(0);
+        c1.inc(0);
+        let mut c2 = Clock::new();
+        c2.inc(0);
+        let mut c3 = Clock::new();
+        c3.inc(1);
+        let mut c4 = Clock::new();
+        c4.advance(2, 0);
+
+        for others in [vec![], vec![&c2], vec![&c2, &c3], vec![&c1], vec![&c4]] {
+            let mut merged = c1.clone();
+            merged.merge(&others);
+            let mut merged_cmp = c1.clone();
+            let ordering = merged_cmp.merge_cmp(&others);
+            assert_eq!(merged_cmp, merged);
+            assert_eq!(Some(ordering), merged.partial_cmp(&c1));
+        }
+    }
+
+    #[test]
+    fn clock_apply() {
+        let mut local = Clock::new();
+        local.inc(0);
+        local.inc(0);
+        let mut behind = Clock::new();
+        behind.inc(0);
+        let mut ahead = behind.clone();
+        ahead.inc(1);
+
+        assert!(!local.apply(&behind));
+        assert!(local.apply(&ahead));
+        assert_eq!(local.values, HashMap::from([(0, 2), (1, 1)]));
+        assert!(!local.apply(&ahead));
+    }
+
+    #[test]
+    fn clock_changed_since() {
+        let mut baseline = Clock::new();
+        baseline.inc(0);
+        baseline.inc(1);
+        let mut current = baseline.clone();
+        current.inc(1);
+        current.advance(2, 0);
+        current.advance(3, 5);
+
+        let delta = current.changed_since(&baseline);
+        assert_eq!(delta.values.len(), 3);
+        assert_eq!(delta.values.get(&0), None);
+        assert_eq!(delta.values.get(&1), Some(&2));
+        let mut reconstructed = baseline.clone();
+        reconstructed.merge(&vec![&delta]);
+        assert_eq!(reconstructed, current);
+        assert_eq!(current.changed_since(&current), Clock::new());
+    }
+
+    #[test]
+    fn clock_from_iter() {
+        let pairs = [(0, 3), (7, 0), (1 << 100, 1)];
+        let c: Clock = pairs.into_iter().collect();
+        assert_eq!(c.len(), 3);
+        assert!(!c.is_empty());
+        let mut components: Vec<_> = c.iter().collect();
+        components.sort();
+        assert_eq!(components, pairs);
+
+        let mut expected = Clock::new();
+        expected.advance(0, 3);
+        expected.advance(7, 0);
+        expected.advance(1 << 100, 1);
+        assert_eq!(c, expected);
+        assert_eq!(c.iter().collect::<Clock>(), c);
+
+        // repeated ids merge
+        let c: Clock = [(0, 3), (0, 1)].into_iter().collect();
+        assert_eq!(c.iter().collect::<Vec<_>>(), [(0, 3)]);
+        assert!(Clock::new().is_empty());
+    }
+
+    #[test]
+    fn ordinary_clock_conversion() {
+        let mut c = Clock::new();
+        c.inc(0);
+        c.advance(3, 7);
+        let ordinary = OrdinaryClock::try_from(&c).unwrap();
+        assert_eq!(
+            ordinary,
+            OrdinaryClock(std::collections::BTreeMap::from([(0, 1), (3, 7)]))
+        );
+        assert_eq!(Clock::from(&ordinary), c);
+
+        let mut c = Clock::new();
+        c.advance(1 << 64, 1);
+        assert!(OrdinaryClock::try_from(&c).is_err());
+        let mut c = Clock::new();
+        c.advance(0, 1 << 64);
+        assert!(OrdinaryClock::try_from(&c).is_err());
+    }
+
+    #[test]
+    fn clock_relation() {
+        let mut c1 = Clock::new();
+        c1.inc(0);
+        let mut c2 = c1.clone();
+        c2.inc(0);
+        let mut c3 = c1.clone();
+        c3.inc(1);
+
+        for (a, b, relation) in [
+            (&c1, &c2, CausalRelation::Before),
+            (&c2, &c1, CausalRelation::After),
+            (&c1, &c1, CausalRelation::Equal),
+            (&c2, &c3, CausalRelation::Concurrent),
+        ] {
+            assert_eq!(a.relation(b), relation);
+            assert_eq!(CausalRelation::from(a.partial_cmp(b)), relation);
+        }
+    }
+
     #[test]
     fn clock_cmp() {
         let mut c1 = Clock::new();
@@ -158,6 +487,46 @@ mod tests {
         assert_eq!(c3.partial_cmp(&c1), None);
     }
 
+    #[test]
+    fn clock_cmp_edge_cases() {
+        let clock = |values: &[(u128, u128)]| Clock {
+            values: values.iter().copied().collect(),
+        };
+        for (a, b, ordering) in [
+            // disjoint keys
+            (clock(&[(0, 1)]), clock(&[(1, 1)]), None),
+            (clock(&[(0, 1), (1, 1)]), clock(&[(2, 1), (3, 1)]), None),
+            // extra zero entries
+            (
+                clock(&[(0, 1), (1, 0)]),
+                clock(&[(0, 1)]),
+                Some(cmp::Ordering::Equal),
+            ),
+            (clock(&[(1, 0)]), clock(&[]), Some(cmp::Ordering::Equal)),
+            (
+                clock(&[(0, 1), (1, 0)]),
+                clock(&[(0, 1), (2, 0)]),
+                Some(cmp::Ordering::Equal),
+            ),
+            // strict superset
+            (
+                clock(&[(0, 1), (1, 1)]),
+                clock(&[(0, 1)]),
+                Some(cmp::Ordering::Greater),
+            ),
+            (clock(&[(0, 1)]), clock(&[]), Some(cmp::Ordering::Greater)),
+            // disjoint, but zero on one side
+            (
+                clock(&[(0, 1)]),
+                clock(&[(1, 0)]),
+                Some(cmp::Ordering::Greater),
+            ),
+        ] {
+            assert_eq!(a.partial_cmp(&b), ordering, "{a:?} vs {b:?}");
+            assert_eq!(b.partial_cmp(&a), ordering.map(cmp::Ordering::reverse));
+        }
+    }
+
     #[test]
     fn clock_merge() {
         let mut c1 = Clock::new();
@@ -179,7 +548,6 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn clock_serialize() {
         let mut c1 = Clock::new();
         c1.inc(0);
@@ -188,7 +556,7 @@ mod tests {
         c1.inc(2);
         c1.inc(3);
         let ser1 = bincode::options().serialize(&c1).unwrap();
-        
+
         let mut c2 = Clock::new();
         c2.inc(0);
         c2.inc(1);
@@ -196,15 +564,14 @@ mod tests {
         c2.inc(2);
         c2.inc(3);
         let ser2 = bincode::options().serialize(&c2).unwrap();
-        
+
         println!("{:?}, {:?}", c1, c2);
-        assert_eq!(c1, c2);    // ignore diff order, random
-        // not equal, no order
-        assert_ne!(ser1, ser2);
+        assert_eq!(c1, c2);
+        // equal, serialized in key order
+        assert_eq!(ser1, ser2);
     }
 
     #[test]
-    #[ignore]
     fn clock_sha256() {
         let mut c1 = Clock::new();
         c1.inc(0);
@@ -216,18 +583,18 @@ mod tests {
         let mut f_hasher_1 = Sha256::new();
         f_hasher_1.update(ser1.clone());
         let hash_1 = f_hasher_1.finalize();
-        
+
         let unser1 = bincode::options().deserialize::<Clock>(&ser1).unwrap();
-        assert_eq!(c1, unser1);  // ignore diff order
+        assert_eq!(c1, unser1);
 
-        // not equal
+        // equal, serialized in key order
         let ser2 = bincode::options().serialize(&unser1).unwrap();
-        assert_ne!(ser1, ser2);
+        assert_eq!(ser1, ser2);
 
-        // not equal
+        // equal
         let mut f_hasher_2 = Sha256::new();
         f_hasher_2.update(ser2);
         let hash_2 = f_hasher_2.finalize();
-        assert_ne!(hash_1, hash_2);
+        assert_eq!(hash_1, hash_2);
     }
 }
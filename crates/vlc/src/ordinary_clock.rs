@@ -1,9 +1,16 @@
 //! This clock use the BTreeMap as its core data structure.
 
+use crate::{CausalRelation, VlcError};
 use bincode::Options;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{cmp::Ordering, collections::BTreeMap};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fmt::Debug,
+    hash::Hash,
+    ops::Bound,
+};
 
 pub trait Clock: PartialOrd + Clone + Send + Sync + 'static {
     fn reduce(&self) -> LamportClock;
@@ -11,6 +18,14 @@ pub trait Clock: PartialOrd + Clone + Send + Sync + 'static {
 
 pub type LamportClock = u64;
 
+/// Format version written by `OrdinaryClock::encode_versioned`.
+const ENCODING_VERSION: u8 = 1;
+
+/// Leading byte of `OrdinaryClock::encode_packed`, telling the dense layout
+/// from the general one.
+const PACKED_DENSE: u8 = 0;
+const PACKED_SPARSE: u8 = 1;
+
 impl Clock for LamportClock {
     fn reduce(&self) -> LamportClock {
         *self
@@ -20,13 +35,120 @@ impl Clock for LamportClock {
 /// clock key_id
 pub type KeyId = u64;
 
+/// Types that can identify the components of an `OrdinaryClock`, e.g. a
+/// 128-bit node id or a short name. Serialization of the key must be
+/// deterministic, since clocks are hashed through it.
+pub trait ClockKey:
+    Ord + Hash + Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+}
+
+impl<K> ClockKey for K where
+    K: Ord + Hash + Clone + Debug + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+}
+
+/// How much an `update` moved a clock forward.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    /// Number of components increased by merging the other clocks.
+    pub advanced_by_merge: usize,
+    /// Number of components increased by the self-increment alone, i.e. 1
+    /// unless merging already advanced the incremented component.
+    pub advanced_by_increment: usize,
+    /// Largest increase of a single component, merge and increment included.
+    pub max_delta: u64,
+}
+
+/// Where an `update_bounded` split over several calls resumes: the index of
+/// the other clock being merged, and the last of its components merged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateCursor<K = KeyId> {
+    other: usize,
+    last: Option<K>,
+    complete: bool,
+}
+
+impl<K> Default for UpdateCursor<K> {
+    fn default() -> Self {
+        Self {
+            other: 0,
+            last: None,
+            complete: false,
+        }
+    }
+}
+
+impl<K> UpdateCursor<K> {
+    /// Whether the update reached its self-increment.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+}
+
+/// Zero components are treated as absent, e.g. by `is_genesis` and
+/// `partial_cmp`. The derived `PartialEq`, `Eq` and `Hash` are structural
+/// though, so `{1: 0}` and `{}` are not `==`; use `semantic_eq` to compare
+/// clocks by value.
+///
+/// Human readable formats such as JSON represent the clock explicitly as
+/// `{"components": [[id, count], ...]}`, while compact formats such as
+/// bincode encode the sequence of `to_sorted_pairs`. Components may come in any order; use
+/// `normalize` to also drop the zero ones after deserializing.
+///
+/// Components are keyed by `KeyId` unless another `ClockKey` is given, e.g.
+/// `OrdinaryClock<[u8; 16]>`.
 #[derive(
     Debug, Clone, PartialEq, Eq, Hash, Default, derive_more::Deref, Serialize, Deserialize,
 )]
-pub struct OrdinaryClock(pub BTreeMap<KeyId, u64>);
+#[serde(bound = "K: ClockKey")]
+pub struct OrdinaryClock<K = KeyId>(#[serde(with = "components")] pub BTreeMap<K, u64>);
+
+mod components {
+    use super::ClockKey;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(bound = "K: ClockKey")]
+    struct Components<K> {
+        components: Vec<(K, u64)>,
+    }
+
+    pub fn serialize<K: ClockKey, S: Serializer>(
+        map: &BTreeMap<K, u64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let components = super::sorted_pairs(map.iter());
+        if serializer.is_human_readable() {
+            Components { components }.serialize(serializer)
+        } else {
+            components.serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, K: ClockKey, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<K, u64>, D::Error> {
+        if deserializer.is_human_readable() {
+            let Components { components } = Components::deserialize(deserializer)?;
+            Ok(components.into_iter().collect())
+        } else {
+            Ok(Vec::<(K, u64)>::deserialize(deserializer)?
+                .into_iter()
+                .collect())
+        }
+    }
+}
+
+fn sorted_pairs<'a, K: Clone + 'a>(
+    components: impl Iterator<Item = (&'a K, &'a u64)>,
+) -> Vec<(K, u64)> {
+    components.map(|(id, n)| (id.clone(), *n)).collect()
+}
 
-impl AsRef<OrdinaryClock> for OrdinaryClock {
-    fn as_ref(&self) -> &OrdinaryClock {
+impl<K> AsRef<OrdinaryClock<K>> for OrdinaryClock<K> {
+    fn as_ref(&self) -> &OrdinaryClock<K> {
         self
     }
 }
@@ -36,11 +158,143 @@ impl OrdinaryClock {
         Self::default()
     }
 
+    /// Check that no component of this clock is behind `previous`, naming
+    /// the first one that is.
+    pub fn ensure_ge(&self, previous: &Self) -> Result<(), VlcError> {
+        match self.regressed_from(previous) {
+            Some((&id, previous, current)) => Err(VlcError::Regressed {
+                id,
+                previous,
+                current,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Compact encoding for clocks of a dense id range `base..base + n`, as
+    /// the stress tests build: just the base id and the counts in id order,
+    /// all as varints. Clocks with gaps between ids fall back to the general
+    /// encoding.
+    pub fn encode_packed(&self) -> Vec<u8> {
+        let dense = match (self.0.first_key_value(), self.0.last_key_value()) {
+            (Some((first, _)), Some((last, _))) => {
+                (last - first == self.0.len() as u64 - 1).then_some(*first)
+            }
+            _ => None,
+        };
+        let options = bincode::options();
+        let result = match dense {
+            Some(base) => {
+                let mut buf = vec![PACKED_DENSE];
+                let counts: Vec<u64> = self.0.values().copied().collect();
+                options
+                    .serialize_into(&mut buf, &(base, counts))
+                    .map(|()| buf)
+            }
+            None => {
+                let mut buf = vec![PACKED_SPARSE];
+                options.serialize_into(&mut buf, self).map(|()| buf)
+            }
+        };
+        result.expect("Failed to serialize data")
+    }
+
+    /// Inverse of `encode_packed`.
+    pub fn decode_packed(buf: &[u8]) -> Result<Self, VlcError> {
+        let malformed = |err: bincode::Error| VlcError::Malformed(err.to_string());
+        match buf.split_first() {
+            Some((&PACKED_DENSE, data)) => {
+                let (base, counts): (KeyId, Vec<u64>) =
+                    bincode::options().deserialize(data).map_err(malformed)?;
+                if (counts.len() as u64).saturating_sub(1) > KeyId::MAX - base {
+                    return Err(VlcError::Malformed("id range overflows".into()));
+                }
+                let ids = (0..counts.len() as u64).map(|i| base + i);
+                Ok(Self(ids.zip(counts).collect()))
+            }
+            Some((&PACKED_SPARSE, data)) => bincode::options().deserialize(data).map_err(malformed),
+            Some((tag, _)) => Err(VlcError::Malformed(format!("unknown packed layout {tag}"))),
+            None => Err(VlcError::Malformed("empty buffer".into())),
+        }
+    }
+
+    /// Partition the components into `shards` clocks by `key % shards`, e.g.
+    /// to store or process them separately. `join` is the inverse.
+    pub fn split(&self, shards: usize) -> Vec<Self> {
+        let shards = shards.max(1);
+        let mut split = vec![Self::new(); shards];
+        for (&key, &value) in &self.0 {
+            split[(key % shards as KeyId) as usize].0.insert(key, value);
+        }
+        split
+    }
+
+    /// Reassemble a clock from its `split` shards. A component present in
+    /// several shards keeps its largest count.
+    pub fn join(shards: &[Self]) -> Self {
+        let mut joined = Self::new();
+        for (&key, &value) in shards.iter().flat_map(|shard| &shard.0) {
+            let entry = joined.0.entry(key).or_insert(value);
+            *entry = (*entry).max(value);
+        }
+        joined
+    }
+}
+
+impl<K: ClockKey> OrdinaryClock<K> {
+    /// A genesis clock with a zero count for each of `ids`. Ids should be in
+    /// ascending order, which lets the map be bulk-built in linear time
+    /// instead of inserting entries one by one.
+    pub fn with_components(ids: impl Iterator<Item = K>) -> Self {
+        Self(ids.map(|id| (id, 0)).collect())
+    }
+
+    /// Add `entries`, replacing the count of components already present.
+    /// Entries should be sorted by id, so that they are bulk-built and then
+    /// appended in one pass.
+    pub fn extend_from_sorted(&mut self, entries: impl IntoIterator<Item = (K, u64)>) {
+        let mut other: BTreeMap<_, _> = entries.into_iter().collect();
+        self.0.append(&mut other);
+    }
+
     pub fn is_genesis(&self) -> bool {
         self.0.values().all(|n| *n == 0)
     }
 
+    /// The components in ascending key order. This is what the clock is
+    /// serialized and hashed as, so neither depends on how the map type
+    /// encodes itself.
+    pub fn to_sorted_pairs(&self) -> Vec<(K, u64)> {
+        sorted_pairs(self.0.iter())
+    }
+
+    /// Components with a non-zero count, in key order.
+    fn non_zero(&self) -> impl Iterator<Item = (&K, &u64)> {
+        self.0.iter().filter(|(_, n)| **n != 0)
+    }
+
+    /// Prune zero components, leaving the canonical form of the clock: two
+    /// clocks are `semantic_eq` exactly when their normalized forms are `==`,
+    /// so clocks received in different wire encodings should be normalized
+    /// before they are stored or hashed structurally. The genesis clock
+    /// normalizes to the empty map.
+    pub fn normalize(&mut self) {
+        self.0.retain(|_, n| *n != 0)
+    }
+
+    /// Compare clocks by value, ignoring zero components.
+    pub fn semantic_eq(&self, other: &Self) -> bool {
+        self.non_zero().eq(other.non_zero())
+    }
+
     fn merge(&self, other: &Self) -> Self {
+        #[cfg(feature = "trace-merges")]
+        {
+            let deltas = self.behind(other);
+            if !deltas.is_empty() {
+                tracing::debug!(?deltas, "merge advanced clock");
+            }
+        }
         let merged = self
             .0
             .keys()
@@ -51,37 +305,337 @@ impl OrdinaryClock {
                     (Some(n), None) | (None, Some(n)) => *n,
                     (None, None) => unreachable!(),
                 };
-                (*id, n)
+                (id.clone(), n)
             })
             .collect();
         Self(merged)
     }
 
-    pub fn update<'a>(&'a self, others: impl Iterator<Item = &'a Self>, id: u64) -> Self {
-        let mut updated = others.fold(self.clone(), |version, dep| version.merge(dep));
-        *updated.0.entry(id).or_default() += 1;
-        updated
+    /// Merge all of `others` into a copy of this clock. When there are
+    /// several, identical clocks are merged only once. Also returns the
+    /// number of merges performed.
+    fn merge_all<'a>(&self, others: impl Iterator<Item = &'a Self>) -> (Self, usize) {
+        let others: Vec<_> = others.collect();
+        let distinct = if others.len() > 1 {
+            let mut seen = HashSet::new();
+            others
+                .into_iter()
+                .filter(|other| seen.insert(*other))
+                .collect()
+        } else {
+            others
+        };
+        let merges = distinct.len();
+        let merged = distinct
+            .into_iter()
+            .fold(self.clone(), |version, dep| version.merge(dep));
+        (merged, merges)
+    }
+
+    /// Whether a component is within `PROMOTION_MARGIN` of overflowing, in
+    /// which case the clock should be promoted to a `WideClock` before it is
+    /// updated further. `try_update` fails with `VlcError::Overflow` once
+    /// the incremented component is at `u64::MAX`, and the other updates
+    /// panic.
+    pub fn should_promote(&self) -> bool {
+        self.0
+            .values()
+            .any(|n| *n > u64::MAX - crate::wide_clock::PROMOTION_MARGIN)
+    }
+
+    /// Merge `others` into a copy of this clock and increment the count of
+    /// `id`.
+    ///
+    /// # Panics
+    ///
+    /// If the count of `id` would overflow, see `try_update`.
+    pub fn update<'a>(&'a self, others: impl Iterator<Item = &'a Self>, id: K) -> Self {
+        self.try_update(others, id)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Same as `update`, but failing with `VlcError::Overflow` instead of
+    /// panicking if the count of `id` is already at `u64::MAX`.
+    pub fn try_update<'a>(
+        &'a self,
+        others: impl Iterator<Item = &'a Self>,
+        id: K,
+    ) -> Result<Self, VlcError> {
+        let others: Vec<_> = others.collect();
+        // merging genesis clocks, as on startup, only adds their zero components
+        let mut updated = if others.iter().all(|other| other.is_genesis()) {
+            let mut updated = self.clone();
+            for key in others.iter().flat_map(|other| other.0.keys()) {
+                updated.0.entry(key.clone()).or_default();
+            }
+            updated
+        } else {
+            self.merge_all(others.into_iter()).0
+        };
+        updated.increment(id)?;
+        #[cfg(feature = "debug-checks")]
+        if let Some((id, previous, current)) = updated.regressed_from(self) {
+            panic!("clock component {id:?} went backward from {previous} to {current}")
+        }
+        Ok(updated)
+    }
+
+    /// Increment the count of `id`, failing rather than wrapping around.
+    fn increment(&mut self, id: K) -> Result<(), VlcError> {
+        let n = self.0.entry(id.clone()).or_default();
+        *n = n
+            .checked_add(1)
+            .ok_or_else(|| VlcError::Overflow(format!("{id:?}")))?;
+        Ok(())
+    }
+
+    /// The first component of `previous` this clock is behind, with the
+    /// previous and the current count.
+    fn regressed_from<'a>(&self, previous: &'a Self) -> Option<(&'a K, u64, u64)> {
+        previous.0.iter().find_map(|(id, &n)| {
+            let current = self.0.get(id).copied().unwrap_or_default();
+            (current < n).then_some((id, n, current))
+        })
+    }
+
+    /// Same as `update`, but in place and merging at most `max_ops` (at
+    /// least one) components of `others` per call, for predictable latency
+    /// on large clocks. Each call resumes where `cursor` left off, so its
+    /// work is bounded by `max_ops` whatever the size of the clocks. Returns
+    /// whether the update is complete.
+    ///
+    /// Start with a default cursor. Until the update is complete this clock
+    /// is not a valid clock yet: call `update_bounded` on it again with the
+    /// same `others`, `id` and cursor until it returns true. The
+    /// self-increment happens in the completing call only, and later calls
+    /// with the completed cursor change nothing.
+    ///
+    /// # Panics
+    ///
+    /// If the count of `id` would overflow, see `try_update`.
+    pub fn update_bounded(
+        &mut self,
+        others: &[Self],
+        id: K,
+        max_ops: usize,
+        cursor: &mut UpdateCursor<K>,
+    ) -> bool {
+        if cursor.complete {
+            return true;
+        }
+        let mut ops = 0;
+        while let Some(other) = others.get(cursor.other) {
+            let start = match cursor.last.take() {
+                Some(last) => Bound::Excluded(last),
+                None => Bound::Unbounded,
+            };
+            for (key, &n) in other.0.range((start, Bound::Unbounded)) {
+                if ops == max_ops.max(1) {
+                    return false;
+                }
+                let own = self.0.entry(key.clone()).or_default();
+                *own = (*own).max(n);
+                cursor.last = Some(key.clone());
+                ops += 1;
+            }
+            cursor.other += 1;
+            cursor.last = None;
+        }
+        self.increment(id).unwrap_or_else(|err| panic!("{err}"));
+        cursor.complete = true;
+        true
+    }
+
+    /// Same as `update`, but consuming this clock and `others`, so that they
+    /// are merged in place instead of into fresh copies.
+    ///
+    /// # Panics
+    ///
+    /// If the count of `id` would overflow, see `try_update`.
+    pub fn update_owned(mut self, others: impl IntoIterator<Item = Self>, id: K) -> Self {
+        for other in others {
+            for (id, other_n) in other.0 {
+                let n = self.0.entry(id).or_default();
+                *n = (*n).max(other_n);
+            }
+        }
+        self.increment(id).unwrap_or_else(|err| panic!("{err}"));
+        self
+    }
+
+    /// Same as `update`, additionally reporting how much merging and the
+    /// self-increment advanced the clock.
+    ///
+    /// # Panics
+    ///
+    /// If the count of `id` would overflow, see `try_update`.
+    pub fn update_with_stats<'a>(
+        &'a self,
+        others: impl Iterator<Item = &'a Self>,
+        id: K,
+    ) -> (Self, MergeStats) {
+        let (mut updated, _) = self.merge_all(others);
+        let prev = |id: &K| self.0.get(id).copied().unwrap_or_default();
+        let mut stats = MergeStats {
+            advanced_by_merge: updated.0.iter().filter(|(id, n)| **n > prev(id)).count(),
+            ..Default::default()
+        };
+        if updated.0.get(&id).copied().unwrap_or_default() == prev(&id) {
+            stats.advanced_by_increment = 1;
+        }
+        updated.increment(id).unwrap_or_else(|err| panic!("{err}"));
+        stats.max_delta = updated
+            .0
+            .iter()
+            .map(|(id, n)| n - prev(id))
+            .max()
+            .unwrap_or_default();
+        (updated, stats)
+    }
+
+    /// Merge `others` into this clock in place, in a single pass. Returns
+    /// `Greater` if any component advanced and `Equal` otherwise, i.e. how
+    /// the merged clock compares to the clock before merging.
+    pub fn merge_cmp<'a>(&mut self, others: impl Iterator<Item = &'a Self>) -> Ordering {
+        let mut ordering = Ordering::Equal;
+        for other in others {
+            for (id, other_n) in &other.0 {
+                let n = self.0.entry(id.clone()).or_default();
+                if *n < *other_n {
+                    *n = *other_n;
+                    ordering = Ordering::Greater;
+                }
+            }
+        }
+        ordering
+    }
+
+    /// Fast-forward the count of `id` to `to`, never moving it backward.
+    /// Returns true if the count changed.
+    pub fn advance(&mut self, id: K, to: u64) -> bool {
+        let n = self.0.entry(id).or_default();
+        if *n < to {
+            *n = to;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The count of `id` before and after a merge that turned `prior` into
+    /// `result`, as merge logs record them. A component missing from a
+    /// clock, e.g. of a node new to `prior`, counts as zero.
+    pub fn merge_counts(prior: &Self, result: &Self, id: &K) -> (u64, u64) {
+        let count = |clock: &Self| clock.0.get(id).copied().unwrap_or_default();
+        (count(prior), count(result))
+    }
+
+    /// How many events this clock is behind `reference`, per component.
+    /// Only components where `reference` is strictly ahead are returned.
+    pub fn behind(&self, reference: &Self) -> BTreeMap<K, u64> {
+        reference
+            .0
+            .iter()
+            .filter_map(|(id, &n)| {
+                let own = self.0.get(id).copied().unwrap_or_default();
+                (n > own).then(|| (id.clone(), n - own))
+            })
+            .collect()
+    }
+
+    /// Every component where this clock and `other` differ, with the value
+    /// on each side, `None` where the component is absent, ordered by id.
+    /// Lists why two clocks expected to be the same hash differently; note
+    /// that a zero component and an absent one hash the same though.
+    pub fn explain_diff(&self, other: &Self) -> Vec<(K, Option<u64>, Option<u64>)> {
+        let ids: BTreeSet<&K> = self.0.keys().chain(other.0.keys()).collect();
+        ids.into_iter()
+            .filter_map(|id| {
+                let (own, theirs) = (self.0.get(id).copied(), other.0.get(id).copied());
+                (own != theirs).then(|| (id.clone(), own, theirs))
+            })
+            .collect()
+    }
+
+    /// Component ids present in both this clock and `other`.
+    pub fn shared_keys(&self, other: &Self) -> BTreeSet<K> {
+        self.0
+            .keys()
+            .filter(|id| other.0.contains_key(id))
+            .cloned()
+            .collect()
+    }
+
+    /// Component ids present only in this clock, and only in `other`.
+    pub fn exclusive_keys(&self, other: &Self) -> (BTreeSet<K>, BTreeSet<K>) {
+        let only_in = |clock: &Self, other: &Self| {
+            clock
+                .0
+                .keys()
+                .filter(|id| !other.0.contains_key(id))
+                .cloned()
+                .collect()
+        };
+        (only_in(self, other), only_in(other, self))
+    }
+
+    /// Merge `other`, received from node `owner_id`, trusting it only for the
+    /// owner's own component: that one advances if `other` is ahead, while
+    /// all other components keep this clock's values, so that a node cannot
+    /// advance another node's counter on its own.
+    pub fn merge_authoritative(&self, other: &Self, owner_id: K) -> Self {
+        let mut merged = self.clone();
+        if let Some(&n) = other.0.get(&owner_id) {
+            let own = merged.0.entry(owner_id).or_default();
+            *own = (*own).max(n);
+        }
+        merged
+    }
+
+    /// Merge `other` into a copy of this clock, also returning whether the
+    /// two are concurrent, i.e. versions of the same object that conflict and
+    /// may need to be resolved by the application rather than just joined.
+    pub fn merge_detect_conflict(&self, other: &Self) -> (Self, bool) {
+        (self.merge(other), self.partial_cmp(other).is_none())
     }
 
     pub fn base<'a>(others: impl Iterator<Item = &'a Self>) -> Self {
         let mut combined = BTreeMap::new();
 
         for clock in others {
-            for (&key, &value) in &clock.0 {
+            for (key, &value) in &clock.0 {
                 combined
-                    .entry(key)
+                    .entry(key.clone())
                     .and_modify(|e: &mut u64| *e = (*e).min(value))
                     .or_insert(value);
             }
         }
 
-        OrdinaryClock(combined)
+        Self(combined)
     }
 
+    /// `base` computed in parallel, for many wide clocks: the clocks are
+    /// split into `partitions` runs whose bases are computed on the rayon
+    /// thread pool, then combined. The result is identical to `base`.
+    #[cfg(feature = "parallel")]
+    pub fn par_base(clocks: &[Self], partitions: usize) -> Self {
+        use rayon::prelude::*;
+
+        let chunk_len = clocks.len().div_ceil(partitions.max(1)).max(1);
+        let partial: Vec<Self> = clocks
+            .par_chunks(chunk_len)
+            .map(|chunk| Self::base(chunk.iter()))
+            .collect();
+        Self::base(partial.iter())
+    }
+
+    /// Hash of the clock with zero components pruned, so clocks that are
+    /// `semantic_eq` hash identically. Components are hashed in key order,
+    /// so the hash is deterministic for any key type.
     pub fn calculate_sha256(&self) -> [u8; 32] {
         let mut hasher = Sha256::new();
         let data = bincode::options()
-            .serialize(&self.0)
+            .serialize(&sorted_pairs(self.non_zero()))
             .expect("Failed to serialize data");
         // Update the hasher with the JSON string
         hasher.update(data);
@@ -89,35 +643,175 @@ impl OrdinaryClock {
         // Calculate the hash & return bytes
         hasher.finalize().into()
     }
+
+    /// `calculate_sha256` as a `ClockHash`.
+    pub fn clock_hash(&self) -> ClockHash {
+        ClockHash(self.calculate_sha256())
+    }
+
+    /// `calculate_sha256` as the lowercase 64 character hex string clock
+    /// hashes are stored as.
+    pub fn clock_hash_hex(&self) -> String {
+        self.clock_hash().to_hex()
+    }
+
+    /// Serialize the clock behind a format version byte, so that stored
+    /// clocks survive future changes of the encoding.
+    pub fn encode_versioned(&self) -> Vec<u8> {
+        let mut buf = vec![ENCODING_VERSION];
+        bincode::options()
+            .serialize_into(&mut buf, self)
+            .expect("Failed to serialize data");
+        buf
+    }
+
+    /// Inverse of `encode_versioned`. Fails on an unknown version instead of
+    /// misinterpreting the bytes.
+    pub fn decode_versioned(buf: &[u8]) -> Result<Self, VlcError> {
+        let Some((&version, data)) = buf.split_first() else {
+            return Err(VlcError::Malformed("empty buffer".into()));
+        };
+        if version != ENCODING_VERSION {
+            return Err(VlcError::UnknownVersion(version));
+        }
+        bincode::options()
+            .deserialize(data)
+            .map_err(|err| VlcError::Malformed(err.to_string()))
+    }
+}
+
+/// Sum and number of the non-zero components of a clock. The sum is wide
+/// enough not to overflow.
+fn summary<K>(clock: &OrdinaryClock<K>) -> (u128, usize) {
+    clock
+        .0
+        .values()
+        .filter(|n| **n != 0)
+        .fold((0, 0), |(sum, len), n| (sum + *n as u128, len + 1))
 }
 
-impl PartialOrd for OrdinaryClock {
+impl<K: Ord> PartialOrd for OrdinaryClock<K> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        fn ge(clock: &OrdinaryClock, other_clock: &OrdinaryClock) -> bool {
-            for (other_id, other_n) in &other_clock.0 {
-                if *other_n == 0 {
-                    continue;
-                }
-                let Some(n) = clock.0.get(other_id) else {
-                    return false;
-                };
-                if n < other_n {
-                    return false;
-                }
+        cmp_summarized(self, summary(self), other, summary(other))
+    }
+}
+
+/// `partial_cmp` of two clocks given their `summary`, e.g. to compare many
+/// clocks against one while summarizing it only once.
+fn cmp_summarized<K: Ord>(
+    clock: &OrdinaryClock<K>,
+    (sum, len): (u128, usize),
+    other: &OrdinaryClock<K>,
+    (other_sum, other_len): (u128, usize),
+) -> Option<Ordering> {
+    fn ge<K: Ord>(clock: &OrdinaryClock<K>, other_clock: &OrdinaryClock<K>) -> bool {
+        for (other_id, other_n) in &other_clock.0 {
+            if *other_n == 0 {
+                continue;
+            }
+            let Some(n) = clock.0.get(other_id) else {
+                return false;
+            };
+            if n < other_n {
+                return false;
             }
-            true
-        }
-        match (ge(self, other), ge(other, self)) {
-            (true, true) => Some(Ordering::Equal),
-            (true, false) => Some(Ordering::Greater),
-            (false, true) => Some(Ordering::Less),
-            (false, false) => None,
         }
+        true
+    }
+    // cheap necessary conditions first: a clock can only be at least
+    // another one with at least its sum over at least as many components
+    let may_ge = sum >= other_sum && len >= other_len;
+    let may_le = sum <= other_sum && len <= other_len;
+    if may_ge && may_le {
+        // at least the other clock with the same sum means equal
+        return ge(clock, other).then_some(Ordering::Equal);
+    }
+    match (may_ge && ge(clock, other), may_le && ge(other, clock)) {
+        (true, true) => Some(Ordering::Equal),
+        (true, false) => Some(Ordering::Greater),
+        (false, true) => Some(Ordering::Less),
+        (false, false) => None,
     }
 }
 
-impl OrdinaryClock {
-    pub fn dep_cmp(&self, other: &Self, id: KeyId) -> Ordering {
+impl<K: ClockKey> OrdinaryClock<K> {
+    /// Causal relation of this clock to `other`.
+    pub fn relation(&self, other: &Self) -> CausalRelation {
+        self.partial_cmp(other).into()
+    }
+
+    /// The `relation` of each of `clocks` to `reference`, e.g. to pick the
+    /// clocks to send a peer during sync. Same as calling `relation` on each,
+    /// but `reference` is summarized for the sum prefilter only once.
+    pub fn classify_against(reference: &Self, clocks: &[Self]) -> Vec<CausalRelation> {
+        let reference_summary = summary(reference);
+        clocks
+            .iter()
+            .map(|clock| cmp_summarized(clock, summary(clock), reference, reference_summary).into())
+            .collect()
+    }
+
+    /// Cheap necessary condition for the two clocks to be ordered, i.e. not
+    /// concurrent, comparing only their sums and numbers of non-zero
+    /// components. False means `partial_cmp` returns `None`.
+    pub fn maybe_comparable(&self, other: &Self) -> bool {
+        let (sum, len) = summary(self);
+        let (other_sum, other_len) = summary(other);
+        (sum >= other_sum && len >= other_len) || (sum <= other_sum && len <= other_len)
+    }
+
+    /// `partial_cmp` leaving out the components in `ignore`, e.g. those of a
+    /// flapping or decommissioned node, to order clocks by the other nodes
+    /// only.
+    pub fn partial_cmp_ignoring(&self, other: &Self, ignore: &BTreeSet<K>) -> Option<Ordering> {
+        let kept = |clock: &Self| {
+            Self(
+                clock
+                    .0
+                    .iter()
+                    .filter(|(id, _)| !ignore.contains(id))
+                    .map(|(id, n)| (id.clone(), *n))
+                    .collect(),
+            )
+        };
+        kept(self).partial_cmp(&kept(other))
+    }
+
+    /// Returns true when `self <= other` and the two are not equal, i.e. `self`
+    /// carries no information that `other` does not already have.
+    pub fn is_dominated_by(&self, other: &Self) -> bool {
+        matches!(self.partial_cmp(other), Some(Ordering::Less))
+    }
+
+    /// Group the indices of `clocks` into causal layers: a clock's layer is
+    /// one past the highest layer of any clock that happened before it, so
+    /// the first layer holds the clocks with no predecessor in `clocks`.
+    /// Each layer is an antichain of concurrent or equal clocks, and
+    /// processing layers in order respects causality. Indices within a layer
+    /// are ascending. Takes O(n^2) comparisons in the worst case.
+    pub fn topo_partition(clocks: &[Self]) -> Vec<Vec<usize>> {
+        // a clock that happened before another has a smaller total count, so
+        // visiting by total sees all predecessors of a clock before it
+        let total = |clock: &Self| clock.0.values().map(|n| *n as u128).sum::<u128>();
+        let mut order: Vec<usize> = (0..clocks.len()).collect();
+        order.sort_by_key(|&i| total(&clocks[i]));
+        let mut layer = vec![0; clocks.len()];
+        for (pos, &i) in order.iter().enumerate() {
+            layer[i] = order[..pos]
+                .iter()
+                .filter(|&&j| clocks[j].is_dominated_by(&clocks[i]))
+                .map(|&j| layer[j] + 1)
+                .max()
+                .unwrap_or(0);
+        }
+        let mut layers = vec![Vec::new(); layer.iter().max().map_or(0, |n| n + 1)];
+        for (i, n) in layer.into_iter().enumerate() {
+            layers[n].push(i);
+        }
+        layers
+    }
+
+    pub fn dep_cmp(&self, other: &Self, id: K) -> Ordering {
         match (self.0.get(&id), other.0.get(&id)) {
             // disabling this check after the definition of genesis clock has been extended
             // haven't revealed any bug with this assertion before, hopefully disabling it will not
@@ -131,12 +825,111 @@ impl OrdinaryClock {
     }
 }
 
-impl Clock for OrdinaryClock {
+/// Yields a batch of clocked events in causal order, as the antichains of
+/// concurrent (or equal) events `OrdinaryClock::topo_partition` layers them
+/// into. Events within a group keep their batch order.
+pub fn causal_sort<T, K: ClockKey>(
+    items: impl IntoIterator<Item = (T, OrdinaryClock<K>)>,
+) -> impl Iterator<Item = Vec<(T, OrdinaryClock<K>)>> {
+    let (events, clocks): (Vec<T>, Vec<_>) = items.into_iter().unzip();
+    let layers = OrdinaryClock::topo_partition(&clocks);
+    let mut items: Vec<_> = events.into_iter().zip(clocks).map(Some).collect();
+    layers.into_iter().map(move |layer| {
+        layer
+            .into_iter()
+            .map(|i| items[i].take().expect("indices are in one layer"))
+            .collect()
+    })
+}
+
+/// Returns the maximal clocks among `clocks`, i.e. the ones not dominated by
+/// any other. Equal clocks are kept only once, in first-seen order.
+pub fn frontier<K: ClockKey>(clocks: &[OrdinaryClock<K>]) -> Vec<OrdinaryClock<K>> {
+    let mut maximal: Vec<OrdinaryClock<K>> = Vec::new();
+    for clock in clocks {
+        if maximal.iter().any(|kept| {
+            matches!(
+                clock.partial_cmp(kept),
+                Some(Ordering::Less | Ordering::Equal)
+            )
+        }) {
+            continue;
+        }
+        maximal.retain(|kept| !kept.is_dominated_by(clock));
+        maximal.push(clock.clone());
+    }
+    maximal
+}
+
+/// The stability cut of the clocks of all nodes: per component, the minimum
+/// count across `node_clocks`, a missing component counting as zero. Events
+/// at or below the cut have been seen by every node, so the data kept for
+/// them can be garbage collected. Unlike `OrdinaryClock::base`, a component
+/// some node has not seen is not part of the cut.
+pub fn stable_frontier<K: ClockKey>(node_clocks: &[OrdinaryClock<K>]) -> OrdinaryClock<K> {
+    let Some((first, rest)) = node_clocks.split_first() else {
+        return OrdinaryClock(BTreeMap::new());
+    };
+    let mut cut = first.0.clone();
+    for clock in rest {
+        cut.retain(|key, count| match clock.0.get(key) {
+            Some(&other) => {
+                *count = (*count).min(other);
+                true
+            }
+            None => false,
+        });
+    }
+    cut.retain(|_, count| *count > 0);
+    OrdinaryClock(cut)
+}
+
+/// Whether the event at `event_clock` is stable, i.e. at or below the cut
+/// computed by `stable_frontier`.
+pub fn is_stable<K: ClockKey>(
+    event_clock: &OrdinaryClock<K>,
+    stable_frontier: &OrdinaryClock<K>,
+) -> bool {
+    event_clock.non_zero().all(|(key, count)| {
+        stable_frontier
+            .0
+            .get(key)
+            .is_some_and(|stable| stable >= count)
+    })
+}
+
+impl<K: ClockKey> Clock for OrdinaryClock<K> {
     fn reduce(&self) -> LamportClock {
         self.0.values().copied().sum()
     }
 }
 
+/// A clock hash, see `OrdinaryClock::calculate_sha256`. Parsing stored hex
+/// strings into it ensures they are valid 32 byte hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClockHash(pub [u8; 32]);
+
+impl ClockHash {
+    /// Parse 64 hex characters, in either case.
+    pub fn from_hex(hex: &str) -> Result<Self, VlcError> {
+        let malformed = || VlcError::MalformedHash(hex.to_string());
+        if hex.len() != 64 {
+            return Err(malformed());
+        }
+        let mut hash = [0; 32];
+        for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let nibble = |c: u8| (c as char).to_digit(16).ok_or_else(malformed);
+            *byte = (nibble(pair[0])? << 4 | nibble(pair[1])?) as u8;
+        }
+        Ok(Self(hash))
+    }
+
+    /// The lowercase 64 character hex string clock hashes are stored as.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,20 +937,12 @@ mod tests {
         core::DigestHash,
         recovery::{recover_public_key, sign_message_recover_pk},
     };
-    use futures::future::join_all;
     use rand::rngs::OsRng;
-    use std::{
-        sync::{
-            atomic::{AtomicUsize, Ordering},
-            Arc,
-        },
-        time::{Duration, Instant},
-    };
-    use tokio::runtime::Builder;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn default_is_genesis() -> anyhow::Result<()> {
-        anyhow::ensure!(OrdinaryClock::default().is_genesis());
+        anyhow::ensure!(OrdinaryClock::<KeyId>::default().is_genesis());
         Ok(())
     }
 
@@ -192,6 +977,317 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn par_base_matches_base() -> anyhow::Result<()> {
+        use rand::Rng as _;
+
+        let mut rng = rand::thread_rng();
+        for n in [0, 1, 7, 100, 1000] {
+            let clocks: Vec<OrdinaryClock> = (0..n)
+                .map(|_| {
+                    let width = rng.gen_range(0..32);
+                    OrdinaryClock(
+                        (0..width)
+                            .map(|_| (rng.gen_range(0..64), rng.gen()))
+                            .collect(),
+                    )
+                })
+                .collect();
+            let serial = OrdinaryClock::base(clocks.iter());
+            for partitions in [0, 1, 3, 16, 2000] {
+                assert_eq!(OrdinaryClock::par_base(&clocks, partitions), serial);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn frontier_keeps_maximal_clocks() -> anyhow::Result<()> {
+        let genesis = OrdinaryClock::default();
+        let c1 = genesis.update(std::iter::empty(), 0);
+        let c2 = c1.update(std::iter::empty(), 0);
+        let c3 = c2.update(std::iter::empty(), 0);
+        // concurrent with the chain
+        let d1 = genesis.update(std::iter::empty(), 1);
+        let d2 = d1.update(std::iter::empty(), 1);
+        // concurrent with both c3 and d2
+        let e = c2.update([&d1].into_iter(), 2);
+
+        anyhow::ensure!(c1.is_dominated_by(&c3));
+        anyhow::ensure!(!c3.is_dominated_by(&c1));
+        anyhow::ensure!(!c3.is_dominated_by(&c3));
+        anyhow::ensure!(!d2.is_dominated_by(&c3));
+
+        let clocks = vec![c1, c3.clone(), d1, c2, d2.clone(), e.clone(), c3.clone()];
+        assert_eq!(frontier(&clocks), vec![c3, d2, e]);
+        Ok(())
+    }
+
+    #[test]
+    fn prefiltered_cmp_matches_full_scan() {
+        use rand::Rng as _;
+
+        fn full_cmp(a: &OrdinaryClock, b: &OrdinaryClock) -> Option<Ordering> {
+            let ge = |a: &OrdinaryClock, b: &OrdinaryClock| {
+                b.0.iter()
+                    .all(|(id, n)| *n == 0 || a.0.get(id).is_some_and(|m| m >= n))
+            };
+            match (ge(a, b), ge(b, a)) {
+                (true, true) => Some(Ordering::Equal),
+                (true, false) => Some(Ordering::Greater),
+                (false, true) => Some(Ordering::Less),
+                (false, false) => None,
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        let mut random_clock = || {
+            OrdinaryClock(
+                (0..rng.gen_range(0..6))
+                    .map(|_| (rng.gen_range(0..6), rng.gen_range(0..4)))
+                    .collect(),
+            )
+        };
+        let wide = OrdinaryClock([(0, u64::MAX), (1, u64::MAX)].into());
+        let mut clocks: Vec<OrdinaryClock> = (0..200).map(|_| random_clock()).collect();
+        clocks.extend([OrdinaryClock::default(), wide]);
+        let mut ordered = 0;
+        for a in &clocks {
+            for b in &clocks {
+                let expected = full_cmp(a, b);
+                assert_eq!(a.partial_cmp(b), expected, "{a:?} {b:?}");
+                if expected.is_some() {
+                    ordered += 1;
+                    assert!(a.maybe_comparable(b), "{a:?} {b:?}");
+                }
+            }
+        }
+        // the battery covers ordered pairs beyond each clock with itself
+        assert!(ordered > clocks.len());
+    }
+
+    #[test]
+    fn explain_diff_lists_differing_components() {
+        let clock = OrdinaryClock::with_components(0..100).update(std::iter::empty(), 7);
+        let mut other = clock.clone();
+        assert!(clock.explain_diff(&other).is_empty());
+        other.0.insert(42, 3);
+        other.0.insert(100, 1);
+        other.0.remove(&7);
+        assert_ne!(clock.calculate_sha256(), other.calculate_sha256());
+        assert_eq!(
+            clock.explain_diff(&other),
+            [
+                (7, Some(1), None),
+                (42, Some(0), Some(3)),
+                (100, None, Some(1))
+            ]
+        );
+        assert_eq!(other.explain_diff(&clock)[0], (7, None, Some(1)));
+    }
+
+    #[test]
+    fn cmp_ignoring_components() {
+        let genesis = OrdinaryClock::default();
+        let a = genesis.update(std::iter::empty(), 0);
+        let b = a.update(std::iter::empty(), 1);
+        // a flapping node 2 advanced on the older clock only
+        let a = a.update(std::iter::empty(), 2);
+        assert_eq!(a.partial_cmp(&b), None);
+
+        let flapping = BTreeSet::from([2]);
+        assert_eq!(a.partial_cmp_ignoring(&b, &flapping), Some(Ordering::Less));
+        assert_eq!(
+            b.partial_cmp_ignoring(&a, &flapping),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(a.partial_cmp_ignoring(&b, &BTreeSet::new()), None);
+        let both = BTreeSet::from([1, 2]);
+        assert_eq!(a.partial_cmp_ignoring(&b, &both), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn update_against_genesis() {
+        let clock = OrdinaryClock(BTreeMap::from([(0, 3), (1, 1)]));
+        let empty = OrdinaryClock::default();
+        let zeros = OrdinaryClock::with_components([1, 2, 5].into_iter());
+        for others in [vec![], vec![&empty], vec![&zeros, &empty, &zeros]] {
+            for id in [0, 2, 9] {
+                let (mut general, _) = clock.merge_all(others.iter().copied());
+                *general.0.entry(id).or_default() += 1;
+                assert_eq!(clock.update(others.iter().copied(), id), general);
+            }
+        }
+    }
+
+    #[test]
+    fn clock_hash_parsing() -> anyhow::Result<()> {
+        let clock = OrdinaryClock::default().update(std::iter::empty(), 1);
+        let hex = clock.clock_hash_hex();
+        let hash = ClockHash::from_hex(&hex)?;
+        assert_eq!(hash, clock.clock_hash());
+        assert_eq!(hash.to_hex(), hex);
+        assert_eq!(ClockHash::from_hex(&hex.to_uppercase())?, hash);
+
+        let long = format!("{hex}00");
+        let not_hex = format!("g{}", &hex[1..]);
+        for malformed in [&hex[..62], &long, &not_hex] {
+            assert!(matches!(
+                ClockHash::from_hex(malformed),
+                Err(VlcError::MalformedHash(_))
+            ));
+        }
+        // 64 bytes, but not 64 characters
+        assert!(ClockHash::from_hex(&format!("é{}", &hex[2..])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn merge_counts() {
+        let genesis = OrdinaryClock::default();
+        let first = genesis.update(std::iter::empty(), 1);
+        assert_eq!(OrdinaryClock::merge_counts(&genesis, &first, &1), (0, 1));
+        let peer = first
+            .update(std::iter::empty(), 2)
+            .update(std::iter::empty(), 2);
+        let second = first.update([&peer].into_iter(), 1);
+        assert_eq!(OrdinaryClock::merge_counts(&first, &second, &1), (1, 2));
+        assert_eq!(OrdinaryClock::merge_counts(&first, &second, &2), (0, 2));
+    }
+
+    #[test]
+    fn packed_encoding() -> anyhow::Result<()> {
+        let dense = OrdinaryClock((0..64).map(|id| (id, id % 5)).collect());
+        let packed = dense.encode_packed();
+        assert_eq!(packed[0], PACKED_DENSE);
+        assert_eq!(OrdinaryClock::decode_packed(&packed)?, dense);
+        let general = bincode::options().serialize(&dense)?;
+        assert!(packed.len() < general.len());
+
+        let shifted = OrdinaryClock((1000..1010).map(|id| (id, 1)).collect());
+        let packed = shifted.encode_packed();
+        assert_eq!(OrdinaryClock::decode_packed(&packed)?, shifted);
+        let top = OrdinaryClock(BTreeMap::from([(KeyId::MAX - 1, 1), (KeyId::MAX, 2)]));
+        assert_eq!(OrdinaryClock::decode_packed(&top.encode_packed())?, top);
+
+        for sparse in [
+            OrdinaryClock::new(),
+            OrdinaryClock(BTreeMap::from([(0, 1), (5, 2)])),
+            OrdinaryClock(BTreeMap::from([(0, 1), (KeyId::MAX, 2)])),
+        ] {
+            let packed = sparse.encode_packed();
+            assert_eq!(packed[0], PACKED_SPARSE);
+            assert_eq!(OrdinaryClock::decode_packed(&packed)?, sparse);
+        }
+
+        // a dense range running past the largest id
+        let mut overflowing = vec![PACKED_DENSE];
+        bincode::options().serialize_into(&mut overflowing, &(KeyId::MAX, vec![1u64, 2]))?;
+        assert!(OrdinaryClock::decode_packed(&overflowing).is_err());
+        assert!(OrdinaryClock::decode_packed(&[7]).is_err());
+        assert!(OrdinaryClock::decode_packed(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn split_then_join() {
+        let clock = OrdinaryClock((0..20).map(|key| (key * 3, key + 1)).collect());
+        for shards in [1, 2, 4, 7, 64] {
+            let split = clock.split(shards);
+            assert_eq!(split.len(), shards);
+            for (i, shard) in split.iter().enumerate() {
+                assert!(shard.0.keys().all(|key| *key as usize % shards == i));
+            }
+            assert_eq!(OrdinaryClock::join(&split), clock);
+        }
+        let genesis = OrdinaryClock::new();
+        assert_eq!(OrdinaryClock::join(&genesis.split(4)), genesis);
+    }
+
+    #[test]
+    fn stability_cut() -> anyhow::Result<()> {
+        let clock = |pairs: &[(KeyId, u64)]| OrdinaryClock(pairs.iter().copied().collect());
+        let nodes = [
+            clock(&[(0, 3), (1, 2), (2, 5)]),
+            clock(&[(0, 4), (1, 1), (2, 5)]),
+            // has not heard from node 2 yet
+            clock(&[(0, 2), (1, 4)]),
+        ];
+        let cut = stable_frontier(&nodes);
+        assert_eq!(cut, clock(&[(0, 2), (1, 1)]));
+
+        anyhow::ensure!(is_stable(&clock(&[(0, 2), (1, 1)]), &cut));
+        anyhow::ensure!(is_stable(&clock(&[(0, 1), (2, 0)]), &cut));
+        anyhow::ensure!(!is_stable(&clock(&[(0, 3)]), &cut));
+        anyhow::ensure!(!is_stable(&clock(&[(2, 1)]), &cut));
+        assert_eq!(stable_frontier::<KeyId>(&[]), OrdinaryClock::default());
+        Ok(())
+    }
+
+    #[test]
+    fn topo_partition_layers() -> anyhow::Result<()> {
+        let genesis = OrdinaryClock::default();
+        let a1 = genesis.update(std::iter::empty(), 0);
+        let a2 = a1.update(std::iter::empty(), 0);
+        let b1 = genesis.update(std::iter::empty(), 1);
+        // joins both branches
+        let c = a2.update([&b1].into_iter(), 2);
+        let d = c.update(std::iter::empty(), 0);
+
+        let clocks = [d, b1, c, a2.clone(), a1, genesis, a2];
+        assert_eq!(
+            OrdinaryClock::topo_partition(&clocks),
+            vec![vec![5], vec![1, 4], vec![3, 6], vec![2], vec![0]]
+        );
+        anyhow::ensure!(OrdinaryClock::<KeyId>::topo_partition(&[]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn classify_against_reference() {
+        let genesis = OrdinaryClock::default();
+        let a = genesis.update(std::iter::empty(), 0);
+        let reference = a.update(std::iter::empty(), 1);
+        let clocks = [
+            a.clone(),
+            reference.update(std::iter::empty(), 2),
+            a.update(std::iter::empty(), 2),
+            reference.clone(),
+            genesis,
+        ];
+        let relations = OrdinaryClock::classify_against(&reference, &clocks);
+        assert_eq!(
+            relations,
+            [
+                CausalRelation::Before,
+                CausalRelation::After,
+                CausalRelation::Concurrent,
+                CausalRelation::Equal,
+                CausalRelation::Before,
+            ]
+        );
+        for (clock, relation) in clocks.iter().zip(relations) {
+            assert_eq!(clock.relation(&reference), relation);
+        }
+    }
+
+    #[test]
+    fn causal_sort_groups() {
+        let genesis = OrdinaryClock::default();
+        let a = genesis.update(std::iter::empty(), 0);
+        let b = genesis.update(std::iter::empty(), 1);
+        let c = a.update([&b].into_iter(), 2);
+        let d = a.update(std::iter::empty(), 0);
+
+        let batch = [("d", d), ("c", c), ("b", b), ("a", a)];
+        let groups: Vec<Vec<&str>> = causal_sort(batch)
+            .map(|group| group.into_iter().map(|(event, _)| event).collect())
+            .collect();
+        assert_eq!(groups, [vec!["b", "a"], vec!["d", "c"]]);
+        assert_eq!(causal_sort::<(), KeyId>([]).count(), 0);
+    }
+
     #[test]
     fn clock_sha256() -> anyhow::Result<()> {
         let mut clock = OrdinaryClock((0..4).map(|i| (i as _, 0)).collect());
@@ -202,10 +1298,465 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn sorted_pairs_hash() -> anyhow::Result<()> {
+        let pairs = (0..64).map(|i| (i * 7 % 64, i + 1));
+        let from_hash_map: std::collections::HashMap<KeyId, u64> = pairs.clone().collect();
+        let mut from_btree_map = BTreeMap::new();
+        for (id, n) in pairs.rev() {
+            from_btree_map.insert(id, n);
+        }
+        let clock = OrdinaryClock(from_hash_map.into_iter().collect());
+        let other = OrdinaryClock(from_btree_map);
+
+        let sorted = clock.to_sorted_pairs();
+        anyhow::ensure!(sorted.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(sorted, other.to_sorted_pairs());
+        assert_eq!(clock.calculate_sha256(), other.calculate_sha256());
+        assert_eq!(clock.calculate_sha256(), clock.clone().calculate_sha256());
+        // the wire format is the sequence of pairs
+        let encoded = bincode::options().serialize(&clock)?;
+        assert_eq!(encoded, bincode::options().serialize(&sorted)?);
+        assert_eq!(
+            bincode::options().deserialize::<OrdinaryClock>(&encoded)?,
+            other
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn update_stats() -> anyhow::Result<()> {
+        let clock = OrdinaryClock(BTreeMap::from([(0, 1), (1, 1)]));
+        let ahead = OrdinaryClock(BTreeMap::from([(0, 1), (1, 5), (2, 3)]));
+        let (updated, stats) = clock.update_with_stats([&ahead].into_iter(), 0);
+        assert_eq!(updated, clock.update([&ahead].into_iter(), 0));
+        assert_eq!(
+            stats,
+            MergeStats {
+                advanced_by_merge: 2,
+                advanced_by_increment: 1,
+                max_delta: 4,
+            }
+        );
+
+        // the merge already advanced the incremented component
+        let (_, stats) = clock.update_with_stats([&ahead].into_iter(), 1);
+        assert_eq!(stats.advanced_by_merge, 2);
+        assert_eq!(stats.advanced_by_increment, 0);
+        assert_eq!(stats.max_delta, 5);
+
+        // nothing to merge
+        let (_, stats) = clock.update_with_stats([&clock].into_iter(), 2);
+        assert_eq!(
+            stats,
+            MergeStats {
+                advanced_by_merge: 0,
+                advanced_by_increment: 1,
+                max_delta: 1,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn advance_never_goes_backward() -> anyhow::Result<()> {
+        let mut clock = OrdinaryClock(BTreeMap::from([(0, 3)]));
+        anyhow::ensure!(!clock.advance(0, 2));
+        anyhow::ensure!(!clock.advance(0, 3));
+        assert_eq!(clock.0[&0], 3);
+        anyhow::ensure!(clock.advance(0, 7));
+        anyhow::ensure!(clock.advance(1, 1));
+        assert_eq!(clock, OrdinaryClock(BTreeMap::from([(0, 7), (1, 1)])));
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_construction() -> anyhow::Result<()> {
+        let clock = OrdinaryClock::with_components(0..1000);
+        assert_eq!(clock, OrdinaryClock((0..1000).map(|i| (i, 0)).collect()));
+        anyhow::ensure!(clock.is_genesis());
+
+        let mut clock = OrdinaryClock::with_components(0..4);
+        clock.extend_from_sorted((2..8).map(|i| (i, i * 10)));
+        assert_eq!(
+            clock,
+            OrdinaryClock::with_components(0..2)
+                .merge(&OrdinaryClock((2..8).map(|i| (i, i * 10)).collect()))
+        );
+        // existing counts are replaced, not merged
+        clock.extend_from_sorted([(2, 1)]);
+        assert_eq!(clock.0[&2], 1);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_cmp_matches_merge_then_compare() -> anyhow::Result<()> {
+        let clock = OrdinaryClock(BTreeMap::from([(0, 2), (1, 1)]));
+        let behind = OrdinaryClock(BTreeMap::from([(0, 1)]));
+        let ahead = OrdinaryClock(BTreeMap::from([(1, 3)]));
+        let zero = OrdinaryClock::with_components(5..6);
+        for others in [vec![], vec![&behind], vec![&behind, &ahead], vec![&zero]] {
+            let merged = others
+                .iter()
+                .fold(clock.clone(), |merged, other| merged.merge(other));
+            let mut merged_cmp = clock.clone();
+            let ordering = merged_cmp.merge_cmp(others.into_iter());
+            assert_eq!(merged_cmp, merged);
+            assert_eq!(Some(ordering), merged.partial_cmp(&clock));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn merge_identical_clocks_once() -> anyhow::Result<()> {
+        let clock = OrdinaryClock(BTreeMap::from([(0, 1), (1, 1)]));
+        let other = OrdinaryClock(BTreeMap::from([(0, 3), (2, 1)]));
+        let copies = vec![other.clone(); 100];
+        let (merged, merges) = clock.merge_all(copies.iter());
+        assert_eq!(merged, clock.merge(&other));
+        assert_eq!(merges, 1);
+        assert_eq!(
+            clock.update(copies.iter(), 0),
+            clock.update([&other].into_iter(), 0)
+        );
+
+        let (_, merges) = clock.merge_all([&other, &clock, &other].into_iter());
+        assert_eq!(merges, 2);
+        Ok(())
+    }
+
+    #[cfg(feature = "trace-merges")]
+    #[test]
+    fn merge_traces_deltas() -> anyhow::Result<()> {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let clock = OrdinaryClock(BTreeMap::from([(0, 2), (1, 1)]));
+            let ahead = OrdinaryClock(BTreeMap::from([(0, 2), (1, 5), (2, 3)]));
+            clock.update([&ahead].into_iter(), 0);
+            // nothing to log
+            ahead.update([&clock].into_iter(), 0);
+        });
+        let output = String::from_utf8(captured.0.lock().unwrap().clone())?;
+        anyhow::ensure!(output.lines().count() == 1, "{output}");
+        anyhow::ensure!(output.contains("deltas={1: 4, 2: 3}"), "{output}");
+        Ok(())
+    }
+
+    #[test]
+    fn versioned_encoding() -> anyhow::Result<()> {
+        let clock = OrdinaryClock::<KeyId>(BTreeMap::from([(0, 1), (7, 0), (1 << 40, 3)]));
+        let buf = clock.encode_versioned();
+        assert_eq!(buf[0], ENCODING_VERSION);
+        assert_eq!(OrdinaryClock::decode_versioned(&buf)?, clock);
+
+        let mut bumped = buf.clone();
+        bumped[0] += 1;
+        assert_eq!(
+            OrdinaryClock::<KeyId>::decode_versioned(&bumped),
+            Err(VlcError::UnknownVersion(ENCODING_VERSION + 1))
+        );
+        anyhow::ensure!(matches!(
+            OrdinaryClock::<KeyId>::decode_versioned(&buf[..buf.len() - 1]),
+            Err(VlcError::Malformed(_))
+        ));
+        anyhow::ensure!(matches!(
+            OrdinaryClock::<KeyId>::decode_versioned(&[]),
+            Err(VlcError::Malformed(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn serde_representations() -> anyhow::Result<()> {
+        let clock = OrdinaryClock::<KeyId>(BTreeMap::from([(0, 1), (7, 0), (1 << 40, 3)]));
+        let json = serde_json::to_string(&clock)?;
+        assert_eq!(json, r#"{"components":[[0,1],[7,0],[1099511627776,3]]}"#);
+        assert_eq!(serde_json::from_str::<OrdinaryClock>(&json)?, clock);
+        let empty = serde_json::from_str::<OrdinaryClock>(r#"{"components":[]}"#)?;
+        anyhow::ensure!(empty.is_genesis());
+
+        // bincode keeps the plain map encoding
+        let buf = bincode::options().serialize(&clock)?;
+        assert_eq!(buf, bincode::options().serialize(&clock.0)?);
+        let decoded: OrdinaryClock = bincode::options().deserialize(&buf)?;
+        assert_eq!(decoded, clock);
+        Ok(())
+    }
+
+    #[test]
+    fn normalize_wire_encodings() -> anyhow::Result<()> {
+        let json = r#"{"components":[[3,1],[5,0],[0,2]]}"#;
+        let mut from_json = serde_json::from_str::<OrdinaryClock>(json)?;
+        let buf = bincode::options().serialize(&BTreeMap::from([(0u64, 2u64), (3, 1)]))?;
+        let mut from_bincode: OrdinaryClock = bincode::options().deserialize(&buf)?;
+        assert_ne!(from_json, from_bincode);
+        from_json.normalize();
+        from_bincode.normalize();
+        assert_eq!(from_json, from_bincode);
+        assert_eq!(
+            from_json.calculate_sha256(),
+            from_bincode.calculate_sha256()
+        );
+
+        let mut genesis = OrdinaryClock::with_components(0..4);
+        genesis.normalize();
+        anyhow::ensure!(genesis.0.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn wide_keys() -> anyhow::Result<()> {
+        let (a, b) = ([0xaa; 16], [0xbb; 16]);
+        let genesis = OrdinaryClock::<[u8; 16]>::default();
+        let first = genesis.update([].iter(), a);
+        let second = genesis.update([].iter(), b);
+        assert_eq!(first.partial_cmp(&second), None);
+        let merged = first.update([&second].into_iter(), a);
+        anyhow::ensure!(merged > first && merged > second);
+        assert_eq!(merged.0, BTreeMap::from([(a, 2), (b, 1)]));
+
+        let rebuilt = OrdinaryClock(BTreeMap::from([(b, 1), (a, 2), ([0; 16], 0)]));
+        assert_eq!(rebuilt.calculate_sha256(), merged.calculate_sha256());
+        let decoded = OrdinaryClock::decode_versioned(&merged.encode_versioned())?;
+        assert_eq!(decoded, merged);
+        Ok(())
+    }
+
+    #[test]
+    fn clock_hash_hex() -> anyhow::Result<()> {
+        let clock = OrdinaryClock::<KeyId>(BTreeMap::from([(0, 3), (2, 0), (5, 1)]));
+        let hex = clock.clock_hash_hex();
+        assert_eq!(hex.len(), 64);
+        anyhow::ensure!(hex
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)));
+        let digest = clock.calculate_sha256();
+        assert_eq!(hex[..2], format!("{:02x}", digest[0]));
+
+        // stored clocks read back to the same hash, zero components or not
+        let stored = serde_json::to_string(&clock)?;
+        let mut restored = serde_json::from_str::<OrdinaryClock>(&stored)?;
+        assert_eq!(restored.clock_hash_hex(), hex);
+        restored.normalize();
+        assert_eq!(restored.clock_hash_hex(), hex);
+        Ok(())
+    }
+
+    #[test]
+    fn causal_relation() -> anyhow::Result<()> {
+        let clock = OrdinaryClock(BTreeMap::from([(0, 1), (1, 1)]));
+        let after = OrdinaryClock(BTreeMap::from([(0, 2), (1, 1)]));
+        let concurrent = OrdinaryClock(BTreeMap::from([(0, 0), (1, 2)]));
+        // zero components do not matter
+        let equal = OrdinaryClock(BTreeMap::from([(0, 1), (1, 1), (2, 0)]));
+        assert_eq!(clock.relation(&after), CausalRelation::Before);
+        assert_eq!(after.relation(&clock), CausalRelation::After);
+        assert_eq!(clock.relation(&equal), CausalRelation::Equal);
+        assert_eq!(clock.relation(&concurrent), CausalRelation::Concurrent);
+        assert_eq!(concurrent.partial_cmp(&clock), None);
+        Ok(())
+    }
+
+    #[test]
+    fn update_owned_matches_update() -> anyhow::Result<()> {
+        let clock = OrdinaryClock(BTreeMap::from([(0, 2), (1, 1)]));
+        let others = [
+            OrdinaryClock(BTreeMap::from([(0, 1), (1, 4)])),
+            OrdinaryClock(BTreeMap::from([(2, 3)])),
+            OrdinaryClock::default(),
+        ];
+        for id in [0, 1, 5] {
+            assert_eq!(
+                clock.clone().update_owned(others.clone(), id),
+                clock.update(others.iter(), id)
+            );
+        }
+        assert_eq!(
+            clock.clone().update_owned([], 0),
+            clock.update(std::iter::empty(), 0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn behind_reference() -> anyhow::Result<()> {
+        let clock = OrdinaryClock(BTreeMap::from([(0, 4), (1, 2), (3, 9)]));
+        let reference = OrdinaryClock(BTreeMap::from([(0, 4), (1, 5), (2, 1)]));
+        assert_eq!(clock.behind(&reference), BTreeMap::from([(1, 3), (2, 1)]));
+        // components only in self, and equal ones, contribute nothing
+        assert_eq!(reference.behind(&clock), BTreeMap::from([(3, 9)]));
+        anyhow::ensure!(clock.behind(&clock).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_update_matches_update() {
+        let prev = OrdinaryClock::with_components(0..100);
+        // four clocks splitting components 0..200 between them
+        let others: Vec<_> = (0..4)
+            .map(|i| OrdinaryClock((i..200).step_by(4).map(|k| (k, k + 1)).collect()))
+            .collect();
+        let expected = prev.update(others.iter(), 7);
+
+        let bounded = |mut clock: OrdinaryClock| {
+            let mut cursor = UpdateCursor::default();
+            let mut calls = 0;
+            loop {
+                let before = (cursor.other, cursor.last);
+                calls += 1;
+                let complete = clock.update_bounded(&others, 7, 16, &mut cursor);
+                // each call merges the next 16 components, or the rest
+                let after = (cursor.other, cursor.last);
+                let merged = others
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, other)| other.0.keys().map(move |key| (i, *key)))
+                    .filter(|&(i, key)| {
+                        (i, Some(key)) > (before.0, before.1)
+                            && (complete || (i, Some(key)) <= (after.0, after.1))
+                    })
+                    .count();
+                assert!(merged <= 16);
+                if complete {
+                    return (clock, calls);
+                }
+                assert_eq!(merged, 16);
+            }
+        };
+        let (clock, calls) = bounded(prev);
+        assert_eq!(clock, expected);
+        // 200 components to merge, 16 per call, the last call completing
+        assert_eq!(calls, 13);
+        // components already covered cost the same, rather than being
+        // skipped in one unbounded scan
+        let (clock, calls) = bounded(expected.clone());
+        assert_eq!(clock, expected.update([].into_iter(), 7));
+        assert_eq!(calls, 13);
+
+        // nothing to merge completes at once, even without an op budget
+        let mut clock = expected.clone();
+        let mut cursor = UpdateCursor::default();
+        assert!(clock.update_bounded(&[], 7, 0, &mut cursor));
+        assert!(cursor.is_complete());
+        assert_eq!(clock, expected.update([].into_iter(), 7));
+        // and stays complete
+        assert!(clock.update_bounded(&others, 7, 0, &mut cursor));
+        assert_eq!(clock, expected.update([].into_iter(), 7));
+    }
+
+    #[test]
+    fn authoritative_merge() {
+        let clock = OrdinaryClock(BTreeMap::from([(0, 3), (1, 2), (2, 5)]));
+        // node 1 advances itself, and forges advances of nodes 2 and 3
+        let other = OrdinaryClock(BTreeMap::from([(0, 1), (1, 4), (2, 9), (3, 1)]));
+        let merged = clock.merge_authoritative(&other, 1);
+        assert_eq!(merged.0, BTreeMap::from([(0, 3), (1, 4), (2, 5)]));
+        // an owner behind this clock changes nothing
+        assert_eq!(clock.merge_authoritative(&other, 0), clock);
+        assert_eq!(clock.merge_authoritative(&other, 4), clock);
+    }
+
+    #[test]
+    fn merge_conflicts() {
+        let base = OrdinaryClock::<KeyId>(BTreeMap::from([(0, 1), (1, 1)]));
+        let left = base.update([].iter(), 0);
+        let right = base.update([].iter(), 1);
+        let (merged, conflict) = left.merge_detect_conflict(&right);
+        assert!(conflict);
+        assert_eq!(merged.0, BTreeMap::from([(0, 2), (1, 2)]));
+        assert_eq!(right.merge_detect_conflict(&left), (merged, true));
+
+        let (merged, conflict) = base.merge_detect_conflict(&left);
+        assert!(!conflict);
+        assert_eq!(merged, left);
+        assert_eq!(left.merge_detect_conflict(&base), (left.clone(), false));
+        assert_eq!(left.merge_detect_conflict(&left), (left.clone(), false));
+    }
+
+    #[test]
+    fn shared_and_exclusive_keys() {
+        let a = OrdinaryClock(BTreeMap::from([(0, 1), (1, 2), (2, 3)]));
+        let b = OrdinaryClock(BTreeMap::from([(1, 5), (2, 1), (4, 1)]));
+        assert_eq!(a.shared_keys(&b), BTreeSet::from([1, 2]));
+        assert_eq!(b.shared_keys(&a), BTreeSet::from([1, 2]));
+        assert_eq!(
+            a.exclusive_keys(&b),
+            (BTreeSet::from([0]), BTreeSet::from([4]))
+        );
+        assert_eq!(a.exclusive_keys(&a), (BTreeSet::new(), BTreeSet::new()));
+        let empty = OrdinaryClock::default();
+        assert!(a.shared_keys(&empty).is_empty());
+        assert_eq!(a.exclusive_keys(&empty).0, BTreeSet::from([0, 1, 2]));
+    }
+
+    #[test]
+    fn ensure_monotonic() -> anyhow::Result<()> {
+        let previous = OrdinaryClock(BTreeMap::from([(0, 4), (1, 2)]));
+        let updated = previous.update([].into_iter(), 1);
+        updated.ensure_ge(&previous)?;
+        previous.ensure_ge(&previous)?;
+
+        // a bad merge that lost component 0 and moved component 1 back
+        let regressed = OrdinaryClock(BTreeMap::from([(1, 1), (2, 7)]));
+        assert_eq!(
+            regressed.ensure_ge(&previous),
+            Err(VlcError::Regressed {
+                id: 0,
+                previous: 4,
+                current: 0
+            })
+        );
+        let regressed = OrdinaryClock(BTreeMap::from([(0, 4), (1, 1)]));
+        assert_eq!(
+            regressed.ensure_ge(&previous),
+            Err(VlcError::Regressed {
+                id: 1,
+                previous: 2,
+                current: 1
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn zero_components_are_semantically_absent() -> anyhow::Result<()> {
+        let explicit_zero = OrdinaryClock(BTreeMap::from([(1, 0)]));
+        let empty = OrdinaryClock::default();
+        anyhow::ensure!(explicit_zero != empty);
+        anyhow::ensure!(explicit_zero.semantic_eq(&empty));
+        assert_eq!(explicit_zero.calculate_sha256(), empty.calculate_sha256());
+
+        let advanced = OrdinaryClock(BTreeMap::from([(1, 1), (2, 0)]));
+        anyhow::ensure!(!advanced.semantic_eq(&empty));
+        anyhow::ensure!(advanced.semantic_eq(&OrdinaryClock(BTreeMap::from([(1, 1)]))));
+        assert_ne!(advanced.calculate_sha256(), empty.calculate_sha256());
+        Ok(())
+    }
+
     #[test]
     #[ignore]
     fn hash_big_clock_sha256() -> anyhow::Result<()> {
-        let clock = OrdinaryClock((0..1 << 27).map(|i| (i as _, 0)).collect());
+        let clock = OrdinaryClock::with_components(0..1 << 27);
         let start_time = Instant::now();
         let clock_hash = clock.sha256().to_fixed_bytes();
         println!("{:?}, {:?}", clock_hash, start_time.elapsed());
@@ -215,7 +1766,7 @@ mod tests {
     #[test]
     #[ignore]
     fn increment_big_clock() -> anyhow::Result<()> {
-        let clock = OrdinaryClock((0..1 << 27).map(|i| (i as _, 0)).collect());
+        let clock = OrdinaryClock::with_components(0..1 << 27);
         let start_time = Instant::now();
         let appended = OrdinaryClock::new();
         appended.update(vec![].iter(), 1 << 2 + 1);
@@ -229,88 +1780,28 @@ mod tests {
     async fn stress_raw_update() -> anyhow::Result<()> {
         for size in (0..=12).step_by(2).map(|n| 1 << n) {
             let num_merged = 0;
-            let clock = OrdinaryClock((0..size).map(|i| (i as _, 0)).collect());
-
-            let mut count = 0;
-            let start_time = Instant::now();
-            let close_loops_session = async {
-                let mut current_clock = clock.clone();
-                loop {
-                    if start_time.elapsed() >= Duration::from_secs(10) {
-                        break;
-                    }
+            let clock = OrdinaryClock::with_components(0..size as _);
 
-                    let updated_clock =
-                        current_clock.update(vec![clock.clone(); num_merged].iter(), 0);
-                    count += 1;
-                    current_clock = updated_clock;
-                }
-                anyhow::Ok(())
-            };
-
-            close_loops_session.await?;
+            let merged = vec![clock.clone(); num_merged];
+            let limit = crate::bench::RunLimit::timed(Duration::from_secs(10));
+            let (_, count) = crate::bench::update_loop(clock, &merged, limit, &Instant::now());
             println!("key {size},merged {num_merged}, tps {}", count as f32 / 10.);
         }
         Ok(())
     }
 
-    #[tokio::test]
+    #[test]
     #[ignore]
-    async fn stress_raw_update_concurrency() -> anyhow::Result<()> {
-        let core = num_cpus::get();
-        let rt = Arc::new(
-            Builder::new_multi_thread()
-                .worker_threads(core)
-                .build()
-                .unwrap(),
-        );
-
-        for size in (0..=12).step_by(2).map(|n| 1 << n) {
-            let count = Arc::new(AtomicUsize::new(0));
-            let mut tasks = Vec::new();
-            let mut shifts: Vec<i32> = Vec::with_capacity(core);
-            for _ in 0..core {
-                shifts.push(size);
-            }
-            for size in shifts {
-                let num_merged = 0;
-                let clock = OrdinaryClock((0..size).map(|i| (i as _, 0)).collect());
-
-                let count_clone = Arc::clone(&count);
-                let start_time = Instant::now();
-                let close_loops_session = async move {
-                    // different clocks in different threads
-                    let mut current_clock = clock.clone();
-                    loop {
-                        if start_time.elapsed() >= Duration::from_secs(10) {
-                            break;
-                        }
-
-                        let updated_clock =
-                            current_clock.update(vec![clock.clone(); num_merged].iter(), 0);
-                        count_clone.fetch_add(1, Ordering::Relaxed);
-                        current_clock = updated_clock;
-                    }
-                    current_clock
-                };
-                tasks.push(rt.spawn(close_loops_session));
-            }
-            let results = join_all(tasks).await;
-            for result in results {
-                let clock = result?;
-                println!("key: {}, clock: {:?}", size, clock.0.get(&0));
-            }
-
+    fn stress_raw_update_concurrency() -> anyhow::Result<()> {
+        let sizes: Vec<usize> = (0..=12).step_by(2).map(|n| 1 << n).collect();
+        let results =
+            crate::bench::raw_update_throughput(&sizes, num_cpus::get(), Duration::from_secs(10))?;
+        for result in results {
             println!(
-                "key {}, merged 0, tps {}",
-                size,
-                count.load(Ordering::Relaxed) as f32 / 10.
+                "key {}, merged {}, tps {}",
+                result.key_size, result.merged, result.tps
             );
         }
-
-        // Shutdown Runtime
-        Arc::try_unwrap(rt).unwrap().shutdown_background();
-
         Ok(())
     }
 
@@ -324,7 +1815,7 @@ mod tests {
 
         for size in (0..=12).step_by(2).map(|n| 1 << n) {
             let num_merged = 0;
-            let clock = OrdinaryClock((0..size).map(|i| (i as _, 0)).collect());
+            let clock = OrdinaryClock::with_components(0..size as _);
             let clock_hash = clock.sha256().to_fixed_bytes();
             let mut count = 0;
 
@@ -370,7 +1861,7 @@ mod tests {
         let (secret_key, _public_key) = secp.generate_keypair(&mut OsRng);
         for size in (0..=12).step_by(2).map(|n| 1 << n) {
             let num_merged = 0;
-            let clock = OrdinaryClock((0..size).map(|i| (i as _, 0)).collect());
+            let clock = OrdinaryClock::with_components(0..size as _);
             let mut count = 0;
 
             let start_time = Instant::now();
@@ -380,13 +1871,14 @@ mod tests {
                     if start_time.elapsed() >= Duration::from_secs(10) {
                         break;
                     }
-                    
+
                     // sign
                     let clock_hash = clock.sha256().to_fixed_bytes();
                     sign_message_recover_pk(&secp, &secret_key, &clock_hash).unwrap();
 
                     // update
-                    let updated_clock = current_clock.update(vec![clock.clone(); num_merged].iter(), 0);
+                    let updated_clock =
+                        current_clock.update(vec![clock.clone(); num_merged].iter(), 0);
                     count += 1;
                     current_clock = updated_clock;
                 }
@@ -394,10 +1886,7 @@ mod tests {
             };
 
             close_loops_session.await?;
-            println!(
-                "key {size},merged {num_merged}, tps {}",
-                count as f32 / 10.
-            );
+            println!("key {size},merged {num_merged}, tps {}", count as f32 / 10.);
         }
         Ok(())
     }
@@ -412,7 +1901,7 @@ mod tests {
 
         for size in (0..=12).step_by(2).map(|n| 1 << n) {
             let num_merged = 0;
-            let clock = OrdinaryClock((0..size).map(|i| (i as _, 0)).collect());
+            let clock = OrdinaryClock::with_components(0..size as _);
 
             let mut count = 0;
             let mut signatures = None;
@@ -0,0 +1,174 @@
+//! Interning of string node ids into clock keys.
+//!
+//! Deployments name nodes by strings, while `OrdinaryClock` is keyed by
+//! `KeyId`. A `NodeIdRegistry` assigns each name the next free `KeyId` the
+//! first time it is seen, and keeps the assignment for good. Persist the
+//! registry with serde, as the list of names in id order, and share it
+//! between everything that builds or reads clocks of the same nodes. A list
+//! naming a node twice fails to deserialize, since the names after the
+//! duplicate would be restored with other ids than they were assigned.
+
+use crate::ordinary_clock::{KeyId, OrdinaryClock};
+use crate::VlcError;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct NodeIdRegistry {
+    /// Names by id.
+    names: Vec<String>,
+    ids: HashMap<String, KeyId>,
+}
+
+impl TryFrom<Vec<String>> for NodeIdRegistry {
+    type Error = VlcError;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        let mut registry = Self::default();
+        for name in names {
+            if registry.id(&name).is_some() {
+                return Err(VlcError::DuplicateNode(name));
+            }
+            registry.intern(&name);
+        }
+        Ok(registry)
+    }
+}
+
+impl From<NodeIdRegistry> for Vec<String> {
+    fn from(registry: NodeIdRegistry) -> Self {
+        registry.names
+    }
+}
+
+impl NodeIdRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The id of `name`, assigning the next free one if it is new.
+    pub fn intern(&mut self, name: &str) -> KeyId {
+        if let Some(id) = self.ids.get(name) {
+            return *id;
+        }
+        let id = self.names.len() as KeyId;
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// The id of `name`, if it was interned.
+    pub fn id(&self, name: &str) -> Option<KeyId> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: KeyId) -> Option<&str> {
+        self.names
+            .get(usize::try_from(id).ok()?)
+            .map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// A clock with the given counts of named nodes, interning new names.
+    pub fn clock<'a>(&mut self, counts: impl IntoIterator<Item = (&'a str, u64)>) -> OrdinaryClock {
+        OrdinaryClock(
+            counts
+                .into_iter()
+                .map(|(name, n)| (self.intern(name), n))
+                .collect(),
+        )
+    }
+
+    /// `OrdinaryClock::update`, incrementing the component of the node
+    /// `name`.
+    pub fn update<'a>(
+        &mut self,
+        clock: &'a OrdinaryClock,
+        others: impl Iterator<Item = &'a OrdinaryClock>,
+        name: &str,
+    ) -> OrdinaryClock {
+        clock.update(others, self.intern(name))
+    }
+
+    /// The counts of `clock` by node name. Fails on an id no name was
+    /// interned for, e.g. a clock built with another registry.
+    pub fn named_counts(&self, clock: &OrdinaryClock) -> Result<BTreeMap<String, u64>, VlcError> {
+        clock
+            .iter()
+            .map(|(id, n)| match self.name(*id) {
+                Some(name) => Ok((name.to_string(), *n)),
+                None => Err(VlcError::UnknownNode(*id)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_interning() -> anyhow::Result<()> {
+        let mut registry = NodeIdRegistry::new();
+        let alice = registry.intern("alice");
+        let bob = registry.intern("bob");
+        assert_ne!(alice, bob);
+        assert_eq!(registry.intern("alice"), alice);
+        assert_eq!(registry.id("bob"), Some(bob));
+        assert_eq!(registry.id("carol"), None);
+        assert_eq!(registry.name(alice), Some("alice"));
+        assert_eq!(registry.len(), 2);
+
+        // persisted and restored, names keep their ids
+        let persisted = serde_json::to_string(&registry)?;
+        assert_eq!(persisted, r#"["alice","bob"]"#);
+        let mut restored = serde_json::from_str::<NodeIdRegistry>(&persisted)?;
+        assert_eq!(restored, registry);
+        assert_eq!(restored.intern("bob"), bob);
+        assert_eq!(restored.intern("carol"), 2);
+
+        // a name listed twice would shift the ids of the names after it
+        let err = serde_json::from_str::<NodeIdRegistry>(r#"["alice","bob","alice","carol"]"#)
+            .unwrap_err();
+        anyhow::ensure!(
+            err.to_string().contains(r#""alice" registered twice"#),
+            "{err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn clocks_by_name() -> anyhow::Result<()> {
+        let mut registry = NodeIdRegistry::new();
+        let base = registry.clock([("alice", 1), ("bob", 2)]);
+        let alice = registry.update(&base, std::iter::empty(), "alice");
+        let bob = registry.update(&base, std::iter::empty(), "bob");
+        anyhow::ensure!(alice > base && bob > base);
+        assert_eq!(alice.partial_cmp(&bob), None);
+        let merged = registry.update(&alice, [&bob].into_iter(), "carol");
+        anyhow::ensure!(merged > alice && merged > bob);
+        assert_eq!(
+            registry.named_counts(&merged)?,
+            BTreeMap::from([
+                ("alice".to_string(), 2),
+                ("bob".to_string(), 3),
+                ("carol".to_string(), 1),
+            ])
+        );
+        // the same names give the same clock, whatever the order they come in
+        assert_eq!(registry.clock([("bob", 2), ("alice", 1)]), base);
+        assert_eq!(
+            NodeIdRegistry::new().named_counts(&base),
+            Err(VlcError::UnknownNode(0))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+//! Total ordering of clocked events into one sequence.
+//!
+//! Event logs often want a single sequence number per event rather than a
+//! partial order. A `Sequencer` linearizes events by the Lamport scalar of
+//! their clock, `reduce()`, breaking ties by the id of the node that stamped
+//! the event. Positions are computed from the clock and the id alone, so all
+//! nodes derive the same sequence whatever order they see events in, and an
+//! event is always sequenced after the events it causally follows.
+
+use crate::ordinary_clock::{KeyId, OrdinaryClock};
+use std::num::NonZeroU64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sequencer {
+    /// Bound of the node ids, which are the tiebreak within a scalar.
+    nodes: NonZeroU64,
+}
+
+impl Sequencer {
+    /// A sequencer of events stamped by nodes with ids below `nodes`.
+    pub fn new(nodes: NonZeroU64) -> Self {
+        Self { nodes }
+    }
+
+    /// Sequence position of the event that node `id` stamped with `clock`,
+    /// the clock's Lamport scalar times the bound plus `id`. Positions of
+    /// distinct events are distinct. Returns `None` if `id` is not below the
+    /// sequencer's bound, or if the position does not fit a `u64`, rather
+    /// than handing out a position that may collide.
+    pub fn next(&self, clock: &OrdinaryClock, id: KeyId) -> Option<u64> {
+        if id >= self.nodes.get() {
+            return None;
+        }
+        // summed wide, since `reduce` overflows on large clocks
+        let scalar: u128 = clock.values().map(|n| u128::from(*n)).sum();
+        let position = scalar.checked_mul(self.nodes.get().into())? + u128::from(id);
+        u64::try_from(position).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_events_are_sequenced_by_node() {
+        let sequencer = Sequencer::new(NonZeroU64::new(4).unwrap());
+        let base = OrdinaryClock::default().update(std::iter::empty(), 0);
+        // concurrent events of three nodes, then one following all of them
+        let concurrent: Vec<_> = [3, 1, 2]
+            .into_iter()
+            .map(|id| (base.update(std::iter::empty(), id), id))
+            .collect();
+        let clocks = concurrent.iter().map(|(clock, _)| clock);
+        let last = (base.update(clocks, 0), 0);
+
+        let mut events = concurrent.clone();
+        events.push(last.clone());
+        let sequence = |events: &[(OrdinaryClock, KeyId)]| {
+            let mut positions: Vec<_> = events
+                .iter()
+                .map(|(clock, id)| (sequencer.next(clock, *id).unwrap(), *id))
+                .collect();
+            positions.sort();
+            positions
+        };
+        let expected = sequence(&events);
+        assert_eq!(
+            expected.iter().map(|(_, id)| *id).collect::<Vec<_>>(),
+            [1, 2, 3, 0]
+        );
+        // the same in any arrival order
+        events.reverse();
+        assert_eq!(sequence(&events), expected);
+        events.rotate_left(2);
+        assert_eq!(sequence(&events), expected);
+
+        assert!(sequencer.next(&base, 0).unwrap() < expected[0].0);
+    }
+
+    #[test]
+    fn out_of_range_positions() {
+        let sequencer = Sequencer::new(NonZeroU64::new(4).unwrap());
+        let clock = OrdinaryClock::default().update(std::iter::empty(), 0);
+        assert_eq!(sequencer.next(&clock, 3), Some(7));
+        assert_eq!(sequencer.next(&clock, 4), None);
+
+        // the largest position that fits, and the first that does not
+        let clock = OrdinaryClock([(0, u64::MAX / 4)].into());
+        assert_eq!(sequencer.next(&clock, 3), Some(u64::MAX));
+        let clock = OrdinaryClock([(0, u64::MAX / 4), (1, 1)].into());
+        assert_eq!(sequencer.next(&clock, 0), None);
+        // a scalar beyond u64, which `reduce` cannot sum
+        let clock = OrdinaryClock([(0, u64::MAX), (1, u64::MAX)].into());
+        assert_eq!(Sequencer::new(NonZeroU64::MIN).next(&clock, 0), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! Clock with 128-bit components, for counters that outgrow the 64-bit
+//! components of `OrdinaryClock`.
+
+use crate::ordinary_clock::{ClockKey, KeyId, OrdinaryClock};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// Counts left before `u64::MAX` below which `OrdinaryClock::should_promote`
+/// suggests promoting a clock to a `WideClock`.
+pub const PROMOTION_MARGIN: u64 = 1 << 32;
+
+/// An `OrdinaryClock` with `u128` components. Promote a clock with `From`
+/// once `OrdinaryClock::should_promote` says so, and keep updating the
+/// promoted clock instead. As with `OrdinaryClock`, zero components are
+/// treated as absent when comparing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(bound = "K: ClockKey")]
+pub struct WideClock<K = KeyId>(pub BTreeMap<K, u128>);
+
+impl<K: ClockKey> From<OrdinaryClock<K>> for WideClock<K> {
+    fn from(clock: OrdinaryClock<K>) -> Self {
+        Self(clock.0.into_iter().map(|(id, n)| (id, n.into())).collect())
+    }
+}
+
+impl<K: ClockKey> WideClock<K> {
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut merged = self.0.clone();
+        for (id, n) in &other.0 {
+            let own = merged.entry(id.clone()).or_default();
+            *own = (*own).max(*n);
+        }
+        Self(merged)
+    }
+
+    /// Same as `OrdinaryClock::update`.
+    pub fn update<'a>(&'a self, others: impl Iterator<Item = &'a Self>, id: K) -> Self {
+        let mut updated = others.fold(self.clone(), |clock, other| clock.merge(other));
+        *updated.0.entry(id).or_default() += 1;
+        updated
+    }
+}
+
+impl<K: Ord> PartialOrd for WideClock<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        fn ge<K: Ord>(clock: &WideClock<K>, other: &WideClock<K>) -> bool {
+            other
+                .0
+                .iter()
+                .all(|(id, n)| *n == 0 || clock.0.get(id).is_some_and(|own| own >= n))
+        }
+        match (ge(self, other), ge(other, self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Greater),
+            (false, true) => Some(Ordering::Less),
+            (false, false) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn promote_near_overflow() {
+        let clock = OrdinaryClock([(0, u64::MAX - 1), (1, 3)].into());
+        assert!(clock.should_promote());
+        assert!(!OrdinaryClock([(0, u64::MAX - PROMOTION_MARGIN)].into()).should_promote());
+
+        // the last count before overflow, after which updates fail
+        let full = clock.update(std::iter::empty(), 0);
+        assert_eq!(full.0[&0], u64::MAX);
+        assert!(matches!(
+            full.try_update(std::iter::empty(), 0),
+            Err(crate::VlcError::Overflow(_))
+        ));
+        assert!(std::panic::catch_unwind(|| full.update(std::iter::empty(), 0)).is_err());
+        assert!(std::panic::catch_unwind(|| full.clone().update_owned([], 0)).is_err());
+
+        let promoted = WideClock::from(clock.clone());
+        assert_eq!(promoted.0[&0], u64::MAX as u128 - 1);
+        let other = WideClock::from(OrdinaryClock([(1, 5)].into()));
+        let mut wide = promoted.update([&other].into_iter(), 0);
+        for _ in 0..2 {
+            wide = wide.update(std::iter::empty(), 0);
+        }
+        assert_eq!(wide.0, [(0, u64::MAX as u128 + 2), (1, 5)].into());
+        assert_eq!(wide.partial_cmp(&promoted), Some(Ordering::Greater));
+        assert_eq!(other.partial_cmp(&promoted), None);
+    }
+}
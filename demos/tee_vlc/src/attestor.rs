@@ -0,0 +1,132 @@
+use crate::pcr_policy::PcrPolicy;
+use serde::{Deserialize, Deserializer, Serialize};
+use types::raw_wrapper::Payload;
+
+/// A trusted execution environment that attests clocks, and verifies the
+/// attestations of its peers.
+pub trait Attestor: Send + Sync + 'static {
+    /// Produce an attestation document binding `user_data`.
+    fn attest(&self, user_data: Vec<u8>) -> anyhow::Result<Vec<u8>>;
+
+    /// Verify that `document` is a genuine attestation of
+    /// `expected_user_data`, produced by an image that satisfies `policy`.
+    fn verify(
+        &self,
+        document: &[u8],
+        expected_user_data: &[u8],
+        policy: &PcrPolicy,
+    ) -> anyhow::Result<()>;
+}
+
+/// An attestation document as carried by a clock. Documents are checked on
+/// construction and deserialization to look like a COSE_Sign1 structure, as
+/// Nitro documents are, so that garbage is rejected when it is received
+/// rather than when it is verified. The check is structural only, the
+/// signature is up to `Attestor::verify`.
+///
+/// The empty document stands for no document, as carried by the genesis
+/// clock.
+#[derive(Debug, Clone, Default, PartialEq, Eq, derive_more::Deref, Serialize)]
+pub struct AttestationDocument(Payload);
+
+impl AttestationDocument {
+    pub fn new(document: Vec<u8>) -> anyhow::Result<Self> {
+        if !document.is_empty() {
+            check_cose_sign1(&document)?
+        }
+        Ok(Self(Payload(document)))
+    }
+}
+
+impl<'de> Deserialize<'de> for AttestationDocument {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let Payload(document) = Payload::deserialize(deserializer)?;
+        Self::new(document).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Check the CBOR header of a COSE_Sign1 structure: an array of four items,
+/// optionally tagged, the first of which is the protected header as a byte
+/// string.
+fn check_cose_sign1(document: &[u8]) -> anyhow::Result<()> {
+    const COSE_SIGN1_TAG: u8 = 0xd2;
+    const ARRAY_OF_FOUR: u8 = 0x84;
+    const BYTE_STRING: u8 = 2;
+    let document = document.strip_prefix(&[COSE_SIGN1_TAG]).unwrap_or(document);
+    anyhow::ensure!(
+        document.first() == Some(&ARRAY_OF_FOUR),
+        "attestation document is not a COSE_Sign1 array"
+    );
+    anyhow::ensure!(
+        document.get(1).is_some_and(|b| b >> 5 == BYTE_STRING),
+        "attestation document has no protected header"
+    );
+    Ok(())
+}
+
+/// Parse a Nitro attestation document, validating its certificate chain up to
+/// the AWS root and checking that it carries `expected_user_data`.
+#[cfg(feature = "nitro-enclaves")]
+pub fn nitro_document(
+    document: &[u8],
+    expected_user_data: &[u8],
+) -> anyhow::Result<aws_nitro_enclaves_nsm_api::api::AttestationDoc> {
+    use aws_nitro_enclaves_attestation::{AttestationProcess as _, AWS_ROOT_CERT};
+    use aws_nitro_enclaves_nsm_api::api::AttestationDoc;
+    let document = AttestationDoc::from_bytes(
+        document,
+        AWS_ROOT_CERT,
+        std::time::SystemTime::UNIX_EPOCH
+            .elapsed()
+            .unwrap()
+            .as_secs(),
+    )?;
+    anyhow::ensure!(
+        document.user_data.as_ref().map(|user_data| &***user_data) == Some(expected_user_data)
+    );
+    Ok(document)
+}
+
+#[cfg(feature = "nitro-enclaves")]
+impl Attestor for enclaves::nitro_secure::NitroSecureModule {
+    fn attest(&self, user_data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        self.process_attestation(user_data)
+    }
+
+    fn verify(
+        &self,
+        document: &[u8],
+        expected_user_data: &[u8],
+        policy: &PcrPolicy,
+    ) -> anyhow::Result<()> {
+        policy.check(&nitro_document(document, expected_user_data)?.pcrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nitro_clock::NitroEnclavesClock;
+    use bincode::Options;
+    use vlc::ordinary_clock::OrdinaryClock;
+
+    #[test]
+    fn malformed_document_is_rejected_on_deserialize() -> anyhow::Result<()> {
+        let plain = OrdinaryClock::new().update([].iter(), 0);
+        let encode =
+            |document: &[u8]| bincode::options().serialize(&(&plain, Payload(document.to_vec())));
+        let decode = |buf: Vec<u8>| bincode::options().deserialize::<NitroEnclavesClock>(&buf);
+
+        let clock = decode(encode(&[0x84, 0x44, 0xa1, 0x01, 0x38, 0x22])?)?;
+        assert_eq!(clock.plain, plain);
+        decode(encode(&[0xd2, 0x84, 0x40])?)?;
+        // no document
+        decode(encode(&[])?)?;
+
+        for garbage in [&b"garbage"[..], &[0x84], &[0x84, 0xa0], &[0xd2, 0x83, 0x40]] {
+            anyhow::ensure!(decode(encode(garbage)?).is_err(), "{garbage:02x?} accepted");
+            anyhow::ensure!(AttestationDocument::new(garbage.to_vec()).is_err());
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,12 @@
-use std::{
-    env,
-    fmt::Write,
-    future::pending,
-    time::Duration,
-};
+use std::{env, fmt::Write, future::pending, num::NonZeroUsize, time::Duration};
 
-use vlc::ordinary_clock::OrdinaryClock;
 use tee_vlc::nitro_clock::{nitro_enclaves_portal_session, NitroEnclavesClock, Update, UpdateOk};
+use tee_vlc::portal_metrics::PortalMetrics;
 use tokio::{
     sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     time::{sleep, timeout, Instant},
 };
+use vlc::ordinary_clock::OrdinaryClock;
 
 // tee id
 const CID: u32 = 16;
@@ -21,11 +17,12 @@ async fn main() -> anyhow::Result<()> {
     let args: Vec<String> = env::args().collect();
 
     let num_concurrent = if args.len() > 1 {
-        args[1].parse::<usize>().ok()
+        Some(args[1].parse::<NonZeroUsize>()?)
     } else {
         None
     };
 
+    let metrics = PortalMetrics::default();
     let run_nitro_client = {
         let (update_sender, update_receiver) = unbounded_channel();
         let (update_ok_sender, mut update_ok_receiver) = unbounded_channel::<UpdateOk<_>>();
@@ -42,6 +39,8 @@ async fn main() -> anyhow::Result<()> {
                 5006,
                 update_receiver,
                 update_ok_sender,
+                metrics.clone(),
+                num_concurrent.unwrap_or(NonZeroUsize::MIN),
             )),
             tokio::spawn(async move {
                 let verify = |clock: NitroEnclavesClock| {
@@ -55,13 +54,19 @@ async fn main() -> anyhow::Result<()> {
                         stress_bench_session(
                             size,
                             0,
-                            num_concurrent,
+                            num_concurrent.get(),
                             &update_sender,
                             &mut update_ok_receiver,
                             &mut lines,
                         )
                         .await?;
                     }
+                    let total = metrics.metrics().total;
+                    println!(
+                        "in tee p50 {:?}, p99 {:?}",
+                        total.percentile(50.),
+                        total.percentile(99.)
+                    );
                     // println!("{lines}")
                 } else {
                     println!("key, num_merged, deserialize_tee, verify_proof_tee, update_clock_tee, gen_clock_proof_tee, total_in_tee, net_round");
@@ -132,7 +137,11 @@ where
 
     for _ in 0..5 {
         sleep(Duration::from_millis(100)).await;
-        let update = Update(clock.clone(), vec![clock.clone(); num_merged], INITIAL_ZERO_VALUE);
+        let update = Update(
+            clock.clone(),
+            vec![clock.clone(); num_merged],
+            INITIAL_ZERO_VALUE,
+        );
         let start = Instant::now();
         update_sender.send(update)?;
         let Some((_, clock, elapsed_in_tee)) = update_ok_receiver.recv().await else {
@@ -142,7 +151,12 @@ where
         // eprintln!("{size:8} {num_merged:3} {elapsed:?}");
         println!(
             "{size}, {num_merged}, {:?}, {:?}, {:?}, {:?}, {:?}, {:?}",
-            elapsed_in_tee[0], elapsed_in_tee[1], elapsed_in_tee[2], elapsed_in_tee[3], elapsed_in_tee[4], elapsed 
+            elapsed_in_tee[0],
+            elapsed_in_tee[1],
+            elapsed_in_tee[2],
+            elapsed_in_tee[3],
+            elapsed_in_tee[4],
+            elapsed
         );
         writeln!(lines, "{size},{num_merged},{}ms", elapsed.as_millis())?;
         verify(clock)?
@@ -186,10 +200,6 @@ where
         "key {size},merged {num_merged},counts {count}, tps {}",
         count as f32 / 10.
     );
-    writeln!(
-        lines,
-        "{size},{num_merged},{count},{}",
-        count as f32 / 10.
-    )?;
+    writeln!(lines, "{size},{num_merged},{count},{}", count as f32 / 10.)?;
     Ok(())
 }
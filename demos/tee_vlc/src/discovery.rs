@@ -0,0 +1,96 @@
+//! Discovery of the CID of a running enclave, which is assigned when the
+//! enclave is launched, so that callers of `try_connection` and
+//! `nitro_enclaves_portal_session` can find an enclave by name.
+
+use serde::Deserialize;
+
+/// A running enclave, as described by `nitro-cli describe-enclaves`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EnclaveDescription {
+    pub enclave_name: String,
+    #[serde(rename = "EnclaveCID")]
+    pub enclave_cid: u32,
+    pub state: String,
+}
+
+/// A source of the enclaves running on this host.
+pub trait EnclaveLookup {
+    fn describe_enclaves(&self) -> anyhow::Result<Vec<EnclaveDescription>>;
+}
+
+/// Lookup through the Nitro CLI, which must be on the path.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NitroCli;
+
+impl EnclaveLookup for NitroCli {
+    fn describe_enclaves(&self) -> anyhow::Result<Vec<EnclaveDescription>> {
+        let output = std::process::Command::new("nitro-cli")
+            .arg("describe-enclaves")
+            .output()?;
+        anyhow::ensure!(
+            output.status.success(),
+            "nitro-cli describe-enclaves failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        parse_descriptions(&output.stdout)
+    }
+}
+
+/// Parse the JSON array printed by `nitro-cli describe-enclaves`. Fields
+/// beyond the ones of `EnclaveDescription` are ignored.
+pub fn parse_descriptions(json: &[u8]) -> anyhow::Result<Vec<EnclaveDescription>> {
+    Ok(serde_json::from_slice(json)?)
+}
+
+/// The CID of the running enclave named `name`.
+pub fn enclave_cid(lookup: &impl EnclaveLookup, name: &str) -> anyhow::Result<u32> {
+    lookup
+        .describe_enclaves()?
+        .into_iter()
+        .find(|enclave| enclave.enclave_name == name && enclave.state == "RUNNING")
+        .map(|enclave| enclave.enclave_cid)
+        .ok_or_else(|| anyhow::anyhow!("no running enclave named {name:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(Vec<EnclaveDescription>);
+
+    impl EnclaveLookup for Fixed {
+        fn describe_enclaves(&self) -> anyhow::Result<Vec<EnclaveDescription>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn cid_of_named_enclave() -> anyhow::Result<()> {
+        let described = parse_descriptions(
+            br#"[
+                {
+                    "EnclaveName": "tee_vlc",
+                    "EnclaveID": "i-0000-enc0000",
+                    "ProcessID": 4242,
+                    "EnclaveCID": 16,
+                    "NumberOfCPUs": 2,
+                    "CPUIDs": [1, 3],
+                    "MemoryMiB": 512,
+                    "State": "RUNNING",
+                    "Flags": "NONE"
+                },
+                {
+                    "EnclaveName": "stale",
+                    "EnclaveCID": 17,
+                    "State": "TERMINATING"
+                }
+            ]"#,
+        )?;
+        let lookup = Fixed(described);
+        assert_eq!(enclave_cid(&lookup, "tee_vlc")?, 16);
+        anyhow::ensure!(enclave_cid(&lookup, "stale").is_err());
+        anyhow::ensure!(enclave_cid(&lookup, "unknown").is_err());
+        Ok(())
+    }
+}
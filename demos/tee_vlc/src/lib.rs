@@ -1,4 +1,9 @@
+pub mod attestor;
+#[cfg(feature = "nitro-enclaves")]
+pub mod discovery;
 pub mod nitro_clock;
+pub mod pcr_policy;
+pub mod portal_metrics;
 
 use serde::{Deserialize, Serialize};
 
@@ -10,4 +15,4 @@ pub struct Clocked<M, C> {
 
 pub trait Verify<S>: Send + Sync + 'static {
     fn verify_clock(&self, num_faulty: usize, state: &S) -> anyhow::Result<()>;
-}
\ No newline at end of file
+}
@@ -3,5 +3,6 @@ use tee_vlc::nitro_clock::NitroEnclavesClock;
 #[tokio::main]
 #[cfg(feature = "nitro-enclaves")]
 async fn main() -> anyhow::Result<()> {
-    NitroEnclavesClock::run(5006).await
-}
\ No newline at end of file
+    let concurrency = std::thread::available_parallelism()?;
+    NitroEnclavesClock::run(5006, concurrency).await
+}
@@ -1,13 +1,27 @@
-use std::{sync::Arc, time::Duration};
+use crate::attestor::{AttestationDocument, Attestor};
+use crate::pcr_policy::PcrPolicy;
+use crate::portal_metrics::PortalMetrics;
 use bincode::Options;
-use types::raw_wrapper::Payload;
 use crypto::core::DigestHash;
-use enclaves::nitro_secure::{HandleFn, NitroSecureModule as NitroSecure};
-use vlc::ordinary_clock::{Clock, LamportClock, OrdinaryClock};
 use derive_where::derive_where;
+use enclaves::nitro_secure::{handshake, HandleFn, NitroSecureModule as NitroSecure};
 use serde::{Deserialize, Serialize};
-use tokio::{sync::mpsc::{UnboundedReceiver, UnboundedSender}, time::Instant};
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        Semaphore,
+    },
+    time::Instant,
+};
 use tracing::*;
+use types::retry::RetryPolicy;
+use vlc::ordinary_clock::{Clock, LamportClock, OrdinaryClock};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Update<C>(pub C, pub Vec<C>, pub u64);
@@ -15,13 +29,200 @@ pub struct Update<C>(pub C, pub Vec<C>, pub u64);
 // feel lazy to define event type for replying
 pub type UpdateOk<C> = (u64, C, Vec<Duration>);
 
+/// The worker's answer to an update frame. Every frame is answered, so the
+/// portal can free the frame's outstanding slot either way.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum UpdateReply<C> {
+    Ok(UpdateOk<C>),
+    /// The frame was rejected, e.g. as a replay or because an input clock
+    /// failed to verify, for the given reason.
+    Rejected(String),
+}
+
+/// What the worker did for one update, for audit logging on the host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateAudit {
+    pub id: u64,
+    /// Hashes of the previous and the merged clocks, in that order.
+    pub input_hashes: Vec<[u8; 32]>,
+    /// Hash of the updated clock, which its attestation covers.
+    pub output_hash: [u8; 32],
+    /// The timers of the reply.
+    pub timings: Vec<Duration>,
+}
+
+/// Receives an `UpdateAudit` for every update the worker replies to.
+pub type AuditSink = Arc<dyn Fn(UpdateAudit) + Send + Sync>;
+
+/// What the worker is asked to do.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request<C> {
+    /// Update a clock, answered with an `UpdateOk`.
+    Update(Update<C>),
+    /// Attest `challenge`, answered with a `HealthReply`, see
+    /// `NitroEnclavesClock::probe`.
+    Health { challenge: [u8; 32] },
+}
+
+/// The frame actually sent over vsock: a `Request` stamped by the portal with
+/// a random per-connection session id and a nonce increasing from 1, so the
+/// worker can reject replayed frames.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NoncedUpdate<C> {
+    pub session: u64,
+    pub nonce: u64,
+    pub request: Request<C>,
+}
+
+/// The worker's answer to a health request.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HealthReply {
+    /// The attestation document of the challenge.
+    Attested(Vec<u8>),
+    /// The worker failed to attest.
+    Failed(String),
+}
+
+/// Health of an enclave worker, as seen by `NitroEnclavesClock::probe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthStatus {
+    /// The worker attested the challenge with a valid document.
+    Healthy,
+    /// The worker attested, but the document did not verify.
+    InvalidDocument(String),
+    /// The worker reported that it failed to attest.
+    WorkerError(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    /// Time from sending the request to receiving the reply.
+    pub round_trip: Duration,
+    pub status: HealthStatus,
+}
+
+impl HealthReport {
+    /// Whether the worker is fit to receive updates.
+    pub fn is_ready(&self) -> bool {
+        self.status == HealthStatus::Healthy
+    }
+}
+
+/// Number of nonces below the highest seen one that are still accepted, to
+/// tolerate frames handled slightly out of order.
+const REPLAY_WINDOW: u64 = 64;
+
+/// Number of sessions a `ReplayGuard` tracks by default. Every connection
+/// and health probe opens a session, so the least recently used ones are
+/// evicted beyond this.
+const MAX_REPLAY_SESSIONS: usize = 4096;
+
+/// Sliding window of accepted nonces for one session.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: u64,
+    // bit i set means nonce `highest - i` has been accepted
+    seen: u64,
+    // tick of the guard when the session was last checked
+    last_used: u64,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, nonce: u64) -> bool {
+        if nonce > self.highest {
+            let shift = nonce - self.highest;
+            self.seen = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.highest = nonce;
+            return true;
+        }
+        let offset = self.highest - nonce;
+        if nonce == 0 || offset >= REPLAY_WINDOW || self.seen & (1 << offset) != 0 {
+            return false;
+        }
+        self.seen |= 1 << offset;
+        true
+    }
+}
+
+/// Worker-side replay protection, tracking a nonce window per session for
+/// a bounded number of sessions.
+#[derive(Debug)]
+pub struct ReplayGuard(Mutex<ReplaySessions>);
+
+#[derive(Debug)]
+struct ReplaySessions {
+    windows: HashMap<u64, ReplayWindow>,
+    capacity: usize,
+    tick: u64,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::with_capacity(NonZeroUsize::new(MAX_REPLAY_SESSIONS).unwrap())
+    }
+}
+
+impl ReplayGuard {
+    /// A guard tracking at most `capacity` sessions. A new
+    /// session beyond that evicts the least recently used one, which starts
+    /// over with a fresh window if it is seen again, so `capacity` should
+    /// well exceed the number of concurrent connections.
+    pub fn with_capacity(capacity: NonZeroUsize) -> Self {
+        Self(Mutex::new(ReplaySessions {
+            windows: HashMap::new(),
+            capacity: capacity.get(),
+            tick: 0,
+        }))
+    }
+
+    /// Accept a nonce for a session, rejecting duplicates and nonces too far
+    /// behind the highest one seen in that session.
+    pub fn check(&self, session: u64, nonce: u64) -> anyhow::Result<()> {
+        let mut sessions = self.0.lock().unwrap();
+        let sessions = &mut *sessions;
+        sessions.tick += 1;
+        if sessions.windows.len() >= sessions.capacity && !sessions.windows.contains_key(&session) {
+            let idlest = sessions
+                .windows
+                .iter()
+                .min_by_key(|(_, window)| window.last_used)
+                .map(|(session, _)| *session);
+            if let Some(idlest) = idlest {
+                sessions.windows.remove(&idlest);
+            }
+        }
+        let window = sessions.windows.entry(session).or_default();
+        window.last_used = sessions.tick;
+        anyhow::ensure!(
+            window.accept(nonce),
+            "replayed or stale nonce {nonce} in session {session}"
+        );
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Default, derive_more::AsRef, Serialize, Deserialize)]
 #[derive_where(PartialOrd, PartialEq)]
 pub struct NitroEnclavesClock {
     #[as_ref]
     pub plain: OrdinaryClock,
     #[derive_where(skip)]
-    pub document: Payload,
+    pub document: AttestationDocument,
+}
+
+impl NitroEnclavesClock {
+    /// The genesis clock, which carries no attestation document.
+    pub fn genesis() -> Self {
+        Self {
+            plain: OrdinaryClock::default(),
+            document: Default::default(),
+        }
+    }
 }
 
 impl TryFrom<OrdinaryClock> for NitroEnclavesClock {
@@ -42,42 +243,84 @@ impl Clock for NitroEnclavesClock {
     }
 }
 
-// technically `feature = "aws-nitro-enclaves-attestation"` is sufficient for
-// attestation, NSM API is only depended by `NitroSecureModule` that running
-// inside enclaves image
-#[cfg(feature = "nitro-enclaves")]
 impl NitroEnclavesClock {
-    pub fn verify(
+    /// Verify the clock's attestation with `attestor`. The genesis clock
+    /// carries no attestation and always verifies.
+    pub fn verify_by<A: Attestor + ?Sized>(
         &self,
-    ) -> anyhow::Result<Option<aws_nitro_enclaves_nsm_api::api::AttestationDoc>> {
+        attestor: &A,
+        policy: &PcrPolicy,
+    ) -> anyhow::Result<()> {
         if self.plain.is_genesis() {
-            return Ok(None);
+            return Ok(());
         }
-        use aws_nitro_enclaves_attestation::{AttestationProcess as _, AWS_ROOT_CERT};
-        use aws_nitro_enclaves_nsm_api::api::AttestationDoc;
-        let document = AttestationDoc::from_bytes(
-            &self.document,
-            AWS_ROOT_CERT,
-            std::time::SystemTime::UNIX_EPOCH
-                .elapsed()
-                .unwrap()
-                .as_secs(),
-        )?;
-        use DigestHash as _;
-        anyhow::ensure!(
-            document.user_data.as_ref().map(|user_data| &***user_data)
-                == Some(&self.plain.sha256().to_fixed_bytes()[..])
-        );
-        Ok(Some(document))
+        let user_data = self.plain.sha256().to_fixed_bytes();
+        attestor.verify(&self.document, &user_data, policy)
     }
 
-    pub fn worker() -> HandleFn {
-        Arc::new(|buf, nsm, pcrs, write_sender| {
+    /// Check that the enclave worker behind `stream` is alive and attests
+    /// correctly, before routing updates to it: the worker attests a random
+    /// challenge, whose document is verified with `attestor`. Fails only if
+    /// the worker cannot be talked to; wrap it in a timeout to also catch a
+    /// worker that does not answer.
+    pub async fn probe<A: Attestor + ?Sized>(
+        stream: tokio::net::UnixStream,
+        attestor: &A,
+        policy: &PcrPolicy,
+    ) -> anyhow::Result<HealthReport> {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let (mut read_half, mut write_half) = stream.into_split();
+        handshake(&mut read_half, &mut write_half).await?;
+        let challenge: [u8; 32] = rand::random();
+        let buf = bincode::options().serialize(&NoncedUpdate::<Self> {
+            session: rand::random(),
+            nonce: 1,
+            request: Request::Health { challenge },
+        })?;
+        let start = Instant::now();
+        write_half.write_u64_le(buf.len() as _).await?;
+        write_half.write_all(&buf).await?;
+        let len = read_half.read_u64_le().await?;
+        let mut buf = vec![0; len as _];
+        read_half.read_exact(&mut buf).await?;
+        let round_trip = start.elapsed();
+        let status = match bincode::options().deserialize(&buf)? {
+            HealthReply::Attested(document) => {
+                match attestor.verify(&document, &challenge, policy) {
+                    Ok(()) => HealthStatus::Healthy,
+                    Err(err) => HealthStatus::InvalidDocument(err.to_string()),
+                }
+            }
+            HealthReply::Failed(err) => HealthStatus::WorkerError(err),
+        };
+        Ok(HealthReport { round_trip, status })
+    }
+
+    /// The worker serving updates, with the attestor `attestor` picks for the
+    /// secure module of the enclave.
+    pub fn worker_with<A: Attestor>(
+        attestor: impl Fn(Arc<NitroSecure>) -> Arc<A> + Send + Sync + 'static,
+    ) -> HandleFn {
+        Self::worker_audited(attestor, None)
+    }
+
+    /// `worker_with`, also passing an `UpdateAudit` of every update it
+    /// replies to to `audit`.
+    pub fn worker_audited<A: Attestor>(
+        attestor: impl Fn(Arc<NitroSecure>) -> Arc<A> + Send + Sync + 'static,
+        audit: Option<AuditSink>,
+    ) -> HandleFn {
+        let replay = Arc::new(ReplayGuard::default());
+        Arc::new(move |buf, nsm, pcrs, write_sender| {
+            let replay = replay.clone();
+            let attestor = attestor(nsm);
+            let audit = audit.clone();
             Box::pin(async move {
                 // IO action in tee is severe delay, just debug
                 // println!("Received buffer: {:?}", buf);
                 // let _ = io::stdout().flush();
-               
+
                 // if production env, need to remove time slot log
                 let mut timers = Vec::new();
                 if let Err(err) = async {
@@ -86,46 +329,64 @@ impl NitroEnclavesClock {
 
                     // 1. decode time
                     let start = Instant::now();
-                    let Update(prev, merged, id) = bincode::options()
-                        .deserialize::<Update<NitroEnclavesClock>>(&buf)?;                  
-                    
+                    let NoncedUpdate {
+                        session,
+                        nonce,
+                        request,
+                    } = bincode::options().deserialize::<NoncedUpdate<NitroEnclavesClock>>(&buf)?;
+                    replay.check(session, nonce)?;
+                    let Update(prev, merged, id) = match request {
+                        Request::Update(update) => update,
+                        Request::Health { challenge } => {
+                            let reply = match attestor.attest(challenge.to_vec()) {
+                                Ok(document) => HealthReply::Attested(document),
+                                Err(err) => HealthReply::Failed(err.to_string()),
+                            };
+                            write_sender.send(bincode::options().serialize(&reply)?)?;
+                            return anyhow::Ok(());
+                        }
+                    };
+
                     let elapsed = start.elapsed();
                     timers.push(elapsed);
-                    
+
                     // 2. verify clocks time
                     let start = Instant::now();
+                    // clocks must be attested by enclaves running this same image
+                    let policy = PcrPolicy::from(&*pcrs);
                     for clock in [&prev].into_iter().chain(&merged) {
-                        if let Some(document) = clock.verify()? {
-                            for (i, pcr) in pcrs.iter().enumerate() {
-                                anyhow::ensure!(
-                                    document.pcrs.get(&i).map(|pcr| &**pcr) == Some(pcr),
-                                    "PCR value mismatch at index {}", i
-                                )
-                            }
-                        }
+                        clock.verify_by(&*attestor, &policy)?;
                     }
 
                     let elapsed = start.elapsed();
                     timers.push(elapsed);
 
+                    let input_hashes: Option<Vec<_>> = audit.is_some().then(|| {
+                        [&prev]
+                            .into_iter()
+                            .chain(&merged)
+                            .map(|clock| clock.plain.sha256().to_fixed_bytes())
+                            .collect()
+                    });
+
                     // 3. update clock time
                     let start = Instant::now();
                     let plain = prev
                         .plain
                         .update(merged.iter().map(|clock| &clock.plain), id);
-                    
+
                     let elapsed = start.elapsed();
                     timers.push(elapsed);
-                    
+
                     // 4. gen clock with proof time
                     let start = Instant::now();
                     // relies on the fact that different clocks always hash into different
                     // digests, hopefully true
-                    let user_data = plain.sha256().to_fixed_bytes().to_vec();
-                    let document = nsm.process_attestation(user_data)?;
+                    let output_hash = plain.sha256().to_fixed_bytes();
+                    let document = attestor.attest(output_hash.to_vec())?;
                     let updated = NitroEnclavesClock {
                         plain,
-                        document: Payload(document),
+                        document: AttestationDocument::new(document)?,
                     };
 
                     let elapsed = start.elapsed();
@@ -133,33 +394,95 @@ impl NitroEnclavesClock {
 
                     let elapsed = full_start.elapsed();
                     timers.push(elapsed);
-                    
-                    let buf = bincode::options().serialize(&(id, updated, timers))?;
-                    write_sender.send(buf)?;
-                    Ok(())
+
+                    let reply = UpdateReply::Ok((id, updated, timers.clone()));
+                    write_sender.send(bincode::options().serialize(&reply)?)?;
+                    if let (Some(audit), Some(input_hashes)) = (&audit, input_hashes) {
+                        audit(UpdateAudit {
+                            id,
+                            input_hashes,
+                            output_hash,
+                            timings: timers,
+                        });
+                    }
+                    anyhow::Ok(())
                 }
                 .await
                 {
-                    warn!("{err}")
+                    warn!("{err}");
+                    let reply = UpdateReply::<NitroEnclavesClock>::Rejected(err.to_string());
+                    // the portal is gone if this fails too
+                    if let Ok(buf) = bincode::options().serialize(&reply) {
+                        let _ = write_sender.send(buf);
+                    }
                 }
                 Ok(())
             })
         })
     }
+}
 
-    pub async fn run(port: u32) -> anyhow::Result<()> {
+// technically `feature = "aws-nitro-enclaves-attestation"` is sufficient for
+// attestation, NSM API is only depended by `NitroSecureModule` that running
+// inside enclaves image
+#[cfg(feature = "nitro-enclaves")]
+impl NitroEnclavesClock {
+    pub fn verify(
+        &self,
+    ) -> anyhow::Result<Option<aws_nitro_enclaves_nsm_api::api::AttestationDoc>> {
+        if self.plain.is_genesis() {
+            return Ok(None);
+        }
+        use DigestHash as _;
+        let user_data = self.plain.sha256().to_fixed_bytes();
+        crate::attestor::nitro_document(&self.document, &user_data).map(Some)
+    }
+
+    /// `verify`, additionally checking the document's PCRs against `policy`.
+    pub fn verify_with(
+        &self,
+        policy: &PcrPolicy,
+    ) -> anyhow::Result<Option<aws_nitro_enclaves_nsm_api::api::AttestationDoc>> {
+        let document = self.verify()?;
+        if let Some(document) = &document {
+            policy.check(&document.pcrs)?
+        }
+        Ok(document)
+    }
+
+    pub fn worker() -> HandleFn {
+        Self::worker_with(|nsm| nsm)
+    }
+
+    /// Serve clock updates on vsock `port`, attesting up to `concurrency`
+    /// updates of a connection in parallel.
+    pub async fn run(port: u32, concurrency: NonZeroUsize) -> anyhow::Result<()> {
         let handler: HandleFn = NitroEnclavesClock::worker();
 
-        NitroSecure::run(port, handler).await
+        NitroSecure::run(port, concurrency, handler).await
     }
 }
 
+/// Slots for updates sent to the enclave but not replied yet. A portal
+/// session takes one before sending an update, waiting while all
+/// `max_outstanding` are taken, and frees one on every reply, including the
+/// `UpdateReply::Rejected` of an update the enclave rejects.
+fn outstanding_slots(max_outstanding: NonZeroUsize) -> anyhow::Result<Arc<Semaphore>> {
+    anyhow::ensure!(
+        max_outstanding.get() <= Semaphore::MAX_PERMITS,
+        "at most {} outstanding updates are supported, got {max_outstanding}",
+        Semaphore::MAX_PERMITS
+    );
+    Ok(Arc::new(Semaphore::new(max_outstanding.get())))
+}
 
 pub async fn nitro_enclaves_portal_session(
     cid: u32,
     port: u32,
     mut events: UnboundedReceiver<Update<NitroEnclavesClock>>,
     sender: UnboundedSender<UpdateOk<NitroEnclavesClock>>,
+    metrics: PortalMetrics,
+    max_outstanding: NonZeroUsize,
 ) -> anyhow::Result<()> {
     use std::os::fd::AsRawFd;
 
@@ -182,9 +505,20 @@ pub async fn nitro_enclaves_portal_session(
     stream.set_nonblocking(true)?;
     let stream = tokio::net::UnixStream::from_std(stream)?;
     let (mut read_half, mut write_half) = stream.into_split();
+    handshake(&mut read_half, &mut write_half).await?;
+    let outstanding = outstanding_slots(max_outstanding)?;
+    let replied = outstanding.clone();
     let write_session = tokio::spawn(async move {
+        let session = rand::random();
+        let mut nonce = 0;
         while let Some(update) = events.recv().await {
-            let buf = bincode::options().serialize(&update)?;
+            outstanding.acquire().await?.forget();
+            nonce += 1;
+            let buf = bincode::options().serialize(&NoncedUpdate {
+                session,
+                nonce,
+                request: Request::Update(update),
+            })?;
             write_half.write_u64_le(buf.len() as _).await?;
             write_half.write_all(&buf).await?
         }
@@ -195,7 +529,16 @@ pub async fn nitro_enclaves_portal_session(
             let len = read_half.read_u64_le().await?;
             let mut buf = vec![0; len as _];
             read_half.read_exact(&mut buf).await?;
-            sender.send(bincode::options().deserialize(&buf)?)?
+            replied.add_permits(1);
+            match bincode::options().deserialize(&buf)? {
+                UpdateReply::Ok(update_ok) => {
+                    metrics.record(&update_ok.2);
+                    sender.send(update_ok)?
+                }
+                UpdateReply::<NitroEnclavesClock>::Rejected(err) => {
+                    warn!("update rejected by the enclave: {err}")
+                }
+            }
         }
         #[allow(unreachable_code)] // for type hinting
         anyhow::Ok(())
@@ -244,18 +587,75 @@ pub fn try_connection(cid: u32, port: u32) -> anyhow::Result<tokio::net::UnixStr
     Ok(stream)
 }
 
+/// `try_connection`, retried up to `attempts` times in total while the
+/// enclave is not reachable yet, e.g. when it is still booting as the host
+/// starts. The delay before the n-th retry is jittered between half and all
+/// of `base_delay * 2^n`.
+pub async fn connect_with_retry(
+    cid: u32,
+    port: u32,
+    attempts: usize,
+    base_delay: Duration,
+) -> anyhow::Result<tokio::net::UnixStream> {
+    let policy = RetryPolicy::new(base_delay, Duration::MAX, attempts, 0.5)?;
+    connect_with_policy(cid, port, &policy).await
+}
+
+/// `try_connection`, retried according to `policy`.
+pub async fn connect_with_policy(
+    cid: u32,
+    port: u32,
+    policy: &RetryPolicy,
+) -> anyhow::Result<tokio::net::UnixStream> {
+    retry_with_jitter(policy, || try_connection(cid, port)).await
+}
+
+async fn retry_with_jitter<T>(
+    policy: &RetryPolicy,
+    mut connect: impl FnMut() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    use rand::Rng as _;
+
+    let mut attempt = 1;
+    loop {
+        let err = match connect() {
+            Ok(connection) => return Ok(connection),
+            Err(err) => err,
+        };
+        let Some(delay) = policy.delay(attempt - 1, rand::thread_rng().gen()) else {
+            return Err(err.context(format!("connect failed after {attempt} attempts")));
+        };
+        debug!("connect attempt {attempt} failed: {err}");
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
 pub async fn tee_start_listening(
     stream: tokio::net::UnixStream,
     mut events: UnboundedReceiver<Update<NitroEnclavesClock>>,
     sender: UnboundedSender<UpdateOk<NitroEnclavesClock>>,
+    metrics: PortalMetrics,
+    max_outstanding: NonZeroUsize,
 ) -> anyhow::Result<()> {
     use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
 
     let (mut read_half, mut write_half) = stream.into_split();
+    handshake(&mut read_half, &mut write_half).await?;
+    let outstanding = outstanding_slots(max_outstanding)?;
+    let replied = outstanding.clone();
 
     let write_session = tokio::spawn(async move {
-        while let Some(prompt) = events.recv().await {
-            let buf = bincode::options().serialize(&prompt)?;
+        let session = rand::random();
+        let mut nonce = 0;
+        while let Some(update) = events.recv().await {
+            outstanding.acquire().await?.forget();
+            nonce += 1;
+            let buf = bincode::options().serialize(&NoncedUpdate {
+                session,
+                nonce,
+                request: Request::Update(update),
+            })?;
             write_half.write_u64_le(buf.len() as _).await?;
             write_half.write_all(&buf).await?;
         }
@@ -267,7 +667,16 @@ pub async fn tee_start_listening(
             let len = read_half.read_u64_le().await?;
             let mut buf = vec![0; len as _];
             read_half.read_exact(&mut buf).await?;
-            sender.send(bincode::options().deserialize(&buf)?)?
+            replied.add_permits(1);
+            match bincode::options().deserialize(&buf)? {
+                UpdateReply::Ok(update_ok) => {
+                    metrics.record(&update_ok.2);
+                    sender.send(update_ok)?
+                }
+                UpdateReply::<NitroEnclavesClock>::Rejected(err) => {
+                    warn!("update rejected by the enclave: {err}")
+                }
+            }
         }
         #[allow(unreachable_code)] // for type hinting
         anyhow::Ok(())
@@ -280,3 +689,368 @@ pub async fn tee_start_listening(
 
     anyhow::bail!("unreachable")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replayed_frame_is_rejected() -> anyhow::Result<()> {
+        let frame = |nonce| {
+            bincode::options().serialize(&NoncedUpdate {
+                session: 7,
+                nonce,
+                request: Request::Update(Update(NitroEnclavesClock::default(), Vec::new(), 0)),
+            })
+        };
+        let first = frame(1)?;
+        let second = frame(2)?;
+
+        let guard = ReplayGuard::default();
+        let accept = |buf: &[u8]| {
+            let NoncedUpdate { session, nonce, .. } =
+                bincode::options().deserialize::<NoncedUpdate<NitroEnclavesClock>>(buf)?;
+            guard.check(session, nonce)
+        };
+        accept(&first)?;
+        anyhow::ensure!(accept(&first).is_err(), "replay accepted");
+        accept(&second)?;
+        anyhow::ensure!(accept(&second).is_err(), "replay accepted");
+        Ok(())
+    }
+
+    #[test]
+    fn replay_window() -> anyhow::Result<()> {
+        let guard = ReplayGuard::default();
+        // out of order within the window is fine, once
+        guard.check(1, 5)?;
+        guard.check(1, 3)?;
+        anyhow::ensure!(guard.check(1, 3).is_err());
+        // sessions are independent
+        guard.check(2, 3)?;
+        // too far behind the highest nonce
+        guard.check(1, 5 + REPLAY_WINDOW)?;
+        anyhow::ensure!(guard.check(1, 4).is_err());
+        anyhow::ensure!(guard.check(1, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn replay_sessions_are_bounded() -> anyhow::Result<()> {
+        let guard = ReplayGuard::with_capacity(NonZeroUsize::new(2).unwrap());
+        guard.check(1, 1)?;
+        guard.check(2, 1)?;
+        guard.check(1, 2)?;
+        // a third session evicts the least recently used one, 2
+        guard.check(3, 1)?;
+        for session in 4..100 {
+            guard.check(session, 1)?;
+            guard.check(1, session)?;
+        }
+        anyhow::ensure!(guard.0.lock().unwrap().windows.len() == 2);
+        // the session kept in use still rejects replays
+        anyhow::ensure!(guard.check(1, 99).is_err());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn outstanding_updates_are_capped() -> anyhow::Result<()> {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        use tokio::net::unix::OwnedReadHalf;
+        use tokio::sync::mpsc::unbounded_channel;
+        use tokio::time::timeout;
+
+        async fn read_update(read_half: &mut OwnedReadHalf) -> anyhow::Result<u64> {
+            let len = read_half.read_u64_le().await?;
+            let mut buf = vec![0; len as _];
+            read_half.read_exact(&mut buf).await?;
+            let request = bincode::options()
+                .deserialize::<NoncedUpdate<NitroEnclavesClock>>(&buf)?
+                .request;
+            let Request::Update(update) = request else {
+                anyhow::bail!("expected an update")
+            };
+            Ok(update.2)
+        }
+
+        let (portal, enclave) = tokio::net::UnixStream::pair()?;
+        let (update_sender, events) = unbounded_channel();
+        let (sender, mut replies) = unbounded_channel();
+        let session = tokio::spawn(tee_start_listening(
+            portal,
+            events,
+            sender,
+            PortalMetrics::default(),
+            NonZeroUsize::new(2).unwrap(),
+        ));
+        let (mut read_half, mut write_half) = enclave.into_split();
+        handshake(&mut read_half, &mut write_half).await?;
+        for id in 0..3 {
+            update_sender.send(Update(NitroEnclavesClock::default(), Vec::new(), id))?;
+        }
+
+        let wait = Duration::from_millis(100);
+        assert_eq!(timeout(wait, read_update(&mut read_half)).await??, 0);
+        assert_eq!(timeout(wait, read_update(&mut read_half)).await??, 1);
+        // both slots are taken
+        anyhow::ensure!(timeout(wait, read_half.read_u8()).await.is_err());
+
+        let reply = UpdateReply::<NitroEnclavesClock>::Ok((0, Default::default(), Vec::new()));
+        let reply = bincode::options().serialize(&reply)?;
+        write_half.write_u64_le(reply.len() as _).await?;
+        write_half.write_all(&reply).await?;
+        anyhow::ensure!(replies.recv().await.is_some_and(|(id, ..)| id == 0));
+        assert_eq!(timeout(wait, read_update(&mut read_half)).await??, 2);
+
+        // a rejection frees its slot too, without a reply to the host
+        update_sender.send(Update(NitroEnclavesClock::default(), Vec::new(), 3))?;
+        anyhow::ensure!(timeout(wait, read_half.read_u8()).await.is_err());
+        let reply = UpdateReply::<NitroEnclavesClock>::Rejected("replayed".into());
+        let reply = bincode::options().serialize(&reply)?;
+        write_half.write_u64_le(reply.len() as _).await?;
+        write_half.write_all(&reply).await?;
+        assert_eq!(timeout(wait, read_update(&mut read_half)).await??, 3);
+        anyhow::ensure!(replies.try_recv().is_err());
+
+        session.abort();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn connect_is_retried() -> anyhow::Result<()> {
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let path = std::env::temp_dir().join(format!("{}-retry.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut listener = None;
+        let mut attempts = 0;
+        let connect = || {
+            attempts += 1;
+            let result = UnixStream::connect(&path);
+            // the enclave comes up right after the first attempt
+            if result.is_err() && listener.is_none() {
+                listener = Some(UnixListener::bind(&path)?)
+            }
+            anyhow::Ok(result?)
+        };
+        let policy = |max_attempts| {
+            RetryPolicy::new(
+                Duration::from_millis(1),
+                Duration::from_secs(10),
+                max_attempts,
+                0.5,
+            )
+            .unwrap()
+        };
+        retry_with_jitter(&policy(3), connect).await?;
+        assert_eq!(attempts, 2);
+
+        std::fs::remove_file(&path)?;
+        let result =
+            retry_with_jitter(&policy(2), || anyhow::Ok(UnixStream::connect(&path)?)).await;
+        anyhow::ensure!(result.is_err());
+        Ok(())
+    }
+
+    /// Attests by prefixing the user data with a tag, after the header of an
+    /// empty COSE_Sign1 structure.
+    struct StubAttestor;
+
+    const STUB_TAG: &[u8] = &[0x84, 0x40, b's', b't', b'u', b'b', b':'];
+
+    impl Attestor for StubAttestor {
+        fn attest(&self, user_data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            Ok([STUB_TAG, &user_data].concat())
+        }
+
+        fn verify(
+            &self,
+            document: &[u8],
+            expected_user_data: &[u8],
+            _: &PcrPolicy,
+        ) -> anyhow::Result<()> {
+            anyhow::ensure!(
+                document.strip_prefix(STUB_TAG) == Some(expected_user_data),
+                "not a stub attestation"
+            );
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn worker_attests_through_attestor() -> anyhow::Result<()> {
+        use tokio::sync::mpsc::unbounded_channel;
+
+        let worker = NitroEnclavesClock::worker_with(|_| Arc::new(StubAttestor));
+        let nsm = Arc::new(NitroSecure(-1));
+        let pcrs = Arc::new([Vec::new(), Vec::new(), Vec::new()]);
+        let (write_sender, mut replies) = unbounded_channel();
+        let mut nonce = 0;
+        let mut update = |update| {
+            nonce += 1;
+            let buf = bincode::options().serialize(&NoncedUpdate {
+                session: 1,
+                nonce,
+                request: Request::Update(update),
+            })?;
+            anyhow::Ok(worker(buf, nsm.clone(), pcrs.clone(), write_sender.clone()))
+        };
+
+        let mut reply = || {
+            anyhow::Ok(
+                bincode::options()
+                    .deserialize::<UpdateReply<NitroEnclavesClock>>(&replies.try_recv()?)?,
+            )
+        };
+
+        update(Update(NitroEnclavesClock::genesis(), Vec::new(), 0))?.await?;
+        let UpdateReply::Ok((_, clock, timers)) = reply()? else {
+            anyhow::bail!("update rejected")
+        };
+        assert_eq!(timers.len(), 5);
+        clock.verify_by(&StubAttestor, &PcrPolicy::default())?;
+
+        // the attested clock is accepted as input of the next update
+        update(Update(clock.clone(), Vec::new(), 1))?.await?;
+        let UpdateReply::Ok((_, next, _)) = reply()? else {
+            anyhow::bail!("update rejected")
+        };
+        anyhow::ensure!(next > clock);
+
+        // and a forged one is not
+        let forged = NitroEnclavesClock {
+            document: AttestationDocument::new(StubAttestor.attest(b"forged".to_vec())?)?,
+            ..next
+        };
+        update(Update(forged, Vec::new(), 0))?.await?;
+        anyhow::ensure!(
+            matches!(reply()?, UpdateReply::Rejected(_)),
+            "forged clock updated"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn worker_audits_updates() -> anyhow::Result<()> {
+        use tokio::sync::mpsc::unbounded_channel;
+
+        let audits = Arc::new(Mutex::new(Vec::new()));
+        let sink = audits.clone();
+        let audit: AuditSink = Arc::new(move |audit| sink.lock().unwrap().push(audit));
+        let worker = NitroEnclavesClock::worker_audited(|_| Arc::new(StubAttestor), Some(audit));
+        let nsm = Arc::new(NitroSecure(-1));
+        let pcrs = Arc::new([Vec::new(), Vec::new(), Vec::new()]);
+        let (write_sender, mut replies) = unbounded_channel();
+        let mut nonce = 0;
+        let mut update = |update| {
+            nonce += 1;
+            let buf = bincode::options().serialize(&NoncedUpdate {
+                session: 1,
+                nonce,
+                request: Request::Update(update),
+            })?;
+            anyhow::Ok(worker(buf, nsm.clone(), pcrs.clone(), write_sender.clone()))
+        };
+        let hash = |clock: &NitroEnclavesClock| clock.plain.sha256().to_fixed_bytes();
+
+        let mut reply = || {
+            anyhow::Ok(
+                bincode::options()
+                    .deserialize::<UpdateReply<NitroEnclavesClock>>(&replies.try_recv()?)?,
+            )
+        };
+
+        let genesis = NitroEnclavesClock::genesis();
+        update(Update(genesis.clone(), Vec::new(), 0))?.await?;
+        let UpdateReply::Ok((_, first, _)) = reply()? else {
+            anyhow::bail!("update rejected")
+        };
+        update(Update(first.clone(), vec![genesis.clone()], 1))?.await?;
+        let UpdateReply::Ok((_, second, timers)) = reply()? else {
+            anyhow::bail!("update rejected")
+        };
+        // a rejected update is answered, but not audited
+        let forged = NitroEnclavesClock {
+            document: AttestationDocument::new(StubAttestor.attest(b"forged".to_vec())?)?,
+            ..second.clone()
+        };
+        update(Update(forged, Vec::new(), 2))?.await?;
+        anyhow::ensure!(matches!(reply()?, UpdateReply::Rejected(_)));
+
+        let audits = audits.lock().unwrap();
+        assert_eq!(audits.len(), 2);
+        assert_eq!(audits[0].id, 0);
+        assert_eq!(audits[0].input_hashes, [hash(&genesis)]);
+        assert_eq!(audits[0].output_hash, hash(&first));
+        assert_eq!(audits[1].id, 1);
+        assert_eq!(audits[1].input_hashes, [hash(&first), hash(&genesis)]);
+        assert_eq!(audits[1].output_hash, hash(&second));
+        assert_eq!(audits[1].timings, timers);
+        Ok(())
+    }
+
+    struct FailingAttestor;
+
+    impl Attestor for FailingAttestor {
+        fn attest(&self, _: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            anyhow::bail!("secure module unavailable")
+        }
+
+        fn verify(&self, _: &[u8], _: &[u8], _: &PcrPolicy) -> anyhow::Result<()> {
+            anyhow::bail!("secure module unavailable")
+        }
+    }
+
+    /// Probe a mock enclave serving one request with `worker`.
+    async fn probe_worker(worker: HandleFn) -> anyhow::Result<HealthReport> {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+        use tokio::sync::mpsc::unbounded_channel;
+
+        let (portal, enclave) = tokio::net::UnixStream::pair()?;
+        let enclave = tokio::spawn(async move {
+            let (mut read_half, mut write_half) = enclave.into_split();
+            handshake(&mut read_half, &mut write_half).await?;
+            let len = read_half.read_u64_le().await?;
+            let mut buf = vec![0; len as _];
+            read_half.read_exact(&mut buf).await?;
+            let (write_sender, mut replies) = unbounded_channel();
+            let nsm = Arc::new(NitroSecure(-1));
+            let pcrs = Arc::new([Vec::new(), Vec::new(), Vec::new()]);
+            worker(buf, nsm, pcrs, write_sender).await?;
+            let reply = replies.try_recv()?;
+            write_half.write_u64_le(reply.len() as _).await?;
+            write_half.write_all(&reply).await?;
+            anyhow::Ok(())
+        });
+        let report =
+            NitroEnclavesClock::probe(portal, &StubAttestor, &PcrPolicy::default()).await?;
+        enclave.await??;
+        Ok(report)
+    }
+
+    #[tokio::test]
+    async fn probe_reports_health() -> anyhow::Result<()> {
+        let healthy = NitroEnclavesClock::worker_with(|_| Arc::new(StubAttestor));
+        let report = probe_worker(healthy).await?;
+        anyhow::ensure!(report.is_ready(), "{report:?}");
+        anyhow::ensure!(report.round_trip > Duration::ZERO);
+
+        let failing = NitroEnclavesClock::worker_with(|_| Arc::new(FailingAttestor));
+        let report = probe_worker(failing).await?;
+        anyhow::ensure!(!report.is_ready());
+        assert_eq!(
+            report.status,
+            HealthStatus::WorkerError("secure module unavailable".into())
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "nitro-enclaves")]
+    #[test]
+    fn genesis_verifies_without_document() -> anyhow::Result<()> {
+        let clock = NitroEnclavesClock::genesis();
+        anyhow::ensure!(clock.plain.is_genesis());
+        anyhow::ensure!(clock.document.is_empty());
+        anyhow::ensure!(clock.verify()?.is_none());
+        Ok(())
+    }
+}
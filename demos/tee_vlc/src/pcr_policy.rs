@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+/// Length of a PCR value, which is a SHA-384 digest.
+pub const PCR_LEN: usize = 48;
+
+/// Expected PCR values of an attestation document, keyed by PCR index.
+/// PCRs not in the policy are not checked.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PcrPolicy {
+    pub expected: BTreeMap<usize, Vec<u8>>,
+}
+
+impl PcrPolicy {
+    /// Load a policy from hex encoded PCR values, e.g. as printed by
+    /// `nitro-cli describe-enclaves`. Fails on malformed hex, a value of the
+    /// wrong length, or an index given twice.
+    pub fn from_hex<S: AsRef<str>>(
+        pcrs: impl IntoIterator<Item = (usize, S)>,
+    ) -> anyhow::Result<Self> {
+        let mut expected = BTreeMap::new();
+        for (index, value) in pcrs {
+            let value = hex::decode(value.as_ref().trim())
+                .map_err(|err| anyhow::anyhow!("PCR{index}: {err}"))?;
+            anyhow::ensure!(
+                value.len() == PCR_LEN,
+                "PCR{index}: expected {PCR_LEN} bytes, got {}",
+                value.len()
+            );
+            anyhow::ensure!(
+                expected.insert(index, value).is_none(),
+                "PCR{index} given more than once"
+            );
+        }
+        Ok(Self { expected })
+    }
+
+    /// Check the PCR map of an attestation document against the policy.
+    pub fn check<T: AsRef<[u8]>>(&self, pcrs: &BTreeMap<usize, T>) -> anyhow::Result<()> {
+        for (index, expected) in &self.expected {
+            anyhow::ensure!(
+                pcrs.get(index).map(AsRef::as_ref) == Some(&expected[..]),
+                "PCR value mismatch at index {index}"
+            )
+        }
+        Ok(())
+    }
+}
+
+/// The policy of an enclave accepting its own image: PCR0, PCR1 and PCR2 as
+/// described by the secure module.
+impl From<&[Vec<u8>; 3]> for PcrPolicy {
+    fn from(pcrs: &[Vec<u8>; 3]) -> Self {
+        Self {
+            expected: pcrs.iter().cloned().enumerate().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_from_hex() -> anyhow::Result<()> {
+        let pcr0 = "ab".repeat(PCR_LEN);
+        let pcr2 = "0".repeat(2 * PCR_LEN);
+        let policy = PcrPolicy::from_hex([(0, &pcr0), (2, &pcr2)])?;
+
+        let mut document = BTreeMap::from([
+            (0, vec![0xab; PCR_LEN]),
+            (1, vec![0x11; PCR_LEN]),
+            (2, vec![0; PCR_LEN]),
+        ]);
+        policy.check(&document)?;
+        document.insert(2, vec![1; PCR_LEN]);
+        anyhow::ensure!(policy.check(&document).is_err());
+        document.remove(&2);
+        anyhow::ensure!(policy.check(&document).is_err());
+
+        anyhow::ensure!(PcrPolicy::from_hex([(0, "zz")]).is_err());
+        anyhow::ensure!(PcrPolicy::from_hex([(0, "abcd")]).is_err());
+        anyhow::ensure!(PcrPolicy::from_hex([(0, &pcr0), (0, &pcr0)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn own_pcrs_policy() -> anyhow::Result<()> {
+        let pcrs = [vec![0; PCR_LEN], vec![1; PCR_LEN], vec![2; PCR_LEN]];
+        let policy = PcrPolicy::from(&pcrs);
+        let document: BTreeMap<_, _> = pcrs.iter().enumerate().collect();
+        policy.check(&document)?;
+        Ok(())
+    }
+}
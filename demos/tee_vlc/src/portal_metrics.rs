@@ -0,0 +1,140 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Positions of the per-stage durations in an `UpdateOk`'s timers, as the
+/// enclave worker records them.
+pub const TIMER_DECODE: usize = 0;
+pub const TIMER_VERIFY: usize = 1;
+pub const TIMER_UPDATE: usize = 2;
+pub const TIMER_ATTEST: usize = 3;
+pub const TIMER_TOTAL: usize = 4;
+
+/// Histogram of durations over power-of-two microsecond buckets. Bucket `i`
+/// counts durations below `2^i` microseconds that do not fit a lower bucket,
+/// so percentiles are reported as an upper bound within a factor of two.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    buckets: [u64; 64],
+    count: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; 64],
+            count: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(63)] += 1;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Upper bound of the `p`-th percentile (`0.0..=100.0`), or `None` if
+    /// nothing was recorded.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((p.clamp(0., 100.) / 100. * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(Duration::from_micros(1 << bucket));
+            }
+        }
+        unreachable!()
+    }
+}
+
+/// Aggregated latencies of the enclave round trips of a portal session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Number of replies received.
+    pub count: u64,
+    /// Time spent in the enclave per update.
+    pub total: LatencyHistogram,
+    /// Time spent verifying the input clocks.
+    pub verify: LatencyHistogram,
+    /// Time spent generating the attestation of the updated clock.
+    pub attest: LatencyHistogram,
+}
+
+/// Latency metrics shared between a portal session, which records every
+/// reply, and whoever reads them.
+#[derive(Debug, Clone, Default)]
+pub struct PortalMetrics(Arc<Mutex<MetricsSnapshot>>);
+
+impl PortalMetrics {
+    /// Record the stage timers of one reply. Stages missing from `timers`
+    /// are skipped.
+    pub fn record(&self, timers: &[Duration]) {
+        let mut guard = self.0.lock().unwrap();
+        let metrics = &mut *guard;
+        metrics.count += 1;
+        for (index, histogram) in [
+            (TIMER_TOTAL, &mut metrics.total),
+            (TIMER_VERIFY, &mut metrics.verify),
+            (TIMER_ATTEST, &mut metrics.attest),
+        ] {
+            if let Some(duration) = timers.get(index) {
+                histogram.record(*duration)
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregate_replies() -> anyhow::Result<()> {
+        let metrics = PortalMetrics::default();
+        anyhow::ensure!(metrics.metrics().total.percentile(50.).is_none());
+        let ms = Duration::from_millis;
+        for i in 1..=100 {
+            let mut timers = vec![Duration::ZERO; 5];
+            timers[TIMER_VERIFY] = ms(1);
+            timers[TIMER_ATTEST] = ms(i);
+            timers[TIMER_TOTAL] = ms(i + 1);
+            metrics.record(&timers);
+        }
+        // a reply without timers still counts
+        metrics.record(&[]);
+
+        let snapshot = metrics.metrics();
+        assert_eq!(snapshot.count, 101);
+        assert_eq!(snapshot.total.count(), 100);
+        let within = |histogram: &LatencyHistogram, p, expected: Duration| {
+            let bound = histogram.percentile(p).unwrap();
+            anyhow::ensure!(
+                bound >= expected && bound < expected * 2,
+                "p{p} {bound:?} for {expected:?}"
+            );
+            Ok(())
+        };
+        within(&snapshot.attest, 50., ms(50))?;
+        within(&snapshot.attest, 99., ms(99))?;
+        within(&snapshot.total, 99., ms(100))?;
+        within(&snapshot.verify, 50., ms(1))?;
+        within(&snapshot.verify, 100., ms(1))?;
+        Ok(())
+    }
+}